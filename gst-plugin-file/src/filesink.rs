@@ -7,19 +7,36 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use url::Url;
 
-use std::io::Write;
+use std::io::{self, Seek, SeekFrom, Write};
 use std::convert::From;
 
 use gst_plugin::error::*;
+use gst_plugin::object::*;
 use gst_plugin_simple::sink::*;
 use gst_plugin_simple::UriValidator;
 
+use glib;
 use gst;
 use gst::prelude::*;
 
+#[cfg(unix)]
+use libc;
+
+const DEFAULT_APPEND: bool = false;
+
+pub static PROPERTIES: [Property; 1] = [
+    Property::Boolean(
+        "append",
+        "Append",
+        "Append to an already existing file instead of overwriting it",
+        DEFAULT_APPEND,
+        PropertyMutability::ReadWriteReady,
+    ),
+];
+
 #[derive(Debug)]
 enum StreamingState {
     Stopped,
@@ -30,6 +47,7 @@ enum StreamingState {
 pub struct FileSink {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
+    append: bool,
 }
 
 impl FileSink {
@@ -41,6 +59,7 @@ impl FileSink {
                 gst::DebugColorFlags::empty(),
                 "Rust file source",
             ),
+            append: DEFAULT_APPEND,
         }
     }
 
@@ -49,6 +68,65 @@ impl FileSink {
     }
 }
 
+// Writes all slices with a single writev() call if possible
+#[cfg(unix)]
+fn write_vectored(file: &mut File, slices: &[&[u8]]) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Most systems don't allow more than 1024 slices per call
+    for slices in slices.chunks(1024) {
+        let iovecs = slices
+            .iter()
+            .map(|slice| {
+                libc::iovec {
+                    iov_base: slice.as_ptr() as *mut libc::c_void,
+                    iov_len: slice.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut written = loop {
+            let res = unsafe {
+                libc::writev(
+                    file.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                )
+            };
+            if res >= 0 {
+                break res as usize;
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        };
+
+        // Write whatever is left after a short write one slice at a time
+        for slice in slices {
+            if written >= slice.len() {
+                written -= slice.len();
+                continue;
+            }
+
+            try!(file.write_all(&slice[written..]));
+            written = 0;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_vectored(file: &mut File, slices: &[&[u8]]) -> io::Result<()> {
+    for slice in slices {
+        try!(file.write_all(slice));
+    }
+
+    Ok(())
+}
+
 fn validate_uri(uri: &Url) -> Result<(), UriError> {
     let _ = try!(uri.to_file_path().or_else(|_| {
         Err(UriError::new(
@@ -86,28 +164,49 @@ impl SinkImpl for FileSink {
         }));
 
 
-        let file = try!(File::create(location.as_path()).or_else(|err| {
-            gst_error!(
-                self.cat,
-                obj: sink,
-                "Could not open file for writing: {}",
-                err.to_string()
-            );
-            Err(error_msg!(
-                gst::ResourceError::OpenWrite,
-                [
-                    "Could not open file for writing '{}': {}",
-                    location.to_str().unwrap_or("Non-UTF8 path"),
-                    err.to_string()
-                ]
-            ))
-        }));
+        let file = try!(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(self.append)
+                .truncate(!self.append)
+                .open(location.as_path())
+                .or_else(|err| {
+                    gst_error!(
+                        self.cat,
+                        obj: sink,
+                        "Could not open file for writing: {}",
+                        err.to_string()
+                    );
+                    Err(error_msg!(
+                        gst::ResourceError::OpenWrite,
+                        [
+                            "Could not open file for writing '{}': {}",
+                            location.to_str().unwrap_or("Non-UTF8 path"),
+                            err.to_string()
+                        ]
+                    ))
+                })
+        );
+
+        // When appending, our position is after the existing data
+        let position = if self.append {
+            file.metadata().map(|m| m.len()).unwrap_or(0)
+        } else {
+            0
+        };
 
-        gst_debug!(self.cat, obj: sink, "Opened file {:?}", file);
+        gst_debug!(
+            self.cat,
+            obj: sink,
+            "Opened file {:?} at position {}",
+            file,
+            position
+        );
 
         self.streaming_state = StreamingState::Started {
             file: file,
-            position: 0,
+            position: position,
         };
 
         Ok(())
@@ -160,4 +259,121 @@ impl SinkImpl for FileSink {
 
         Ok(())
     }
+
+    fn render_list(
+        &mut self,
+        sink: &RsBaseSink,
+        list: &gst::BufferListRef,
+    ) -> Result<(), FlowError> {
+        let cat = self.cat;
+        let streaming_state = &mut self.streaming_state;
+
+        gst_trace!(cat, obj: sink, "Rendering list {:?}", list);
+
+        let (file, position) = match *streaming_state {
+            StreamingState::Started {
+                ref mut file,
+                ref mut position,
+            } => (file, position),
+            StreamingState::Stopped => {
+                return Err(FlowError::Error(
+                    error_msg!(gst::LibraryError::Failed, ["Not started yet"]),
+                ));
+            }
+        };
+
+        let maps = match list.iter()
+            .map(|buffer| buffer.map_readable())
+            .collect::<Option<Vec<_>>>()
+        {
+            None => {
+                return Err(FlowError::Error(error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Failed to map buffer"]
+                )));
+            }
+            Some(maps) => maps,
+        };
+        let slices = maps.iter().map(|map| map.as_slice()).collect::<Vec<_>>();
+
+        try!(write_vectored(file, &slices).or_else(|err| {
+            gst_error!(cat, obj: sink, "Failed to write: {}", err);
+            Err(FlowError::Error(error_msg!(
+                gst::ResourceError::Write,
+                ["Failed to write: {}", err]
+            )))
+        }));
+
+        *position += slices.iter().map(|slice| slice.len() as u64).sum::<u64>();
+
+        Ok(())
+    }
+
+    fn event(&mut self, sink: &RsBaseSink, event: &gst::Event) -> Result<(), ErrorMessage> {
+        use gst::EventView;
+
+        let cat = self.cat;
+        let append = self.append;
+
+        let (file, position) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut file,
+                ref mut position,
+            } => (file, position),
+            StreamingState::Stopped => return Ok(()),
+        };
+
+        match event.view() {
+            EventView::Segment(ref e) => {
+                // Byte segments tell us where the following data belongs, e.g.
+                // to rewrite headers at the start of the file. Writes always go
+                // to the end when appending
+                let segment = e.get_segment();
+                if segment.get_format() == gst::Format::Bytes && !append &&
+                    segment.get_start() != *position
+                {
+                    let start = segment.get_start();
+                    gst_debug!(cat, obj: sink, "Seeking to {}", start);
+
+                    try!(file.seek(SeekFrom::Start(start)).or_else(|err| {
+                        gst_error!(cat, obj: sink, "Failed to seek to {}: {}", start, err);
+                        Err(error_msg!(
+                            gst::ResourceError::Seek,
+                            ["Failed to seek to {}: {}", start, err.to_string()]
+                        ))
+                    }));
+                    *position = start;
+                }
+            }
+            EventView::Eos(..) => {
+                // Make sure everything is on disk before EOS is posted
+                try!(file.sync_all().or_else(|err| {
+                    gst_error!(cat, obj: sink, "Failed to sync: {}", err);
+                    Err(error_msg!(
+                        gst::ResourceError::Write,
+                        ["Failed to sync: {}", err.to_string()]
+                    ))
+                }));
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn set_property(&mut self, _sink: &RsBaseSink, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Boolean("append", ..) => {
+                self.append = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _sink: &RsBaseSink, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::Boolean("append", ..) => Ok(self.append.to_value()),
+            _ => unimplemented!(),
+        }
+    }
 }
@@ -28,6 +28,7 @@ enum StreamingState {
 pub struct FileSrc {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
+    flushing: FlushingFlag,
 }
 
 impl FileSrc {
@@ -39,6 +40,7 @@ impl FileSrc {
                 gst::DebugColorFlags::empty(),
                 "Rust file source",
             ),
+            flushing: FlushingFlag::new(),
         }
     }
 
@@ -62,6 +64,10 @@ impl SourceImpl for FileSrc {
         Box::new(validate_uri)
     }
 
+    fn unlock_handler(&self) -> Option<Box<UnlockHandler>> {
+        Some(Box::new(self.flushing.clone()))
+    }
+
     fn is_seekable(&self, _src: &RsBaseSrc) -> bool {
         true
     }
@@ -138,6 +144,13 @@ impl SourceImpl for FileSrc {
         let cat = self.cat;
         let streaming_state = &mut self.streaming_state;
 
+        // Reading from local files doesn't block for long, so it's enough to
+        // check before each read
+        if self.flushing.is_flushing() {
+            gst_debug!(cat, obj: src, "Flushing");
+            return Err(FlowError::Flushing);
+        }
+
         let (file, position) = match *streaming_state {
             StreamingState::Started {
                 ref mut file,
@@ -8,11 +8,14 @@
 
 #![crate_type = "cdylib"]
 
+extern crate glib;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
 #[macro_use]
 extern crate gstreamer as gst;
+#[cfg(unix)]
+extern crate libc;
 extern crate url;
 
 use gst_plugin_simple::source::*;
@@ -37,6 +40,10 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: FileSrc::new_boxed,
             protocols: vec!["file".into()],
             push_only: false,
+            output_caps: gst::Caps::new_any(),
+            is_live: false,
+            format: gst::Format::Bytes,
+            properties: &[],
         },
     );
 
@@ -51,6 +58,10 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             rank: 256 + 100,
             create_instance: FileSink::new_boxed,
             protocols: vec!["file".into()],
+            properties: &filesink::PROPERTIES,
+            sync: false,
+            async_enabled: false,
+            max_lateness: None,
         },
     );
 
@@ -7,6 +7,8 @@
 // except according to those terms.
 
 use std::cmp;
+use std::mem;
+use std::u64;
 use std::io::{Cursor, Write};
 
 use nom;
@@ -17,10 +19,13 @@ use flavors::parser as flavors;
 use gst_plugin::error::*;
 use gst_plugin::adapter::*;
 use gst_plugin::bytes::*;
+use gst_plugin::object::*;
+use gst_plugin::typefind::*;
 use gst_plugin_simple::demuxer::*;
 
 use muldiv::*;
 
+use glib;
 use gst;
 use gst::prelude::*;
 
@@ -28,6 +33,49 @@ use num_rational::Rational32;
 
 const AUDIO_STREAM_ID: u32 = 0;
 const VIDEO_STREAM_ID: u32 = 1;
+const CAPTION_STREAM_ID: u32 = 2;
+
+// Enhanced FLV packet types. Audio has no CodedFramesX and uses a different
+// value for multitrack packets
+const EX_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+const EX_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+const EX_PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+const EX_AUDIO_PACKET_TYPE_MULTITRACK: u8 = 5;
+const EX_VIDEO_PACKET_TYPE_MULTITRACK: u8 = 6;
+
+// Sound format signalling an enhanced audio tag header
+const EX_AUDIO_SOUND_FORMAT: u8 = 9;
+// Video frame type of command frames, which contain no media
+const EX_VIDEO_FRAME_TYPE_COMMAND: u8 = 5;
+
+// Byte range below which seeking stops bisecting and scans the tags linearly
+const BISECT_MIN_RANGE: u64 = 128 * 1024;
+
+// Larger tags are considered garbage when resyncing
+const MAX_RESYNC_TAG_SIZE: usize = 4 * 1024 * 1024;
+
+const DEFAULT_DISCONT_THRESHOLD: u64 = 2 * 1_000_000_000;
+
+const DEFAULT_RESILIENT: bool = false;
+
+pub static PROPERTIES: [Property; 2] = [
+    Property::UInt64(
+        "discont-threshold",
+        "Discont Threshold",
+        "Timestamp jumps larger than this are handled as discontinuities, in nanoseconds \
+         (0 = disabled)",
+        (0, u64::MAX),
+        DEFAULT_DISCONT_THRESHOLD,
+        PropertyMutability::ReadWriteReady,
+    ),
+    Property::Boolean(
+        "resilient",
+        "Resilient",
+        "Skip corrupted data by searching for the next valid tag instead of failing",
+        DEFAULT_RESILIENT,
+        PropertyMutability::ReadWrite,
+    ),
+];
 
 #[derive(Debug)]
 enum State {
@@ -38,7 +86,29 @@ enum State {
         video: bool,
         skip_left: u32,
     },
+    // Reading the size and then the header of the last tag in pull mode to
+    // get the duration, before continuing with the first tag
+    ProbingDuration {
+        audio: bool,
+        video: bool,
+        last_tag_size: Option<u32>,
+    },
     Streaming,
+    // After seeking into the middle of the stream, until the next tag is found
+    Resyncing,
+    // Seeking without index entry around the target. Probes the middle of
+    // the byte range for the first keyframe after it, low is always a
+    // keyframe at or before the target
+    Bisecting {
+        target: u64,
+        low: u64,
+        high: u64,
+        position: u64,
+        synced: bool,
+    },
+    // Reading the tags from a keyframe on for the last keyframe at or before
+    // the target
+    Scanning { target: u64, keyframe: u64 },
 }
 
 #[derive(Debug)]
@@ -48,12 +118,27 @@ struct StreamingState {
     video: Option<VideoFormat>,
     expect_video: bool,
     got_all_streams: bool,
+    // If the caption stream was added after the first onCaption tag
+    captions: bool,
     last_position: Option<u64>,
+    // Estimated from the upstream size and the bitrate so far, and if it has
+    // to be announced with a duration-changed message
+    estimated_duration: Option<u64>,
+    duration_changed: bool,
 
     metadata: Option<Metadata>,
-
-    aac_sequence_header: Option<gst::Buffer>,
-    avc_sequence_header: Option<gst::Buffer>,
+    pending_tags: Vec<(Option<StreamIndex>, gst::TagList)>,
+
+    // Milliseconds added to all tag timestamps because of wraparounds and
+    // discontinuities, and the last tag timestamp after adding it
+    timestamp_offset: i64,
+    last_timestamp: Option<i64>,
+    // If the next buffer of each stream has to be marked as DISCONT
+    audio_discont: bool,
+    video_discont: bool,
+
+    audio_sequence_header: Option<gst::Buffer>,
+    video_sequence_header: Option<gst::Buffer>,
 }
 
 impl StreamingState {
@@ -64,22 +149,240 @@ impl StreamingState {
             video: None,
             expect_video: video,
             got_all_streams: false,
+            captions: false,
             last_position: None,
+            estimated_duration: None,
+            duration_changed: false,
             metadata: None,
-            aac_sequence_header: None,
-            avc_sequence_header: None,
+            pending_tags: Vec::new(),
+            timestamp_offset: 0,
+            last_timestamp: None,
+            audio_discont: false,
+            video_discont: false,
+            audio_sequence_header: None,
+            video_sequence_header: None,
+        }
+    }
+}
+
+// Reads big-endian bit fields as used by the MPEG-4 audio headers
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        if self.position + bits > self.data.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = self.data[self.position / 8];
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u32;
+            self.position += 1;
+        }
+
+        Some(value)
+    }
+}
+
+const AAC_SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350
+];
+
+// MPEG-4 AudioSpecificConfig from the AAC sequence header, with the rate
+// and channels as output by the decoder if SBR/PS are signalled
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct AacConfig {
+    object_type: u8,
+    rate: u32,
+    channels: Option<u8>,
+    sbr: bool,
+    ps: bool,
+}
+
+impl AacConfig {
+    fn parse(data: &[u8]) -> Option<AacConfig> {
+        fn read_object_type(reader: &mut BitReader) -> Option<u8> {
+            match reader.read(5) {
+                Some(31) => reader.read(6).map(|t| 32 + t as u8),
+                t => t.map(|t| t as u8),
+            }
+        }
+
+        fn read_sampling_frequency(reader: &mut BitReader) -> Option<u32> {
+            match reader.read(4) {
+                Some(0xf) => reader.read(24),
+                Some(index) => AAC_SAMPLING_FREQUENCIES.get(index as usize).cloned(),
+                None => None,
+            }
+        }
+
+        // Skips the GASpecificConfig of the core to get to the backwards
+        // compatible SBR/PS signalling after it. Returns the output rate
+        // and if PS is present
+        fn read_sync_extension(
+            reader: &mut BitReader,
+            object_type: u8,
+            channel_configuration: u32,
+        ) -> Option<(u32, bool)> {
+            // Program config elements are not skipped here
+            match object_type {
+                1...4 | 6 | 7 | 17 | 19...23 if channel_configuration != 0 => (),
+                _ => return None,
+            }
+
+            // frameLengthFlag, dependsOnCoreCoder with its coreCoderDelay
+            // and extensionFlag
+            let depends_on_core_coder = match reader.read(2) {
+                Some(value) => value & 1 == 1,
+                None => return None,
+            };
+            if depends_on_core_coder && reader.read(14).is_none() {
+                return None;
+            }
+            let extension_flag = match reader.read(1) {
+                Some(value) => value == 1,
+                None => return None,
+            };
+            if (object_type == 6 || object_type == 20) && reader.read(3).is_none() {
+                return None;
+            }
+            if extension_flag {
+                let bits = match object_type {
+                    22 => 16 + 1,
+                    17 | 19 | 20 | 23 => 3 + 1,
+                    _ => 1,
+                };
+                if reader.read(bits).is_none() {
+                    return None;
+                }
+            }
+
+            if reader.read(11) != Some(0x2b7) || read_object_type(reader) != Some(5) ||
+                reader.read(1) != Some(1)
+            {
+                return None;
+            }
+            let rate = match read_sampling_frequency(reader) {
+                Some(value) => value,
+                None => return None,
+            };
+            let ps = reader.read(11) == Some(0x548) && reader.read(1) == Some(1);
+
+            Some((rate, ps))
+        }
+
+        let mut reader = BitReader::new(data);
+        let mut object_type = match read_object_type(&mut reader) {
+            Some(value) => value,
+            None => return None,
+        };
+        let mut rate = match read_sampling_frequency(&mut reader) {
+            Some(value) => value,
+            None => return None,
+        };
+        let channel_configuration = match reader.read(4) {
+            Some(value) => value,
+            None => return None,
+        };
+
+        // 0 means the channels are defined by a program config element
+        let mut channels = match channel_configuration {
+            1...6 => Some(channel_configuration as u8),
+            7 => Some(8),
+            _ => None,
+        };
+
+        let mut sbr = object_type == 5 || object_type == 29;
+        let mut ps = object_type == 29;
+        if sbr {
+            rate = match read_sampling_frequency(&mut reader) {
+                Some(value) => value,
+                None => return None,
+            };
+            object_type = match read_object_type(&mut reader) {
+                Some(value) => value,
+                None => return None,
+            };
+        } else if let Some((extension_rate, extension_ps)) =
+            read_sync_extension(&mut reader, object_type, channel_configuration)
+        {
+            rate = extension_rate;
+            sbr = true;
+            ps = extension_ps;
+        }
+
+        if ps && channels == Some(1) {
+            channels = Some(2);
+        }
+
+        Some(AacConfig {
+            object_type: object_type,
+            rate: rate,
+            channels: channels,
+            sbr: sbr,
+            ps: ps,
+        })
+    }
+
+    fn profile(&self) -> Option<&'static str> {
+        match self.object_type {
+            1 => Some("main"),
+            2 => Some("lc"),
+            3 => Some("ssr"),
+            4 => Some("ltp"),
+            _ => None,
+        }
+    }
+
+    // Levels of the AAC and High Efficiency AAC profiles, which only depend
+    // on the number of channels and the sampling rate for the common cases
+    fn level(&self) -> Option<u8> {
+        let channels = match self.channels {
+            Some(channels) => channels,
+            None => return None,
+        };
+
+        if self.object_type != 2 {
+            return None;
+        }
+
+        match (channels, self.rate) {
+            (1...2, 0...24000) if !self.sbr => Some(1),
+            (1...2, 0...48000) => Some(2),
+            (3...5, 0...48000) => Some(4),
+            (1...5, 0...96000) => Some(5),
+            _ => None,
         }
     }
 }
 
+// Legacy FLV sound formats and the ones only available with the enhanced
+// audio tag header
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AudioCodec {
+    Legacy(flavors::SoundFormat),
+    Opus,
+}
+
 #[derive(Debug, Eq, Clone)]
-struct AudioFormat {
-    format: flavors::SoundFormat,
-    rate: u16,
-    width: u8,
-    channels: u8,
-    bitrate: Option<u32>,
-    aac_sequence_header: Option<gst::Buffer>,
+pub struct AudioFormat {
+    pub format: AudioCodec,
+    pub rate: u32,
+    pub width: u8,
+    pub channels: u8,
+    pub bitrate: Option<u32>,
+    pub sequence_header: Option<gst::Buffer>,
 }
 
 // Ignores bitrate
@@ -87,7 +390,7 @@ impl PartialEq for AudioFormat {
     fn eq(&self, other: &Self) -> bool {
         self.format.eq(&other.format) && self.rate.eq(&other.rate) && self.width.eq(&other.width) &&
             self.channels.eq(&other.channels) &&
-            self.aac_sequence_header.eq(&other.aac_sequence_header)
+            self.sequence_header.eq(&other.sequence_header)
     }
 }
 
@@ -95,9 +398,9 @@ impl AudioFormat {
     fn new(
         data_header: &flavors::AudioDataHeader,
         metadata: &Option<Metadata>,
-        aac_sequence_header: &Option<gst::Buffer>,
+        sequence_header: &Option<gst::Buffer>,
     ) -> AudioFormat {
-        let numeric_rate = match (data_header.sound_format, data_header.sound_rate) {
+        let mut numeric_rate = match (data_header.sound_format, data_header.sound_rate) {
             (flavors::SoundFormat::NELLYMOSER_16KHZ_MONO, _) => 16000,
             (flavors::SoundFormat::NELLYMOSER_8KHZ_MONO, _) => 8000,
             (flavors::SoundFormat::MP3_8KHZ, _) => 8000,
@@ -113,21 +416,101 @@ impl AudioFormat {
             flavors::SoundSize::Snd16bit => 16,
         };
 
-        let numeric_channels = match data_header.sound_type {
+        let mut numeric_channels = match data_header.sound_type {
             flavors::SoundType::SndMono => 1,
             flavors::SoundType::SndStereo => 2,
         };
 
+        // The sound flags are always 44.1kHz stereo for AAC, the real values
+        // are only in the AudioSpecificConfig
+        if data_header.sound_format == flavors::SoundFormat::AAC {
+            let config = sequence_header.as_ref().and_then(|header| {
+                let map = header.map_readable().unwrap();
+                AacConfig::parse(map.as_slice())
+            });
+
+            if let Some(config) = config {
+                numeric_rate = config.rate;
+                if let Some(channels) = config.channels {
+                    numeric_channels = channels;
+                }
+            }
+        }
+
         AudioFormat {
-            format: data_header.sound_format,
+            format: AudioCodec::Legacy(data_header.sound_format),
             rate: numeric_rate,
             width: numeric_width,
             channels: numeric_channels,
             bitrate: metadata.as_ref().and_then(|m| m.audio_bitrate),
-            aac_sequence_header: aac_sequence_header.clone(),
+            sequence_header: sequence_header.clone(),
+        }
+    }
+
+    // Opus always runs at 48kHz, the channel count is only known from the
+    // OpusHead in the sequence header and stereo otherwise
+    fn new_opus(metadata: &Option<Metadata>, sequence_header: &Option<gst::Buffer>) -> AudioFormat {
+        let opus_head = sequence_header.as_ref().and_then(|header| {
+            let map = header.map_readable().unwrap();
+            let data = map.as_slice();
+            if data.len() >= 19 && &data[0..8] == b"OpusHead" {
+                Some((header.clone(), data[9]))
+            } else {
+                None
+            }
+        });
+
+        AudioFormat {
+            format: AudioCodec::Opus,
+            rate: 48000,
+            width: 16,
+            channels: opus_head.as_ref().map(|&(_, channels)| channels).unwrap_or(2),
+            bitrate: metadata.as_ref().and_then(|m| m.audio_bitrate),
+            sequence_header: opus_head.map(|(header, _)| header),
         }
     }
 
+    // Reverse of to_caps()
+    pub fn from_caps(caps: &gst::Caps) -> Option<AudioFormat> {
+        let s = match caps.get_structure(0) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        let rate = s.get::<i32>("rate").unwrap_or(0);
+        let channels = s.get::<i32>("channels").unwrap_or(0);
+        let mut sequence_header = None;
+
+        let format = match (s.get_name(), s.get::<i32>("mpegversion")) {
+            ("audio/mpeg", Some(1)) if s.get::<i32>("layer") == Some(3) => if rate == 8000 {
+                flavors::SoundFormat::MP3_8KHZ
+            } else {
+                flavors::SoundFormat::MP3
+            },
+            ("audio/mpeg", Some(2)) | ("audio/mpeg", Some(4)) => {
+                match s.get::<gst::Buffer>("codec_data") {
+                    Some(codec_data) => sequence_header = Some(codec_data),
+                    None => return None,
+                }
+                flavors::SoundFormat::AAC
+            }
+            ("audio/x-speex", _) => flavors::SoundFormat::SPEEX,
+            ("audio/x-nellymoser", _) => flavors::SoundFormat::NELLYMOSER,
+            ("audio/x-alaw", _) => flavors::SoundFormat::PCM_ALAW,
+            ("audio/x-mulaw", _) => flavors::SoundFormat::PCM_ULAW,
+            _ => return None,
+        };
+
+        Some(AudioFormat {
+            format: AudioCodec::Legacy(format),
+            rate: rate as u32,
+            width: 16,
+            channels: channels as u8,
+            bitrate: None,
+            sequence_header: sequence_header,
+        })
+    }
+
     fn update_with_metadata(&mut self, metadata: &Metadata) -> bool {
         let mut changed = false;
 
@@ -139,8 +522,8 @@ impl AudioFormat {
         changed
     }
 
-    fn to_caps(&self) -> Option<gst::Caps> {
-        let mut caps = match self.format {
+    fn legacy_caps(&self, format: flavors::SoundFormat) -> Option<gst::Caps> {
+        match format {
             flavors::SoundFormat::MP3 | flavors::SoundFormat::MP3_8KHZ => Some(
                 gst::Caps::new_simple("audio/mpeg", &[("mpegversion", &1i32), ("layer", &3i32)]),
             ),
@@ -170,8 +553,8 @@ impl AudioFormat {
             }
             flavors::SoundFormat::PCM_ALAW => Some(gst::Caps::new_simple("audio/x-alaw", &[])),
             flavors::SoundFormat::PCM_ULAW => Some(gst::Caps::new_simple("audio/x-mulaw", &[])),
-            flavors::SoundFormat::AAC => self.aac_sequence_header.as_ref().map(|header| {
-                gst::Caps::new_simple(
+            flavors::SoundFormat::AAC => self.sequence_header.as_ref().map(|header| {
+                let mut caps = gst::Caps::new_simple(
                     "audio/mpeg",
                     &[
                         ("mpegversion", &4i32),
@@ -179,7 +562,24 @@ impl AudioFormat {
                         ("stream-format", &"raw"),
                         ("codec_data", &header),
                     ],
-                )
+                );
+
+                let config = {
+                    let map = header.map_readable().unwrap();
+                    AacConfig::parse(map.as_slice())
+                };
+
+                if let Some(config) = config {
+                    let caps = caps.get_mut().unwrap();
+                    if let Some(profile) = config.profile() {
+                        caps.set_simple(&[("profile", &profile)]);
+                    }
+                    if let Some(level) = config.level() {
+                        caps.set_simple(&[("level", &level.to_string())]);
+                    }
+                }
+
+                caps
             }),
             flavors::SoundFormat::SPEEX => {
                 let header = {
@@ -230,6 +630,54 @@ impl AudioFormat {
                 // Nobody knows
                 None
             }
+        }
+    }
+
+    // Without OpusHead this can only be mono or stereo, which decoders
+    // handle just fine without stream headers
+    fn opus_caps(&self) -> Option<gst::Caps> {
+        let header = match self.sequence_header {
+            Some(ref header) => header,
+            None => {
+                return Some(gst::Caps::new_simple(
+                    "audio/x-opus",
+                    &[("channel-mapping-family", &0i32)],
+                ))
+            }
+        };
+
+        let channel_mapping_family = {
+            let map = header.map_readable().unwrap();
+            map.as_slice()[18]
+        };
+
+        let comment = {
+            let comment_size = 8 + 4 + 7 /* nothing */ + 4;
+            let mut data = Cursor::new(Vec::with_capacity(comment_size));
+            data.write_all(b"OpusTags").unwrap();
+            data.write_u32le(7).unwrap(); // length of "nothing"
+            data.write_all(b"nothing").unwrap(); // "vendor" string
+            data.write_u32le(0).unwrap(); // number of elements
+
+            assert_eq!(data.position() as usize, comment_size);
+
+            data.into_inner()
+        };
+        let comment = gst::Buffer::from_vec(comment).unwrap();
+
+        Some(gst::Caps::new_simple(
+            "audio/x-opus",
+            &[
+                ("channel-mapping-family", &(channel_mapping_family as i32)),
+                ("streamheader", &gst::Array::new(&[header, &comment])),
+            ],
+        ))
+    }
+
+    fn to_caps(&self) -> Option<gst::Caps> {
+        let mut caps = match self.format {
+            AudioCodec::Legacy(format) => self.legacy_caps(format),
+            AudioCodec::Opus => self.opus_caps(),
         };
 
         if self.rate != 0 {
@@ -251,34 +699,85 @@ impl AudioFormat {
     }
 }
 
+// Legacy FLV codecs and the ones only available via FourCC with the enhanced
+// video tag header
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VideoCodec {
+    Legacy(flavors::CodecId),
+    Hevc,
+    Av1,
+    Vp9,
+}
+
 #[derive(Debug, Eq, Clone)]
-struct VideoFormat {
-    format: flavors::CodecId,
-    width: Option<u32>,
-    height: Option<u32>,
-    pixel_aspect_ratio: Option<Rational32>,
-    framerate: Option<Rational32>,
-    bitrate: Option<u32>,
-    avc_sequence_header: Option<gst::Buffer>,
+pub struct VideoFormat {
+    pub format: VideoCodec,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pixel_aspect_ratio: Option<Rational32>,
+    pub framerate: Option<Rational32>,
+    pub bitrate: Option<u32>,
+    pub sequence_header: Option<gst::Buffer>,
 }
 
 impl VideoFormat {
     fn new(
-        data_header: &flavors::VideoDataHeader,
+        format: VideoCodec,
         metadata: &Option<Metadata>,
-        avc_sequence_header: &Option<gst::Buffer>,
+        sequence_header: &Option<gst::Buffer>,
     ) -> VideoFormat {
         VideoFormat {
-            format: data_header.codec_id,
+            format: format,
             width: metadata.as_ref().and_then(|m| m.video_width),
             height: metadata.as_ref().and_then(|m| m.video_height),
             pixel_aspect_ratio: metadata.as_ref().and_then(|m| m.video_pixel_aspect_ratio),
             framerate: metadata.as_ref().and_then(|m| m.video_framerate),
             bitrate: metadata.as_ref().and_then(|m| m.video_bitrate),
-            avc_sequence_header: avc_sequence_header.clone(),
+            sequence_header: sequence_header.clone(),
         }
     }
 
+    // Reverse of to_caps()
+    pub fn from_caps(caps: &gst::Caps) -> Option<VideoFormat> {
+        let s = match caps.get_structure(0) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        let mut sequence_header = None;
+
+        let format = match s.get_name() {
+            "video/x-flash-video" => flavors::CodecId::SORENSON_H263,
+            "video/x-flash-screen" => flavors::CodecId::SCREEN,
+            "video/x-flash-screen2" => flavors::CodecId::SCREEN2,
+            "video/x-vp6-flash" => flavors::CodecId::VP6,
+            "video/x-vp6-flash-alpha" => flavors::CodecId::VP6A,
+            "video/x-h264" if s.get::<&str>("stream-format") == Some("avc") => {
+                match s.get::<gst::Buffer>("codec_data") {
+                    Some(codec_data) => sequence_header = Some(codec_data),
+                    None => return None,
+                }
+                flavors::CodecId::H264
+            }
+            _ => return None,
+        };
+
+        let framerate = s.get::<gst::Fraction>("framerate")
+            .map(|f| Rational32::new(*f.numer(), *f.denom()));
+        let pixel_aspect_ratio = s.get::<gst::Fraction>("pixel-aspect-ratio")
+            .map(|f| Rational32::new(*f.numer(), *f.denom()));
+
+        Some(VideoFormat {
+            format: VideoCodec::Legacy(format),
+            width: s.get::<i32>("width").map(|w| w as u32),
+            height: s.get::<i32>("height").map(|h| h as u32),
+            pixel_aspect_ratio: pixel_aspect_ratio,
+            framerate: framerate,
+            bitrate: None,
+            sequence_header: sequence_header,
+        })
+    }
+
     fn update_with_metadata(&mut self, metadata: &Metadata) -> bool {
         let mut changed = false;
 
@@ -310,8 +809,8 @@ impl VideoFormat {
         changed
     }
 
-    fn to_caps(&self) -> Option<gst::Caps> {
-        let mut caps = match self.format {
+    fn legacy_caps(&self, format: flavors::CodecId) -> Option<gst::Caps> {
+        match format {
             flavors::CodecId::SORENSON_H263 => Some(gst::Caps::new_simple(
                 "video/x-flash-video",
                 &[("flvversion", &1i32)],
@@ -320,7 +819,7 @@ impl VideoFormat {
             flavors::CodecId::VP6 => Some(gst::Caps::new_simple("video/x-vp6-flash", &[])),
             flavors::CodecId::VP6A => Some(gst::Caps::new_simple("video/x-vp6-flash-alpha", &[])),
             flavors::CodecId::SCREEN2 => Some(gst::Caps::new_simple("video/x-flash-screen2", &[])),
-            flavors::CodecId::H264 => self.avc_sequence_header.as_ref().map(|header| {
+            flavors::CodecId::H264 => self.sequence_header.as_ref().map(|header| {
                 gst::Caps::new_simple(
                     "video/x-h264",
                     &[("stream-format", &"avc"), ("codec_data", &header)],
@@ -335,6 +834,34 @@ impl VideoFormat {
                 // Unused according to spec
                 None
             }
+        }
+    }
+
+    fn to_caps(&self) -> Option<gst::Caps> {
+        let mut caps = match self.format {
+            VideoCodec::Legacy(format) => self.legacy_caps(format),
+            VideoCodec::Hevc => self.sequence_header.as_ref().map(|header| {
+                gst::Caps::new_simple(
+                    "video/x-h265",
+                    &[
+                        ("stream-format", &"hvc1"),
+                        ("alignment", &"au"),
+                        ("codec_data", &header),
+                    ],
+                )
+            }),
+            VideoCodec::Av1 => self.sequence_header.as_ref().map(|header| {
+                gst::Caps::new_simple(
+                    "video/x-av1",
+                    &[
+                        ("stream-format", &"obu-stream"),
+                        ("alignment", &"tu"),
+                        ("codec_data", &header),
+                    ],
+                )
+            }),
+            // The VP9 codec configuration record is not needed by decoders
+            VideoCodec::Vp9 => Some(gst::Caps::new_simple("video/x-vp9", &[])),
         };
 
         if let (Some(width), Some(height)) = (self.width, self.height) {
@@ -379,7 +906,7 @@ impl PartialEq for VideoFormat {
             self.height.eq(&other.height) &&
             self.pixel_aspect_ratio.eq(&other.pixel_aspect_ratio) &&
             self.framerate.eq(&other.framerate) &&
-            self.avc_sequence_header.eq(&other.avc_sequence_header)
+            self.sequence_header.eq(&other.sequence_header)
     }
 }
 
@@ -390,8 +917,10 @@ struct Metadata {
     creation_date: Option<String>,
     creator: Option<String>,
     title: Option<String>,
-    metadata_creator: Option<String>, /* TODO: seek_table: _,
-                                       * filepositions / times metadata arrays */
+    metadata_creator: Option<String>,
+    // Sorted (time, byte offset) pairs of all keyframes. The offset points at
+    // the previous tag size field before the keyframe's tag
+    keyframes: Vec<(u64, u64)>,
 
     audio_bitrate: Option<u32>,
 
@@ -412,6 +941,7 @@ impl Metadata {
             creator: None,
             title: None,
             metadata_creator: None,
+            keyframes: Vec::new(),
             audio_bitrate: None,
             video_width: None,
             video_height: None,
@@ -446,6 +976,10 @@ impl Metadata {
                 ("metadatacreator", &flavors::ScriptDataValue::String(creator)) => {
                     metadata.metadata_creator = Some(String::from(creator));
                 }
+                ("keyframes", &flavors::ScriptDataValue::Object(ref keyframes)) |
+                ("keyframes", &flavors::ScriptDataValue::ECMAArray(ref keyframes)) => {
+                    metadata.keyframes = Metadata::parse_keyframes(keyframes);
+                }
                 ("audiodatarate", &flavors::ScriptDataValue::Number(datarate)) => {
                     metadata.audio_bitrate = Some((datarate * 1024.0) as u32);
                 }
@@ -479,121 +1013,708 @@ impl Metadata {
 
         metadata
     }
-}
 
-pub struct FlvDemux {
-    cat: gst::DebugCategory,
-    state: State,
-    adapter: Adapter,
-    // Only in >= State::Streaming
-    streaming_state: Option<StreamingState>,
-}
+    fn to_tags(&self) -> Vec<(Option<StreamIndex>, gst::TagList)> {
+        let mut tags = Vec::new();
 
-impl FlvDemux {
-    pub fn new(_demuxer: &RsDemuxer) -> FlvDemux {
-        FlvDemux {
-            cat: gst::DebugCategory::new(
-                "rsflvdemux",
-                gst::DebugColorFlags::empty(),
-                "Rust FLV demuxer",
-            ),
-            state: State::Stopped,
-            adapter: Adapter::new(),
-            streaming_state: None,
+        let mut global_tags = gst::TagList::new();
+        {
+            let global_tags = global_tags.get_mut().unwrap();
+
+            if let Some(ref title) = self.title {
+                global_tags.add::<gst::tags::Title>(&title.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some(ref creator) = self.creator {
+                global_tags.add::<gst::tags::Artist>(&creator.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some(ref creator) = self.metadata_creator {
+                global_tags
+                    .add::<gst::tags::Encoder>(&creator.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some((year, month, day, hour, minute, second)) = self.creation_date
+                .as_ref()
+                .and_then(|d| parse_creation_date(d))
+            {
+                let date_time = gst::DateTime::new(0.0, year, month, day, hour, minute, second);
+                global_tags.add::<gst::tags::DateTime>(&date_time, gst::TagMergeMode::Replace);
+            }
+        }
+        if global_tags.n_tags() > 0 {
+            tags.push((None, global_tags));
+        }
+
+        for &(index, bitrate) in &[
+            (AUDIO_STREAM_ID, self.audio_bitrate),
+            (VIDEO_STREAM_ID, self.video_bitrate),
+        ] {
+            if let Some(bitrate) = bitrate {
+                let mut stream_tags = gst::TagList::new();
+                stream_tags
+                    .get_mut()
+                    .unwrap()
+                    .add::<gst::tags::Bitrate>(&bitrate, gst::TagMergeMode::Replace);
+                tags.push((Some(index), stream_tags));
+            }
         }
+
+        tags
     }
 
-    pub fn new_boxed(demuxer: &RsDemuxer) -> Box<DemuxerImpl> {
-        Box::new(Self::new(demuxer))
+    fn parse_keyframes(objects: &[flavors::ScriptDataObject]) -> Vec<(u64, u64)> {
+        let mut times = None;
+        let mut filepositions = None;
+
+        for object in objects {
+            match (object.name, &object.data) {
+                ("times", &flavors::ScriptDataValue::StrictArray(ref values)) => {
+                    times = Some(values);
+                }
+                ("filepositions", &flavors::ScriptDataValue::StrictArray(ref values)) => {
+                    filepositions = Some(values);
+                }
+                _ => {}
+            }
+        }
+
+        let (times, filepositions) = match (times, filepositions) {
+            (Some(times), Some(filepositions)) => (times, filepositions),
+            _ => return Vec::new(),
+        };
+
+        let mut keyframes = Vec::with_capacity(cmp::min(times.len(), filepositions.len()));
+        for (time, position) in times.iter().zip(filepositions.iter()) {
+            match (time, position) {
+                (
+                    &flavors::ScriptDataValue::Number(time),
+                    &flavors::ScriptDataValue::Number(position),
+                ) if time >= 0.0 && position >= 4.0 =>
+                {
+                    // The file positions point at the tag itself but we want to
+                    // continue parsing at the previous tag size before it
+                    add_index_entry(
+                        &mut keyframes,
+                        (time * 1000.0 * 1000.0 * 1000.0) as u64,
+                        position as u64 - 4,
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        keyframes
     }
+}
 
-    fn handle_script_tag(
-        &mut self,
-        demuxer: &RsDemuxer,
-        tag_header: &flavors::TagHeader,
-    ) -> Result<HandleBufferResult, FlowError> {
-        if self.adapter.get_available() < (15 + tag_header.data_size) as usize {
-            return Ok(HandleBufferResult::NeedMoreData);
+// Parses dates in the ctime() format, e.g. "Mon Jun 29 17:15:10 2009"
+fn parse_creation_date(date: &str) -> Option<(i32, i32, i32, i32, i32, f64)> {
+    let parts = date.split_whitespace().collect::<Vec<_>>();
+    if parts.len() != 5 {
+        return None;
+    }
+
+    let month = match parts[1] {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+
+    let time = parts[3].split(':').collect::<Vec<_>>();
+    if time.len() != 3 {
+        return None;
+    }
+    let (hour, minute, second) = match (
+        time[0].parse::<i32>(),
+        time[1].parse::<i32>(),
+        time[2].parse::<f64>(),
+    ) {
+        (Ok(hour), Ok(minute), Ok(second))
+            if hour >= 0 && hour < 24 && minute >= 0 && minute < 60 && second >= 0.0 &&
+                second < 60.0 =>
+        {
+            (hour, minute, second)
         }
+        _ => return None,
+    };
+
+    // Invalid dates can't be converted to a DateTime
+    let year = match parts[4].parse::<i32>() {
+        Ok(year) if year > 0 && year <= 9999 => year,
+        _ => return None,
+    };
+
+    let leap_year = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+
+    let day = match parts[2].parse::<i32>() {
+        Ok(day) if day >= 1 && day <= days_in_month => day,
+        _ => return None,
+    };
+
+    Some((year, month, day, hour, minute, second))
+}
 
-        self.adapter.flush(15).unwrap();
+fn add_index_entry(index: &mut Vec<(u64, u64)>, time: u64, offset: u64) {
+    match index.binary_search_by_key(&time, |&(t, _)| t) {
+        Ok(_) => (),
+        Err(idx) => index.insert(idx, (time, offset)),
+    }
+}
 
-        let buffer = self.adapter
-            .get_buffer(tag_header.data_size as usize)
-            .unwrap();
-        let map = buffer.map_readable().unwrap();
-        let data = map.as_slice();
+// Packet type, FourCC and payload position inside an enhanced audio or video tag
+#[derive(Debug, PartialEq, Eq)]
+struct ExTagBody {
+    packet_type: u8,
+    fourcc: [u8; 4],
+    start: usize,
+    end: usize,
+}
 
-        match flavors::script_data(data) {
-            IResult::Done(_, ref script_data) if script_data.name == "onMetaData" => {
-                gst_trace!(self.cat, obj: demuxer, "Got script tag: {:?}", script_data);
+// Parses the enhanced tag header at the start of the tag data. Of multitrack
+// packets only the track with id 0 is returned
+fn parse_ex_tag_body(data: &[u8], multitrack_packet_type: u8) -> Option<ExTagBody> {
+    if data.len() < 1 + 4 {
+        return None;
+    }
 
-                let metadata = Metadata::new(script_data);
-                gst_debug!(self.cat, obj: demuxer, "Got metadata: {:?}", metadata);
+    let packet_type = data[0] & 0x0f;
+    if packet_type != multitrack_packet_type {
+        let mut fourcc = [0u8; 4];
+        fourcc.copy_from_slice(&data[1..5]);
+        return Some(ExTagBody {
+            packet_type: packet_type,
+            fourcc: fourcc,
+            start: 5,
+            end: data.len(),
+        });
+    }
 
-                let streaming_state = self.streaming_state.as_mut().unwrap();
+    // 0: one track, 1: many tracks, 2: many tracks with many codecs
+    let multitrack_type = data[1] >> 4;
+    let packet_type = data[1] & 0x0f;
+    let mut fourcc = [0u8; 4];
+    let mut pos = 2;
 
-                let audio_changed = streaming_state
-                    .audio
-                    .as_mut()
-                    .map(|a| a.update_with_metadata(&metadata))
-                    .unwrap_or(false);
-                let video_changed = streaming_state
-                    .video
-                    .as_mut()
-                    .map(|v| v.update_with_metadata(&metadata))
-                    .unwrap_or(false);
-                streaming_state.metadata = Some(metadata);
+    if multitrack_type != 2 {
+        if data.len() < pos + 4 {
+            return None;
+        }
+        fourcc.copy_from_slice(&data[pos..(pos + 4)]);
+        pos += 4;
+    }
 
-                if audio_changed || video_changed {
-                    let mut streams = Vec::new();
+    while pos < data.len() {
+        let track_id = data[pos];
+        pos += 1;
 
-                    if audio_changed {
-                        if let Some(caps) = streaming_state.audio.as_ref().and_then(|a| a.to_caps())
-                        {
-                            streams.push(Stream::new(AUDIO_STREAM_ID, caps, String::from("audio")));
-                        }
-                    }
-                    if video_changed {
-                        if let Some(caps) = streaming_state.video.as_ref().and_then(|v| v.to_caps())
-                        {
-                            streams.push(Stream::new(VIDEO_STREAM_ID, caps, String::from("video")));
-                        }
-                    }
+        if multitrack_type == 2 {
+            if data.len() < pos + 4 {
+                return None;
+            }
+            fourcc.copy_from_slice(&data[pos..(pos + 4)]);
+            pos += 4;
+        }
 
-                    return Ok(HandleBufferResult::StreamsChanged(streams));
-                }
+        let size = if multitrack_type == 0 {
+            data.len() - pos
+        } else {
+            if data.len() < pos + 3 {
+                return None;
             }
-            IResult::Done(_, ref script_data) => {
+            let size = ((data[pos] as usize) << 16) | ((data[pos + 1] as usize) << 8) |
+                (data[pos + 2] as usize);
+            pos += 3;
+            size
+        };
+
+        if data.len() < pos + size {
+            return None;
+        }
+
+        if track_id == 0 {
+            return Some(ExTagBody {
+                packet_type: packet_type,
+                fourcc: fourcc,
+                start: pos,
+                end: pos + size,
+            });
+        }
+
+        pos += size;
+    }
+
+    None
+}
+
+// Sets the flags and timestamps of a video buffer from the tag timestamp and
+// the composition time offset in milliseconds
+fn set_video_buffer_metadata(
+    buffer: &mut gst::BufferRef,
+    timestamp: u32,
+    cts: i32,
+    is_keyframe: bool,
+) {
+    if !is_keyframe {
+        buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+    }
+    buffer.set_dts((timestamp as u64).mul_div_floor(1000_000, 1).unwrap());
+
+    // Prevent negative numbers
+    let pts = if cts < 0 && timestamp < (-cts) as u32 {
+        0
+    } else {
+        ((timestamp as i64) + (cts as i64)) as u64
+    };
+    buffer.set_pts(pts.mul_div_floor(1000_000, 1).unwrap());
+}
+
+// Structure names have to start with a letter and can only contain a few
+// special characters
+fn is_valid_structure_name(name: &str) -> bool {
+    let is_letter = |c| match c {
+        'a'...'z' | 'A'...'Z' => true,
+        _ => false,
+    };
+
+    name.chars().next().map_or(false, &is_letter) &&
+        name.chars()
+            .all(|c| is_letter(c) || c.is_digit(10) || "/-_.:+".contains(c))
+}
+
+// Objects become structures named after their key, values that have no
+// equivalent like references are dropped
+fn script_data_value_to_value(name: &str, value: &flavors::ScriptDataValue) -> Option<glib::Value> {
+    match *value {
+        flavors::ScriptDataValue::Number(n) => Some(n.to_value()),
+        flavors::ScriptDataValue::Boolean(b) => Some(b.to_value()),
+        flavors::ScriptDataValue::String(s) | flavors::ScriptDataValue::LongString(s) => {
+            Some(s.to_value())
+        }
+        flavors::ScriptDataValue::Object(ref objects) |
+        flavors::ScriptDataValue::ECMAArray(ref objects) => {
+            Some(script_data_to_structure(name, objects).to_value())
+        }
+        flavors::ScriptDataValue::StrictArray(ref values) => {
+            let values = values
+                .iter()
+                .filter_map(|v| script_data_value_to_value(name, v))
+                .collect::<Vec<_>>();
+            Some(gst::Array::from_owned(values).to_value())
+        }
+        _ => None,
+    }
+}
+
+fn script_data_to_structure(name: &str, objects: &[flavors::ScriptDataObject]) -> gst::Structure {
+    let mut structure =
+        gst::Structure::new_empty(if is_valid_structure_name(name) { name } else { "object" });
+
+    for object in objects {
+        if let Some(value) = script_data_value_to_value(object.name, &object.data) {
+            structure.set_value(object.name, value);
+        }
+    }
+
+    structure
+}
+
+// Standard base64 as used for the caption data, without line breaks
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for c in data.bytes() {
+        let v = match c {
+            b'A'...b'Z' => c - b'A',
+            b'a'...b'z' => c - b'a' + 26,
+            b'0'...b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+// Extracts the CEA-608 byte pairs of the first field from CEA-708 cc_data
+// triplets
+fn cc_data_to_cea608(cc_data: &[u8]) -> Vec<u8> {
+    let mut cea608 = Vec::new();
+
+    for triplet in cc_data.chunks(3) {
+        // cc_valid and cc_type 0
+        if triplet.len() == 3 && triplet[0] & 0x07 == 0x04 {
+            cea608.push(triplet[1]);
+            cea608.push(triplet[2]);
+        }
+    }
+
+    cea608
+}
+
+// Checks if data starts with a previous tag size and a tag header that are
+// plausible when resyncing. Returns the data size and previous tag size
+fn resync_tag_header(data: &[u8; 15]) -> Option<(usize, u64)> {
+    // Cheap check before parsing: only audio, video and script tags, without
+    // the filter bit
+    match data[4] {
+        8 | 9 | 18 => (),
+        _ => return None,
+    }
+
+    let data_size = match flavors::tag_header(&data[4..]) {
+        IResult::Done(_, ref tag_header) if tag_header.stream_id == 0 => {
+            tag_header.data_size as usize
+        }
+        _ => return None,
+    };
+
+    // Garbage sizes would make us wait for up to 16MB of data
+    if data_size > MAX_RESYNC_TAG_SIZE {
+        return None;
+    }
+
+    match nom::be_u32(&data[0..4]) {
+        IResult::Done(_, prev_tag_size) => Some((data_size, prev_tag_size as u64)),
+        IResult::Error(_) | IResult::Incomplete(_) => unreachable!(),
+    }
+}
+
+// Checks if data, which is at byte offset tag_offset, is a plausible tag
+// when resyncing. Its previous tag must start at one of the tag_starts, which
+// are offsets with the data size of the tag there if known. Returns the data
+// size of the tag
+fn resync_candidate(
+    data: &[u8; 15],
+    tag_offset: u64,
+    data_offset: Option<u64>,
+    tag_starts: &[(u64, Option<usize>)],
+) -> Option<usize> {
+    let (data_size, prev_tag_size) = match resync_tag_header(data) {
+        Some(header) => header,
+        None => return None,
+    };
+
+    // The previous tag size must be 0 before the first tag, otherwise the
+    // previous tag must end exactly at this one
+    let first_tag_offset = data_offset.unwrap_or(0);
+    let plausible = if tag_offset == first_tag_offset {
+        prev_tag_size == 0
+    } else if prev_tag_size >= 11 && first_tag_offset + 4 + prev_tag_size <= tag_offset {
+        let prev_tag_offset = tag_offset - 4 - prev_tag_size;
+        tag_starts.iter().any(|&(offset, size)| {
+            offset == prev_tag_offset &&
+                size.map(|size| 11 + size as u64 == prev_tag_size)
+                    .unwrap_or(true)
+        })
+    } else {
+        false
+    };
+
+    if plausible {
+        Some(data_size)
+    } else {
+        None
+    }
+}
+
+pub struct FlvDemux {
+    cat: gst::DebugCategory,
+    state: State,
+    adapter: Adapter,
+    // Byte offset of the end of the data in the adapter
+    offset: u64,
+    upstream_size: Option<u64>,
+    random_access: bool,
+    // Timestamp of the last tag, if it could be read in pull mode
+    probed_duration: Option<u64>,
+    // Byte offset of the first tag after the header
+    data_offset: Option<u64>,
+    // Sorted (time, byte offset) pairs of known keyframes, from the metadata
+    // and from the tags we parsed so far
+    seek_index: Vec<(u64, u64)>,
+    // Sorted (byte offset, timestamp offset) pairs of the tags from which on
+    // the timestamp offset changed, to continue on the same timeline after
+    // seeking
+    timestamp_offsets: Vec<(u64, i64)>,
+    discont_threshold: u64,
+    resilient: bool,
+    // Offset and timestamp state before the last seek, to continue from
+    // there if upstream fails to seek in push mode
+    pre_seek: Option<(u64, i64, Option<i64>, Option<u64>)>,
+    // Possible starts of the tag before the next resync candidate: the end of
+    // the last good tag, and the plausible tag headers skipped since then
+    resync_tag_starts: Vec<(u64, Option<usize>)>,
+    // Upstream has no more data until the next buffer
+    eos: bool,
+    // Only in >= State::Streaming
+    streaming_state: Option<StreamingState>,
+}
+
+impl FlvDemux {
+    pub fn new(_demuxer: &RsDemuxer) -> FlvDemux {
+        FlvDemux {
+            cat: gst::DebugCategory::new(
+                "rsflvdemux",
+                gst::DebugColorFlags::empty(),
+                "Rust FLV demuxer",
+            ),
+            state: State::Stopped,
+            adapter: Adapter::new(),
+            offset: 0,
+            upstream_size: None,
+            random_access: false,
+            probed_duration: None,
+            data_offset: None,
+            seek_index: Vec::new(),
+            timestamp_offsets: Vec::new(),
+            discont_threshold: DEFAULT_DISCONT_THRESHOLD,
+            resilient: DEFAULT_RESILIENT,
+            pre_seek: None,
+            resync_tag_starts: Vec::new(),
+            eos: false,
+            streaming_state: None,
+        }
+    }
+
+    pub fn new_boxed(demuxer: &RsDemuxer) -> Box<DemuxerImpl> {
+        Box::new(Self::new(demuxer))
+    }
+
+    fn handle_script_tag(
+        &mut self,
+        demuxer: &RsDemuxer,
+        tag_header: &flavors::TagHeader,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let data_size = tag_header.data_size as usize;
+        if self.adapter.get_available() < 15 + data_size {
+            return Ok(HandleBufferResult::NeedMoreData);
+        }
+
+        // Copy the tag data so that it can stay in the adapter until it is
+        // completely handled
+        let data = self.adapter.peek(15 + data_size).unwrap()[15..].to_vec();
+
+        match flavors::script_data(&data) {
+            IResult::Done(_, ref script_data) if script_data.name == "onMetaData" => {
+                self.adapter.flush(15 + data_size).unwrap();
+
                 gst_trace!(self.cat, obj: demuxer, "Got script tag: {:?}", script_data);
+
+                let metadata = Metadata::new(script_data);
+                gst_debug!(self.cat, obj: demuxer, "Got metadata: {:?}", metadata);
+
+                for &(time, offset) in &metadata.keyframes {
+                    add_index_entry(&mut self.seek_index, time, offset);
+                }
+
+                let streaming_state = self.streaming_state.as_mut().unwrap();
+                streaming_state.pending_tags = metadata.to_tags();
+
+                let audio_changed = streaming_state
+                    .audio
+                    .as_mut()
+                    .map(|a| a.update_with_metadata(&metadata))
+                    .unwrap_or(false);
+                let video_changed = streaming_state
+                    .video
+                    .as_mut()
+                    .map(|v| v.update_with_metadata(&metadata))
+                    .unwrap_or(false);
+                streaming_state.metadata = Some(metadata);
+
+                if audio_changed || video_changed {
+                    let mut streams = Vec::new();
+
+                    if audio_changed {
+                        if let Some(caps) = streaming_state.audio.as_ref().and_then(|a| a.to_caps())
+                        {
+                            streams.push(Stream::new(AUDIO_STREAM_ID, caps, String::from("audio")));
+                        }
+                    }
+                    if video_changed {
+                        if let Some(caps) = streaming_state.video.as_ref().and_then(|v| v.to_caps())
+                        {
+                            streams.push(Stream::new(VIDEO_STREAM_ID, caps, String::from("video")));
+                        }
+                    }
+
+                    return Ok(HandleBufferResult::StreamsChanged(streams));
+                }
+            }
+            IResult::Done(_, ref script_data) if script_data.name == "onCaption" => {
+                gst_trace!(self.cat, obj: demuxer, "Got script tag: {:?}", script_data);
+
+                return self.handle_caption(demuxer, tag_header, script_data);
+            }
+            IResult::Done(_, ref script_data) => {
+                self.adapter.flush(15 + data_size).unwrap();
+
+                gst_trace!(self.cat, obj: demuxer, "Got script tag: {:?}", script_data);
+
+                return Ok(self.handle_script_event(demuxer, tag_header, script_data));
             }
             IResult::Error(_) | IResult::Incomplete(_) => {
                 // ignore
+                self.adapter.flush(15 + data_size).unwrap();
             }
         }
 
         Ok(HandleBufferResult::Again)
     }
 
-    fn update_audio_stream(
+    // Script tags like onCuePoint are forwarded as custom downstream event
+    // and element message with their arguments as structure
+    fn handle_script_event(
         &mut self,
         demuxer: &RsDemuxer,
-        data_header: &flavors::AudioDataHeader,
+        tag_header: &flavors::TagHeader,
+        script_data: &flavors::ScriptData,
+    ) -> HandleBufferResult {
+        if !is_valid_structure_name(script_data.name) {
+            gst_debug!(
+                self.cat,
+                obj: demuxer,
+                "Ignoring script tag {:?}",
+                script_data.name
+            );
+            return HandleBufferResult::Again;
+        }
+
+        let mut structure = match script_data.arguments {
+            flavors::ScriptDataValue::Object(ref objects) |
+            flavors::ScriptDataValue::ECMAArray(ref objects) => {
+                script_data_to_structure(script_data.name, objects)
+            }
+            ref value => {
+                let mut structure = gst::Structure::new_empty(script_data.name);
+                if let Some(value) = script_data_value_to_value(script_data.name, value) {
+                    structure.set_value("value", value);
+                }
+                structure
+            }
+        };
+
+        // Same timestamp offset as for the media
+        let timestamp_offset = self.streaming_state.as_ref().unwrap().timestamp_offset;
+        let timestamp = cmp::max(tag_header.timestamp as i64 + timestamp_offset, 0) as u64;
+        structure.set_value(
+            "timestamp",
+            timestamp.mul_div_floor(1000_000, 1).unwrap().to_value(),
+        );
+
+        gst_debug!(self.cat, obj: demuxer, "Got script event {:?}", structure);
+
+        let msg = gst::Message::new_element(structure.clone())
+            .src(Some(demuxer))
+            .build();
+        demuxer.post_message(&msg);
+
+        HandleBufferResult::Event(None, gst::Event::new_custom_downstream(structure).build())
+    }
+
+    // The caption data is base64 encoded and either CEA-708 cc_data or plain
+    // CEA-608 byte pairs. The caption stream is added with the first caption
+    fn handle_caption(
+        &mut self,
+        demuxer: &RsDemuxer,
+        tag_header: &flavors::TagHeader,
+        script_data: &flavors::ScriptData,
     ) -> Result<HandleBufferResult, FlowError> {
+        let data_size = tag_header.data_size as usize;
+
+        let mut caption_type = None;
+        let mut caption_data = None;
+        match script_data.arguments {
+            flavors::ScriptDataValue::Object(ref objects) |
+            flavors::ScriptDataValue::ECMAArray(ref objects) => for object in objects {
+                match (object.name, &object.data) {
+                    ("type", &flavors::ScriptDataValue::String(t)) => caption_type = Some(t),
+                    ("data", &flavors::ScriptDataValue::String(d)) |
+                    ("data", &flavors::ScriptDataValue::LongString(d)) => {
+                        caption_data = decode_base64(d)
+                    }
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+
+        let cea608 = match (caption_type, caption_data) {
+            (Some("708"), Some(ref data)) => cc_data_to_cea608(data),
+            (_, Some(data)) => data,
+            (_, None) => {
+                gst_warning!(self.cat, obj: demuxer, "Caption without valid data");
+                Vec::new()
+            }
+        };
+
+        if cea608.is_empty() {
+            self.adapter.flush(15 + data_size).unwrap();
+            return Ok(HandleBufferResult::Again);
+        }
+
+        // The tag stays in the adapter and is handled again afterwards
+        let streaming_state = self.streaming_state.as_mut().unwrap();
+        if !streaming_state.captions {
+            streaming_state.captions = true;
+
+            let caps = gst::Caps::new_simple("closedcaption/x-cea-608", &[("format", &"raw")]);
+            let stream = Stream::new(CAPTION_STREAM_ID, caps, String::from("caption"));
+            return Ok(HandleBufferResult::StreamAdded(stream));
+        }
+
+        self.adapter.flush(15 + data_size).unwrap();
+
+        let mut buffer = gst::Buffer::from_vec(cea608).unwrap();
+        buffer.get_mut().unwrap().set_pts(
+            (tag_header.timestamp as u64)
+                .mul_div_floor(1000_000, 1)
+                .unwrap(),
+        );
+
         gst_trace!(
             self.cat,
             obj: demuxer,
-            "Got audio data header: {:?}",
-            data_header
+            "Outputting caption buffer {:?} for tag {:?}",
+            buffer,
+            tag_header
         );
 
-        let streaming_state = self.streaming_state.as_mut().unwrap();
+        Ok(HandleBufferResult::BufferForStream(CAPTION_STREAM_ID, buffer))
+    }
 
-        let new_audio_format = AudioFormat::new(
-            data_header,
-            &streaming_state.metadata,
-            &streaming_state.aac_sequence_header,
-        );
+    fn update_audio_stream(
+        &mut self,
+        demuxer: &RsDemuxer,
+        new_audio_format: AudioFormat,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let streaming_state = self.streaming_state.as_mut().unwrap();
 
         if streaming_state.audio.as_ref() != Some(&new_audio_format) {
             gst_debug!(
@@ -635,7 +1756,23 @@ impl FlvDemux {
         tag_header: &flavors::TagHeader,
         data_header: &flavors::AudioDataHeader,
     ) -> Result<HandleBufferResult, FlowError> {
-        let res = self.update_audio_stream(demuxer, data_header);
+        gst_trace!(
+            self.cat,
+            obj: demuxer,
+            "Got audio data header: {:?}",
+            data_header
+        );
+
+        let new_audio_format = {
+            let streaming_state = self.streaming_state.as_ref().unwrap();
+            AudioFormat::new(
+                data_header,
+                &streaming_state.metadata,
+                &streaming_state.audio_sequence_header,
+            )
+        };
+
+        let res = self.update_audio_stream(demuxer, new_audio_format);
         match res {
             Ok(HandleBufferResult::Again) => (),
             _ => return res,
@@ -665,7 +1802,7 @@ impl FlvDemux {
             self.adapter.peek_into(&mut data).unwrap();
             match flavors::aac_audio_packet_header(&data[16..]) {
                 IResult::Error(_) | IResult::Incomplete(_) => {
-                    unimplemented!();
+                    return self.handle_corrupted_data(demuxer, "Invalid AAC packet header");
                 }
                 IResult::Done(_, header) => {
                     gst_trace!(self.cat, obj: demuxer, "Got AAC packet header {:?}", header);
@@ -684,7 +1821,7 @@ impl FlvDemux {
                             );
 
                             let streaming_state = self.streaming_state.as_mut().unwrap();
-                            streaming_state.aac_sequence_header = Some(buffer);
+                            streaming_state.audio_sequence_header = Some(buffer);
                             return Ok(HandleBufferResult::Again);
                         }
                         flavors::AACPacketType::Raw => {
@@ -708,7 +1845,7 @@ impl FlvDemux {
         self.adapter.flush(16).unwrap();
 
         let offset = match audio.format {
-            flavors::SoundFormat::AAC => 1,
+            AudioCodec::Legacy(flavors::SoundFormat::AAC) => 1,
             _ => 0,
         };
 
@@ -752,25 +1889,109 @@ impl FlvDemux {
         Ok(HandleBufferResult::BufferForStream(AUDIO_STREAM_ID, buffer))
     }
 
-    fn update_video_stream(
+    fn handle_ex_audio_tag(
         &mut self,
         demuxer: &RsDemuxer,
-        data_header: &flavors::VideoDataHeader,
+        tag_header: &flavors::TagHeader,
     ) -> Result<HandleBufferResult, FlowError> {
+        let data_size = tag_header.data_size as usize;
+        if self.adapter.get_available() < 15 + data_size {
+            return Ok(HandleBufferResult::NeedMoreData);
+        }
+
+        let body = {
+            let data = &self.adapter.peek(15 + data_size).unwrap()[15..];
+            parse_ex_tag_body(data, EX_AUDIO_PACKET_TYPE_MULTITRACK)
+        };
+
+        let body = match body {
+            Some(ref body) if &body.fourcc == b"Opus" => body,
+            _ => {
+                gst_warning!(
+                    self.cat,
+                    obj: demuxer,
+                    "Unsupported enhanced audio tag {:?}",
+                    body
+                );
+                self.adapter.flush(15 + data_size).unwrap();
+                return Ok(HandleBufferResult::Again);
+            }
+        };
+
+        gst_trace!(self.cat, obj: demuxer, "Got enhanced audio tag {:?}", body);
+
+        match body.packet_type {
+            EX_PACKET_TYPE_SEQUENCE_START => {
+                self.adapter.flush(15 + body.start).unwrap();
+                let buffer = self.adapter.get_buffer(body.end - body.start).unwrap();
+                self.adapter.flush(data_size - body.end).unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Got Opus sequence header {:?} of size {}",
+                    buffer,
+                    body.end - body.start
+                );
+
+                let streaming_state = self.streaming_state.as_mut().unwrap();
+                streaming_state.audio_sequence_header = Some(buffer);
+                return Ok(HandleBufferResult::Again);
+            }
+            EX_PACKET_TYPE_CODED_FRAMES => (),
+            _ => {
+                // Skip
+                self.adapter.flush(15 + data_size).unwrap();
+                return Ok(HandleBufferResult::Again);
+            }
+        }
+
+        let new_audio_format = {
+            let streaming_state = self.streaming_state.as_ref().unwrap();
+            AudioFormat::new_opus(
+                &streaming_state.metadata,
+                &streaming_state.audio_sequence_header,
+            )
+        };
+
+        let res = self.update_audio_stream(demuxer, new_audio_format);
+        match res {
+            Ok(HandleBufferResult::Again) => (),
+            _ => return res,
+        }
+
+        if self.streaming_state.as_ref().unwrap().audio == None || body.start == body.end {
+            self.adapter.flush(15 + data_size).unwrap();
+            return Ok(HandleBufferResult::Again);
+        }
+
+        self.adapter.flush(15 + body.start).unwrap();
+        let mut buffer = self.adapter.get_buffer(body.end - body.start).unwrap();
+        self.adapter.flush(data_size - body.end).unwrap();
+
+        buffer.get_mut().unwrap().set_pts(
+            (tag_header.timestamp as u64)
+                .mul_div_floor(1000_000, 1)
+                .unwrap(),
+        );
+
         gst_trace!(
             self.cat,
             obj: demuxer,
-            "Got video data header: {:?}",
-            data_header
+            "Outputting audio buffer {:?} for tag {:?} of size {}",
+            buffer,
+            tag_header,
+            body.end - body.start
         );
 
-        let streaming_state = self.streaming_state.as_mut().unwrap();
+        Ok(HandleBufferResult::BufferForStream(AUDIO_STREAM_ID, buffer))
+    }
 
-        let new_video_format = VideoFormat::new(
-            data_header,
-            &streaming_state.metadata,
-            &streaming_state.avc_sequence_header,
-        );
+    fn update_video_stream(
+        &mut self,
+        demuxer: &RsDemuxer,
+        new_video_format: VideoFormat,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let streaming_state = self.streaming_state.as_mut().unwrap();
 
         if streaming_state.video.as_ref() != Some(&new_video_format) {
             gst_debug!(
@@ -813,7 +2034,23 @@ impl FlvDemux {
         tag_header: &flavors::TagHeader,
         data_header: &flavors::VideoDataHeader,
     ) -> Result<HandleBufferResult, FlowError> {
-        let res = self.update_video_stream(demuxer, data_header);
+        gst_trace!(
+            self.cat,
+            obj: demuxer,
+            "Got video data header: {:?}",
+            data_header
+        );
+
+        let new_video_format = {
+            let streaming_state = self.streaming_state.as_ref().unwrap();
+            VideoFormat::new(
+                VideoCodec::Legacy(data_header.codec_id),
+                &streaming_state.metadata,
+                &streaming_state.video_sequence_header,
+            )
+        };
+
+        let res = self.update_video_stream(demuxer, new_video_format);
         match res {
             Ok(HandleBufferResult::Again) => (),
             _ => return res,
@@ -845,7 +2082,7 @@ impl FlvDemux {
             self.adapter.peek_into(&mut data).unwrap();
             match flavors::avc_video_packet_header(&data[16..]) {
                 IResult::Error(_) | IResult::Incomplete(_) => {
-                    unimplemented!();
+                    return self.handle_corrupted_data(demuxer, "Invalid AVC packet header");
                 }
                 IResult::Done(_, header) => {
                     gst_trace!(self.cat, obj: demuxer, "Got AVC packet header {:?}", header);
@@ -864,7 +2101,7 @@ impl FlvDemux {
                             );
 
                             let streaming_state = self.streaming_state.as_mut().unwrap();
-                            streaming_state.avc_sequence_header = Some(buffer);
+                            streaming_state.video_sequence_header = Some(buffer);
                             return Ok(HandleBufferResult::Again);
                         }
                         flavors::AVCPacketType::NALU => {
@@ -897,8 +2134,9 @@ impl FlvDemux {
         self.adapter.flush(16).unwrap();
 
         let offset = match video.format {
-            flavors::CodecId::VP6 | flavors::CodecId::VP6A => 1,
-            flavors::CodecId::H264 => 4,
+            VideoCodec::Legacy(flavors::CodecId::VP6) |
+            VideoCodec::Legacy(flavors::CodecId::VP6A) => 1,
+            VideoCodec::Legacy(flavors::CodecId::H264) => 4,
             _ => 0,
         };
 
@@ -921,37 +2159,471 @@ impl FlvDemux {
             .get_buffer((tag_header.data_size - 1 - offset) as usize)
             .unwrap();
 
+        set_video_buffer_metadata(
+            buffer.get_mut().unwrap(),
+            tag_header.timestamp,
+            cts,
+            is_keyframe,
+        );
+
+        gst_trace!(
+            self.cat,
+            obj: demuxer,
+            "Outputting video buffer {:?} for tag {:?} of size {}, keyframe: {}",
+            buffer,
+            tag_header,
+            tag_header.data_size - 1 - offset,
+            is_keyframe
+        );
+
+        Ok(HandleBufferResult::BufferForStream(VIDEO_STREAM_ID, buffer))
+    }
+
+    fn handle_ex_video_tag(
+        &mut self,
+        demuxer: &RsDemuxer,
+        tag_header: &flavors::TagHeader,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let data_size = tag_header.data_size as usize;
+        if self.adapter.get_available() < 15 + data_size {
+            return Ok(HandleBufferResult::NeedMoreData);
+        }
+
+        let (frame_type, body) = {
+            let data = &self.adapter.peek(15 + data_size).unwrap()[15..];
+            (
+                data.first().map(|&b| (b >> 4) & 0x07).unwrap_or(0),
+                parse_ex_tag_body(data, EX_VIDEO_PACKET_TYPE_MULTITRACK),
+            )
+        };
+
+        let body = match body {
+            Some(body) => body,
+            None => {
+                gst_warning!(self.cat, obj: demuxer, "Invalid enhanced video tag");
+                self.adapter.flush(15 + data_size).unwrap();
+                return Ok(HandleBufferResult::Again);
+            }
+        };
+
+        gst_trace!(
+            self.cat,
+            obj: demuxer,
+            "Got enhanced video tag {:?} with frame type {}",
+            body,
+            frame_type
+        );
+
+        if frame_type == EX_VIDEO_FRAME_TYPE_COMMAND {
+            // Skip
+            self.adapter.flush(15 + data_size).unwrap();
+            return Ok(HandleBufferResult::Again);
+        }
+
+        let format = match &body.fourcc {
+            b"avc1" => VideoCodec::Legacy(flavors::CodecId::H264),
+            b"hvc1" => VideoCodec::Hevc,
+            b"av01" => VideoCodec::Av1,
+            b"vp09" => VideoCodec::Vp9,
+            _ => {
+                gst_warning!(
+                    self.cat,
+                    obj: demuxer,
+                    "Unsupported video FourCC {:?}",
+                    body.fourcc
+                );
+                self.adapter.flush(15 + data_size).unwrap();
+                return Ok(HandleBufferResult::Again);
+            }
+        };
+
+        match body.packet_type {
+            EX_PACKET_TYPE_SEQUENCE_START => {
+                self.adapter.flush(15 + body.start).unwrap();
+                let buffer = self.adapter.get_buffer(body.end - body.start).unwrap();
+                self.adapter.flush(data_size - body.end).unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Got {:?} sequence header {:?} of size {}",
+                    format,
+                    buffer,
+                    body.end - body.start
+                );
+
+                let streaming_state = self.streaming_state.as_mut().unwrap();
+                streaming_state.video_sequence_header = Some(buffer);
+                return Ok(HandleBufferResult::Again);
+            }
+            EX_PACKET_TYPE_CODED_FRAMES | EX_PACKET_TYPE_CODED_FRAMES_X => (),
+            _ => {
+                // Skip end of sequence, metadata and everything else
+                self.adapter.flush(15 + data_size).unwrap();
+                return Ok(HandleBufferResult::Again);
+            }
+        }
+
+        let new_video_format = {
+            let streaming_state = self.streaming_state.as_ref().unwrap();
+            VideoFormat::new(
+                format,
+                &streaming_state.metadata,
+                &streaming_state.video_sequence_header,
+            )
+        };
+
+        let res = self.update_video_stream(demuxer, new_video_format);
+        match res {
+            Ok(HandleBufferResult::Again) => (),
+            _ => return res,
+        }
+
+        // Only AVC and HEVC coded frames come with a composition time offset,
+        // CodedFramesX implies an offset of 0
+        let cts_size = match format {
+            VideoCodec::Legacy(flavors::CodecId::H264) | VideoCodec::Hevc
+                if body.packet_type == EX_PACKET_TYPE_CODED_FRAMES =>
+            {
+                3
+            }
+            _ => 0,
+        };
+
+        if self.streaming_state.as_ref().unwrap().video == None ||
+            body.end - body.start <= cts_size
         {
-            let buffer = buffer.get_mut().unwrap();
-            if !is_keyframe {
-                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            self.adapter.flush(15 + data_size).unwrap();
+            return Ok(HandleBufferResult::Again);
+        }
+
+        self.adapter.flush(15 + body.start).unwrap();
+
+        let mut cts = 0;
+        if cts_size > 0 {
+            let mut data = [0u8; 3];
+            self.adapter.peek_into(&mut data).unwrap();
+            // Signed 24 bit integer
+            cts = ((data[0] as i8 as i32) << 16) | ((data[1] as i32) << 8) | (data[2] as i32);
+            self.adapter.flush(cts_size).unwrap();
+        }
+
+        let mut buffer = self.adapter
+            .get_buffer(body.end - body.start - cts_size)
+            .unwrap();
+        self.adapter.flush(data_size - body.end).unwrap();
+
+        let is_keyframe = frame_type == 1;
+        set_video_buffer_metadata(
+            buffer.get_mut().unwrap(),
+            tag_header.timestamp,
+            cts,
+            is_keyframe,
+        );
+
+        gst_trace!(
+            self.cat,
+            obj: demuxer,
+            "Outputting video buffer {:?} for tag {:?} of size {}, keyframe: {}",
+            buffer,
+            tag_header,
+            body.end - body.start - cts_size,
+            is_keyframe
+        );
+
+        Ok(HandleBufferResult::BufferForStream(VIDEO_STREAM_ID, buffer))
+    }
+
+    // Checks if the previous tag size after the tag at the start of the adapter
+    // is consistent with the tag's data size. Needs 15 + data_size + 4 bytes
+    fn tag_size_matches(&mut self, data_size: usize) -> bool {
+        let data = self.adapter.peek(15 + data_size + 4).unwrap();
+        match nom::be_u32(&data[(15 + data_size)..]) {
+            IResult::Done(_, tag_size) => tag_size as usize == 11 + data_size,
+            IResult::Error(_) | IResult::Incomplete(_) => unreachable!(),
+        }
+    }
+
+    // Skips data until the adapter starts with a plausible previous tag size
+    // and tag header, returns false if more data is needed for that
+    fn resync(&mut self) -> bool {
+        while self.adapter.get_available() >= 15 {
+            let mut data = [0u8; 15];
+            self.adapter.peek_into(&mut data).unwrap();
+
+            let tag_offset = self.offset - self.adapter.get_available() as u64;
+            let data_size = resync_candidate(
+                &data,
+                tag_offset,
+                self.data_offset,
+                &self.resync_tag_starts,
+            );
+
+            // Tags can't extend after the end of the stream
+            let data_size = match (data_size, self.upstream_size) {
+                (Some(data_size), Some(size))
+                    if tag_offset + 15 + data_size as u64 + 4 > size =>
+                {
+                    None
+                }
+                (data_size, _) => data_size,
+            };
+
+            if let Some(data_size) = data_size {
+                // After EOS no more data arrives to check the candidate,
+                // try the next byte instead
+                if self.adapter.get_available() < 15 + data_size + 4 {
+                    if !self.eos {
+                        return false;
+                    }
+                } else if self.tag_size_matches(data_size) {
+                    self.resync_tag_starts.clear();
+                    return true;
+                }
             }
-            buffer.set_dts(
-                (tag_header.timestamp as u64)
-                    .mul_div_floor(1000_000, 1)
-                    .unwrap(),
+
+            // Tags before this one can't be the previous tag of later ones
+            // anymore, except after the end of the last good tag
+            if let Some((data_size, _)) = resync_tag_header(&data) {
+                self.resync_tag_starts.retain(|&(offset, size)| {
+                    size.is_none() || offset + 15 + MAX_RESYNC_TAG_SIZE as u64 >= tag_offset
+                });
+                self.resync_tag_starts.push((tag_offset, Some(data_size)));
+            }
+
+            self.adapter.flush(1).unwrap();
+        }
+
+        false
+    }
+
+    // Parses the tag at the start of the adapter and returns its offset,
+    // header and if it is a keyframe. Needs 16 bytes
+    fn peek_tag(&mut self) -> Option<(u64, flavors::TagHeader, bool)> {
+        let tag_offset = self.offset - self.adapter.get_available() as u64;
+
+        let mut data = [0u8; 16];
+        self.adapter.peek_into(&mut data).unwrap();
+
+        let tag_header = match flavors::tag_header(&data[4..]) {
+            IResult::Done(_, tag_header) => tag_header,
+            IResult::Error(_) | IResult::Incomplete(_) => return None,
+        };
+
+        // Legacy and enhanced video tags both have the frame type in bits
+        // 4-6, without video every audio frame is a keyframe
+        let keyframe = match tag_header.tag_type {
+            flavors::TagType::Video => (data[15] >> 4) & 0x07 == 1,
+            flavors::TagType::Audio => !self.streaming_state.as_ref().unwrap().expect_video,
+            flavors::TagType::Script => false,
+        };
+
+        Some((tag_offset, tag_header, keyframe))
+    }
+
+    // Probes the middle of the byte range, or scans it linearly from low if
+    // it is small enough. Returns the offset to continue reading from
+    fn bisect(&mut self, demuxer: &RsDemuxer, target: u64, low: u64, high: u64) -> u64 {
+        let offset = if high <= low + BISECT_MIN_RANGE {
+            gst_debug!(
+                self.cat,
+                obj: demuxer,
+                "Scanning for keyframe before {} from offset {}",
+                target,
+                low
+            );
+            self.state = State::Scanning {
+                target: target,
+                keyframe: low,
+            };
+            low
+        } else {
+            let position = low + (high - low) / 2;
+            gst_debug!(
+                self.cat,
+                obj: demuxer,
+                "Bisecting for {} in {}-{} at offset {}",
+                target,
+                low,
+                high,
+                position
             );
+            self.state = State::Bisecting {
+                target: target,
+                low: low,
+                high: high,
+                position: position,
+                synced: false,
+            };
+            position
+        };
+
+        self.adapter.clear();
+        self.offset = offset;
+        self.resync_tag_starts.clear();
+
+        offset
+    }
+
+    // Continues streaming at the keyframe found by bisecting or scanning
+    fn finish_seek_scan(
+        &mut self,
+        demuxer: &RsDemuxer,
+        keyframe: u64,
+    ) -> Result<HandleBufferResult, FlowError> {
+        gst_debug!(self.cat, obj: demuxer, "Continuing at keyframe at offset {}", keyframe);
+
+        self.state = State::Streaming;
+        self.adapter.clear();
+        self.offset = keyframe;
+
+        Ok(HandleBufferResult::NeedDataFromOffset(keyframe))
+    }
+
+    // In resilient mode skip the broken tag by scanning for the next valid
+    // one, otherwise fail
+    fn handle_corrupted_data(
+        &mut self,
+        demuxer: &RsDemuxer,
+        reason: &str,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let offset = self.offset - self.adapter.get_available() as u64;
+
+        if !self.resilient {
+            return Err(FlowError::Error(error_msg!(
+                gst::StreamError::Demux,
+                ["{} at offset {}", reason, offset]
+            )));
+        }
+
+        gst_warning!(
+            self.cat,
+            obj: demuxer,
+            "{} at offset {}, resyncing",
+            reason,
+            offset
+        );
+        error_msg!(
+            gst::StreamError::Demux,
+            ["{} at offset {}, resyncing", reason, offset]
+        ).post_warning(demuxer);
+
+        self.adapter.flush(1).unwrap();
+        self.state = State::Resyncing;
+        self.resync_tag_starts = vec![(offset, None)];
+
+        let streaming_state = self.streaming_state.as_mut().unwrap();
+        streaming_state.audio_discont = true;
+        streaming_state.video_discont = true;
+
+        Ok(HandleBufferResult::Again)
+    }
+
+    // Unwraps the 32 bit millisecond tag timestamps and rebases them if they
+    // jump by more than the discont threshold, e.g. after encoder restarts.
+    // All streams share the same offset so that they stay aligned
+    fn update_timestamps(
+        &mut self,
+        demuxer: &RsDemuxer,
+        stream_index: StreamIndex,
+        tag_offset: u64,
+        tag_timestamp: u32,
+        buffer: &mut gst::Buffer,
+    ) {
+        let discont_threshold = self.discont_threshold;
+        let streaming_state = self.streaming_state.as_mut().unwrap();
+        let previous_offset = streaming_state.timestamp_offset;
+
+        let mut timestamp = tag_timestamp as i64 + streaming_state.timestamp_offset;
+        if let Some(last_timestamp) = streaming_state.last_timestamp {
+            if last_timestamp - timestamp > (1 << 31) {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Timestamp wrapped around at {}ms",
+                    last_timestamp
+                );
+                streaming_state.timestamp_offset += 1 << 32;
+                timestamp += 1 << 32;
+            }
+
+            let diff = timestamp - last_timestamp;
+            if discont_threshold > 0 &&
+                (diff.abs() as u64).saturating_mul(1_000_000) > discont_threshold
+            {
+                gst_warning!(
+                    self.cat,
+                    obj: demuxer,
+                    "Timestamp discontinuity of {}ms at {}ms",
+                    diff,
+                    last_timestamp
+                );
+                streaming_state.timestamp_offset -= diff;
+                timestamp = last_timestamp;
+                streaming_state.audio_discont = true;
+                streaming_state.video_discont = true;
+            }
+        }
+        streaming_state.last_timestamp = Some(timestamp);
+
+        if streaming_state.timestamp_offset != previous_offset {
+            let entry = (tag_offset, streaming_state.timestamp_offset);
+            match self.timestamp_offsets
+                .binary_search_by_key(&tag_offset, |&(offset, _)| offset)
+            {
+                Ok(idx) => self.timestamp_offsets[idx] = entry,
+                Err(idx) => self.timestamp_offsets.insert(idx, entry),
+            }
+        }
+
+        let discont = match stream_index {
+            AUDIO_STREAM_ID => mem::replace(&mut streaming_state.audio_discont, false),
+            VIDEO_STREAM_ID => mem::replace(&mut streaming_state.video_discont, false),
+            _ => false,
+        };
 
+        let offset = streaming_state.timestamp_offset * 1_000_000;
+        let buffer = buffer.get_mut().unwrap();
+        if offset != 0 {
             // Prevent negative numbers
-            let pts = if cts < 0 && tag_header.timestamp < (-cts) as u32 {
-                0
-            } else {
-                ((tag_header.timestamp as i64) + (cts as i64)) as u64
-            };
-            buffer.set_pts(pts.mul_div_floor(1000_000, 1).unwrap());
+            let pts = buffer.get_pts();
+            if pts != gst::CLOCK_TIME_NONE {
+                buffer.set_pts(cmp::max(pts as i64 + offset, 0) as u64);
+            }
+            let dts = buffer.get_dts();
+            if dts != gst::CLOCK_TIME_NONE {
+                buffer.set_dts(cmp::max(dts as i64 + offset, 0) as u64);
+            }
+        }
+        if discont {
+            buffer.set_flags(gst::BufferFlags::DISCONT);
         }
+    }
 
-        gst_trace!(
+    // Continues with the first tag after trying to read the duration
+    fn finish_duration_probe(
+        &mut self,
+        demuxer: &RsDemuxer,
+        audio: bool,
+        video: bool,
+    ) -> Result<HandleBufferResult, FlowError> {
+        let data_offset = self.data_offset.unwrap();
+
+        let mut streaming_state = StreamingState::new(audio, video);
+        streaming_state.duration_changed = self.probed_duration.is_some();
+
+        self.state = State::Streaming;
+        self.streaming_state = Some(streaming_state);
+        self.adapter.clear();
+        self.offset = data_offset;
+
+        gst_debug!(
             self.cat,
             obj: demuxer,
-            "Outputting video buffer {:?} for tag {:?} of size {}, keyframe: {}",
-            buffer,
-            tag_header,
-            tag_header.data_size - 1 - offset,
-            is_keyframe
+            "Continuing at first tag at offset {}",
+            data_offset
         );
 
-        Ok(HandleBufferResult::BufferForStream(VIDEO_STREAM_ID, buffer))
+        Ok(HandleBufferResult::NeedDataFromOffset(data_offset))
     }
 
     fn update_state(&mut self, demuxer: &RsDemuxer) -> Result<HandleBufferResult, FlowError> {
@@ -975,6 +2647,9 @@ impl FlvDemux {
                                 header.offset - 9
                             };
 
+                            let header_offset = self.offset - self.adapter.get_available() as u64;
+                            self.data_offset = Some(header_offset + 9 + skip as u64);
+
                             self.adapter.flush(9).unwrap();
 
                             self.state = State::Skipping {
@@ -997,6 +2672,30 @@ impl FlvDemux {
                 video,
                 skip_left: 0,
             } => {
+                // The metadata often has no duration, so read it from the last
+                // tag first if we can
+                match (self.random_access, self.upstream_size, self.data_offset) {
+                    (true, Some(size), Some(data_offset)) if self.probed_duration.is_none() &&
+                        size >= data_offset + 4 + 11 + 4 =>
+                    {
+                        gst_debug!(
+                            self.cat,
+                            obj: demuxer,
+                            "Reading last tag size at offset {}",
+                            size - 4
+                        );
+                        self.state = State::ProbingDuration {
+                            audio: audio,
+                            video: video,
+                            last_tag_size: None,
+                        };
+                        self.adapter.clear();
+                        self.offset = size - 4;
+                        return Ok(HandleBufferResult::NeedDataFromOffset(size - 4));
+                    }
+                    _ => (),
+                }
+
                 self.state = State::Streaming;
                 self.streaming_state = Some(StreamingState::new(audio, video));
 
@@ -1011,18 +2710,233 @@ impl FlvDemux {
 
                 Ok(HandleBufferResult::Again)
             }
+            State::ProbingDuration {
+                audio,
+                video,
+                last_tag_size: None,
+            } => {
+                if self.adapter.get_available() < 4 {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                let mut data = [0u8; 4];
+                self.adapter.peek_into(&mut data).unwrap();
+                let last_tag_size = match nom::be_u32(&data) {
+                    IResult::Done(_, last_tag_size) => last_tag_size,
+                    IResult::Error(_) | IResult::Incomplete(_) => unreachable!(),
+                };
+
+                let size = self.upstream_size.unwrap();
+                let data_offset = self.data_offset.unwrap();
+                if last_tag_size < 11 || data_offset + 4 + last_tag_size as u64 + 4 > size {
+                    gst_debug!(
+                        self.cat,
+                        obj: demuxer,
+                        "Invalid last tag size {}",
+                        last_tag_size
+                    );
+                    return self.finish_duration_probe(demuxer, audio, video);
+                }
+
+                let offset = size - 4 - last_tag_size as u64;
+                gst_debug!(self.cat, obj: demuxer, "Reading last tag at offset {}", offset);
+                self.state = State::ProbingDuration {
+                    audio: audio,
+                    video: video,
+                    last_tag_size: Some(last_tag_size),
+                };
+                self.adapter.clear();
+                self.offset = offset;
+                Ok(HandleBufferResult::NeedDataFromOffset(offset))
+            }
+            State::ProbingDuration {
+                audio,
+                video,
+                last_tag_size: Some(last_tag_size),
+            } => {
+                if self.adapter.get_available() < 11 {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                let mut data = [0u8; 11];
+                self.adapter.peek_into(&mut data).unwrap();
+                match flavors::tag_header(&data) {
+                    IResult::Done(_, ref tag_header) if tag_header.data_size + 11 ==
+                        last_tag_size =>
+                    {
+                        let duration = tag_header.timestamp as u64 * 1_000_000;
+                        gst_debug!(
+                            self.cat,
+                            obj: demuxer,
+                            "Got duration {} from last tag",
+                            duration
+                        );
+                        self.probed_duration = Some(duration);
+                    }
+                    _ => {
+                        gst_debug!(self.cat, obj: demuxer, "No valid last tag");
+                    }
+                }
+
+                self.finish_duration_probe(demuxer, audio, video)
+            }
+            State::Resyncing => {
+                if !self.resync() {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Resynced at offset {}",
+                    self.offset - self.adapter.get_available() as u64
+                );
+                self.state = State::Streaming;
+                Ok(HandleBufferResult::Again)
+            }
+            State::Bisecting {
+                target,
+                low,
+                high,
+                position,
+                synced: false,
+            } => {
+                let found = self.resync();
+
+                // Nothing usable in the upper half
+                if self.offset - self.adapter.get_available() as u64 + 15 > high {
+                    let offset = self.bisect(demuxer, target, low, position);
+                    return Ok(HandleBufferResult::NeedDataFromOffset(offset));
+                }
+
+                if !found {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                self.state = State::Bisecting {
+                    target: target,
+                    low: low,
+                    high: high,
+                    position: position,
+                    synced: true,
+                };
+                Ok(HandleBufferResult::Again)
+            }
+            State::Bisecting {
+                target,
+                low,
+                high,
+                position,
+                synced: true,
+            } => {
+                if self.adapter.get_available() < 16 {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                let (tag_offset, tag_header, keyframe) = match self.peek_tag() {
+                    Some(tag) => tag,
+                    None => {
+                        self.adapter.flush(1).unwrap();
+                        self.state = State::Bisecting {
+                            target: target,
+                            low: low,
+                            high: high,
+                            position: position,
+                            synced: false,
+                        };
+                        return Ok(HandleBufferResult::Again);
+                    }
+                };
+                let timestamp = tag_header.timestamp as u64 * 1_000_000;
+
+                // No keyframe before the target after the middle, continue in
+                // the lower half
+                if tag_offset + 15 > high || timestamp > target {
+                    let offset = self.bisect(demuxer, target, low, position);
+                    return Ok(HandleBufferResult::NeedDataFromOffset(offset));
+                }
+
+                if keyframe {
+                    add_index_entry(&mut self.seek_index, timestamp, tag_offset);
+                    let offset = self.bisect(demuxer, target, tag_offset, high);
+                    return Ok(HandleBufferResult::NeedDataFromOffset(offset));
+                }
+
+                let tag_size = 15 + tag_header.data_size as usize;
+                if self.adapter.get_available() < tag_size {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+                self.adapter.flush(tag_size).unwrap();
+
+                Ok(HandleBufferResult::Again)
+            }
+            State::Scanning { target, keyframe } => {
+                let tag_offset = self.offset - self.adapter.get_available() as u64;
+                if let Some(size) = self.upstream_size {
+                    if tag_offset + 15 > size {
+                        return self.finish_seek_scan(demuxer, keyframe);
+                    }
+                }
+
+                if self.adapter.get_available() < 16 {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                let (tag_offset, tag_header, is_keyframe) = match self.peek_tag() {
+                    Some(tag) => tag,
+                    None => return self.finish_seek_scan(demuxer, keyframe),
+                };
+                let timestamp = tag_header.timestamp as u64 * 1_000_000;
+
+                if timestamp > target {
+                    return self.finish_seek_scan(demuxer, keyframe);
+                }
+
+                let keyframe = if is_keyframe {
+                    add_index_entry(&mut self.seek_index, timestamp, tag_offset);
+                    tag_offset
+                } else {
+                    keyframe
+                };
+                self.state = State::Scanning {
+                    target: target,
+                    keyframe: keyframe,
+                };
+
+                let tag_size = 15 + tag_header.data_size as usize;
+                if self.adapter.get_available() < tag_size {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+                self.adapter.flush(tag_size).unwrap();
+
+                Ok(HandleBufferResult::Again)
+            }
             State::Streaming => {
                 if self.adapter.get_available() < 16 {
                     return Ok(HandleBufferResult::NeedMoreData);
                 }
 
+                if !self.streaming_state.as_ref().unwrap().pending_tags.is_empty() {
+                    let (index, tags) = self.streaming_state
+                        .as_mut()
+                        .unwrap()
+                        .pending_tags
+                        .remove(0);
+                    return Ok(HandleBufferResult::Tags(index, tags));
+                }
+
+                if self.streaming_state.as_ref().unwrap().duration_changed {
+                    self.streaming_state.as_mut().unwrap().duration_changed = false;
+                    return Ok(HandleBufferResult::DurationChanged);
+                }
+
+                let tag_offset = self.offset - self.adapter.get_available() as u64;
+
                 let mut data = [0u8; 16];
                 self.adapter.peek_into(&mut data).unwrap();
 
                 match nom::be_u32(&data[0..4]) {
-                    IResult::Error(_) | IResult::Incomplete(_) => {
-                        unimplemented!();
-                    }
+                    IResult::Error(_) | IResult::Incomplete(_) => unreachable!(),
                     IResult::Done(_, previous_size) => {
                         gst_trace!(
                             self.cat,
@@ -1036,12 +2950,25 @@ impl FlvDemux {
 
                 let tag_header = match flavors::tag_header(&data[4..]) {
                     IResult::Error(_) | IResult::Incomplete(_) => {
-                        unimplemented!();
+                        return self.handle_corrupted_data(demuxer, "Invalid tag header");
                     }
                     IResult::Done(_, tag_header) => tag_header,
                 };
 
-                let res = match tag_header.tag_type {
+                // Only trust the tag if the previous tag size after it matches,
+                // this delays each tag until the beginning of the next one
+                if self.resilient {
+                    let data_size = tag_header.data_size as usize;
+                    if self.adapter.get_available() < 15 + data_size + 4 {
+                        return Ok(HandleBufferResult::NeedMoreData);
+                    }
+
+                    if !self.tag_size_matches(data_size) {
+                        return self.handle_corrupted_data(demuxer, "Tag size mismatch");
+                    }
+                }
+
+                let mut res = match tag_header.tag_type {
                     flavors::TagType::Script => {
                         gst_trace!(self.cat, obj: demuxer, "Found script tag");
 
@@ -1050,32 +2977,73 @@ impl FlvDemux {
                     flavors::TagType::Audio => {
                         gst_trace!(self.cat, obj: demuxer, "Found audio tag");
 
-                        let data_header = match flavors::audio_data_header(&data[15..]) {
-                            IResult::Error(_) | IResult::Incomplete(_) => {
-                                unimplemented!();
-                            }
-                            IResult::Done(_, data_header) => data_header,
-                        };
+                        if data[15] >> 4 == EX_AUDIO_SOUND_FORMAT {
+                            self.handle_ex_audio_tag(demuxer, &tag_header)
+                        } else {
+                            let data_header = match flavors::audio_data_header(&data[15..]) {
+                                IResult::Error(_) | IResult::Incomplete(_) => {
+                                    return self.handle_corrupted_data(
+                                        demuxer,
+                                        "Invalid audio data header",
+                                    );
+                                }
+                                IResult::Done(_, data_header) => data_header,
+                            };
 
-                        self.handle_audio_tag(demuxer, &tag_header, &data_header)
+                            self.handle_audio_tag(demuxer, &tag_header, &data_header)
+                        }
                     }
                     flavors::TagType::Video => {
                         gst_trace!(self.cat, obj: demuxer, "Found video tag");
 
-                        let data_header = match flavors::video_data_header(&data[15..]) {
-                            IResult::Error(_) | IResult::Incomplete(_) => {
-                                unimplemented!();
-                            }
-                            IResult::Done(_, data_header) => data_header,
-                        };
+                        // Enhanced video tag header with FourCC
+                        if data[15] & 0x80 != 0 {
+                            self.handle_ex_video_tag(demuxer, &tag_header)
+                        } else {
+                            let data_header = match flavors::video_data_header(&data[15..]) {
+                                IResult::Error(_) | IResult::Incomplete(_) => {
+                                    return self.handle_corrupted_data(
+                                        demuxer,
+                                        "Invalid video data header",
+                                    );
+                                }
+                                IResult::Done(_, data_header) => data_header,
+                            };
 
-                        self.handle_video_tag(demuxer, &tag_header, &data_header)
+                            self.handle_video_tag(demuxer, &tag_header, &data_header)
+                        }
                     }
                 };
 
-                if let Ok(HandleBufferResult::BufferForStream(_, ref buffer)) = res {
+                if let Ok(HandleBufferResult::BufferForStream(stream_index, ref mut buffer)) = res {
+                    self.update_timestamps(
+                        demuxer,
+                        stream_index,
+                        tag_offset,
+                        tag_header.timestamp,
+                        buffer,
+                    );
+
                     let streaming_state = self.streaming_state.as_mut().unwrap();
 
+                    // Remember all video keyframes, or all audio frames if
+                    // there is no video, for seeking later
+                    let is_keyframe = match stream_index {
+                        VIDEO_STREAM_ID => {
+                            !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT)
+                        }
+                        AUDIO_STREAM_ID => !streaming_state.expect_video,
+                        _ => false,
+                    };
+                    let timestamp = if buffer.get_dts() != gst::CLOCK_TIME_NONE {
+                        buffer.get_dts()
+                    } else {
+                        buffer.get_pts()
+                    };
+                    if is_keyframe && timestamp != gst::CLOCK_TIME_NONE {
+                        add_index_entry(&mut self.seek_index, timestamp, tag_offset);
+                    }
+
                     if buffer.get_pts() != gst::CLOCK_TIME_NONE {
                         let pts = buffer.get_pts();
                         streaming_state.last_position = streaming_state
@@ -1089,6 +3057,49 @@ impl FlvDemux {
                             .map(|last| cmp::max(last, dts))
                             .or_else(|| Some(dts));
                     }
+
+                    // Without duration from the metadata or the last tag,
+                    // extrapolate the bitrate so far to the upstream size and
+                    // only announce it again if it changed by more than 10%
+                    let has_duration = self.probed_duration.is_some() ||
+                        streaming_state
+                            .metadata
+                            .as_ref()
+                            .and_then(|m| m.duration)
+                            .is_some();
+                    let estimate = match (
+                        has_duration,
+                        self.upstream_size,
+                        self.data_offset,
+                        streaming_state.last_position,
+                    ) {
+                        (false, Some(size), Some(data_offset), Some(position))
+                            if tag_offset > data_offset && size > tag_offset =>
+                        {
+                            position.mul_div_floor(size - data_offset, tag_offset - data_offset)
+                        }
+                        _ => None,
+                    };
+                    if let Some(estimate) = estimate {
+                        let changed = match streaming_state.estimated_duration {
+                            None => true,
+                            Some(previous) => {
+                                cmp::max(estimate, previous) - cmp::min(estimate, previous) >
+                                    previous / 10
+                            }
+                        };
+
+                        if changed {
+                            gst_debug!(
+                                self.cat,
+                                obj: demuxer,
+                                "Estimated duration {}",
+                                estimate
+                            );
+                            streaming_state.estimated_duration = Some(estimate);
+                            streaming_state.duration_changed = true;
+                        }
+                    }
                 }
 
                 res
@@ -1101,10 +3112,13 @@ impl DemuxerImpl for FlvDemux {
     fn start(
         &mut self,
         demuxer: &RsDemuxer,
-        _upstream_size: Option<u64>,
-        _random_access: bool,
+        upstream_size: Option<u64>,
+        random_access: bool,
     ) -> Result<(), ErrorMessage> {
         self.state = State::NeedHeader;
+        self.offset = 0;
+        self.upstream_size = upstream_size;
+        self.random_access = random_access;
 
         Ok(())
     }
@@ -1112,6 +3126,15 @@ impl DemuxerImpl for FlvDemux {
     fn stop(&mut self, demuxer: &RsDemuxer) -> Result<(), ErrorMessage> {
         self.state = State::Stopped;
         self.adapter.clear();
+        self.offset = 0;
+        self.upstream_size = None;
+        self.random_access = false;
+        self.probed_duration = None;
+        self.data_offset = None;
+        self.seek_index.clear();
+        self.timestamp_offsets.clear();
+        self.resync_tag_starts.clear();
+        self.eos = false;
         self.streaming_state = None;
 
         Ok(())
@@ -1123,7 +3146,136 @@ impl DemuxerImpl for FlvDemux {
         start: u64,
         stop: Option<u64>,
     ) -> Result<SeekResult, ErrorMessage> {
-        unimplemented!();
+        let data_offset = match (self.data_offset, self.streaming_state.as_ref()) {
+            (Some(data_offset), Some(_)) => data_offset,
+            _ => return Ok(SeekResult::TooEarly),
+        };
+
+        let duration = self.get_duration(demuxer);
+        if let Some(duration) = duration {
+            if start >= duration {
+                return Ok(SeekResult::Eos);
+            }
+        }
+
+        // Find the last keyframe before the seek position, or the start of
+        // the data if there is none
+        let (keyframe_time, keyframe_offset, after_index) =
+            match self.seek_index.binary_search_by_key(&start, |&(time, _)| time) {
+                Ok(idx) => (self.seek_index[idx].0, self.seek_index[idx].1, false),
+                Err(0) => (0, data_offset, self.seek_index.is_empty()),
+                Err(idx) => (
+                    self.seek_index[idx - 1].0,
+                    self.seek_index[idx - 1].1,
+                    idx == self.seek_index.len(),
+                ),
+            };
+
+        let have_complete_index = self.streaming_state
+            .as_ref()
+            .and_then(|s| s.metadata.as_ref())
+            .map(|m| !m.keyframes.is_empty())
+            .unwrap_or(false);
+
+        // Continue with the timestamp offset that was valid at the keyframe
+        // from the last time we got there, or the initial one
+        let timestamp_offset = match self.timestamp_offsets
+            .binary_search_by_key(&keyframe_offset, |&(offset, _)| offset)
+        {
+            Ok(idx) => self.timestamp_offsets[idx].1,
+            Err(0) => 0,
+            Err(idx) => self.timestamp_offsets[idx - 1].1,
+        };
+
+        {
+            let streaming_state = self.streaming_state.as_mut().unwrap();
+            self.pre_seek = Some((
+                self.offset,
+                streaming_state.timestamp_offset,
+                streaming_state.last_timestamp,
+                streaming_state.last_position,
+            ));
+
+            // Timestamps after the seek position can't be compared with the
+            // previous ones, only the offset is kept
+            streaming_state.last_position = None;
+            streaming_state.timestamp_offset = timestamp_offset;
+            streaming_state.last_timestamp = None;
+        }
+
+        // If we don't know about any keyframe after the seek position, search
+        // for the last one before it from the one we know
+        let offset = match (have_complete_index, after_index, self.upstream_size) {
+            (false, true, Some(size)) if size > keyframe_offset => {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Seeking to {}-{:?} by bisecting after keyframe {}",
+                    start,
+                    stop,
+                    keyframe_time
+                );
+                self.bisect(demuxer, start, keyframe_offset, size)
+            }
+            (false, true, None) => {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Seeking to {}-{:?} by scanning from keyframe {}",
+                    start,
+                    stop,
+                    keyframe_time
+                );
+                self.state = State::Scanning {
+                    target: start,
+                    keyframe: keyframe_offset,
+                };
+                self.adapter.clear();
+                self.offset = keyframe_offset;
+                keyframe_offset
+            }
+            _ => {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Seeking to {}-{:?} at keyframe {} with offset {}",
+                    start,
+                    stop,
+                    keyframe_time,
+                    keyframe_offset
+                );
+                self.state = State::Streaming;
+                self.adapter.clear();
+                self.offset = keyframe_offset;
+                keyframe_offset
+            }
+        };
+
+        Ok(SeekResult::Ok(offset))
+    }
+
+    // Upstream still sends the data from before the seek, but what we had
+    // queued up is gone
+    fn seek_failed(&mut self, demuxer: &RsDemuxer) {
+        let (offset, timestamp_offset, last_timestamp, last_position) =
+            match (self.pre_seek.take(), self.streaming_state.as_mut()) {
+                (Some(pre_seek), Some(_)) => pre_seek,
+                _ => return,
+            };
+
+        gst_debug!(self.cat, obj: demuxer, "Seek failed, resyncing at offset {}", offset);
+
+        self.state = State::Resyncing;
+        self.adapter.clear();
+        self.offset = offset;
+        self.resync_tag_starts.clear();
+
+        let streaming_state = self.streaming_state.as_mut().unwrap();
+        streaming_state.timestamp_offset = timestamp_offset;
+        streaming_state.last_timestamp = last_timestamp;
+        streaming_state.last_position = last_position;
+        streaming_state.audio_discont = true;
+        streaming_state.video_discont = true;
     }
 
     fn handle_buffer(
@@ -1132,19 +3284,38 @@ impl DemuxerImpl for FlvDemux {
         buffer: Option<gst::Buffer>,
     ) -> Result<HandleBufferResult, FlowError> {
         if let Some(buffer) = buffer {
+            self.eos = false;
+
+            // Resynchronize our offset with upstream, e.g. after seeking
+            if buffer.get_flags().contains(gst::BufferFlags::DISCONT) {
+                self.adapter.clear();
+            }
+            if self.adapter.get_available() == 0 && buffer.get_offset() != u64::MAX {
+                self.offset = buffer.get_offset();
+            }
+            self.offset += buffer.get_size() as u64;
             self.adapter.push(buffer);
+        } else if self.eos {
+            // Only resyncing can find more complete tags, which are then
+            // handled while streaming
+            match self.state {
+                State::Resyncing | State::Streaming => (),
+                _ => return Ok(HandleBufferResult::NeedMoreData),
+            }
         }
 
         self.update_state(demuxer)
     }
 
     fn end_of_stream(&mut self, demuxer: &RsDemuxer) -> Result<(), ErrorMessage> {
-        // nothing to do here, all data we have left is incomplete
+        // Only when resyncing the data we have left might still contain
+        // complete tags, otherwise it is incomplete
+        self.eos = true;
         Ok(())
     }
 
     fn is_seekable(&self, demuxer: &RsDemuxer) -> bool {
-        false
+        self.data_offset.is_some() && self.streaming_state.is_some()
     }
 
     fn get_position(&self, demuxer: &RsDemuxer) -> Option<u64> {
@@ -1155,15 +3326,280 @@ impl DemuxerImpl for FlvDemux {
         None
     }
 
+    // The metadata is most accurate, but if it has no duration the timestamp
+    // of the last tag is usually a good substitute
     fn get_duration(&self, demuxer: &RsDemuxer) -> Option<u64> {
-        if let Some(StreamingState {
-            metadata: Some(Metadata { duration, .. }),
-            ..
-        }) = self.streaming_state
-        {
-            return duration;
+        let streaming_state = match self.streaming_state {
+            Some(ref streaming_state) => streaming_state,
+            None => return None,
+        };
+
+        streaming_state
+            .metadata
+            .as_ref()
+            .and_then(|m| m.duration)
+            .or(self.probed_duration)
+            .or(streaming_state.estimated_duration)
+    }
+
+    fn set_property(&mut self, _demuxer: &RsDemuxer, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::UInt64("discont-threshold", ..) => {
+                self.discont_threshold = value.get().unwrap();
+            }
+            Property::Boolean("resilient", ..) => {
+                self.resilient = value.get().unwrap();
+            }
+            _ => unimplemented!(),
         }
+    }
 
-        None
+    fn get_property(&self, _demuxer: &RsDemuxer, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::UInt64("discont-threshold", ..) => Ok(self.discont_threshold.to_value()),
+            Property::Boolean("resilient", ..) => Ok(self.resilient.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+// Returns the offset of the first previous tag size if the data starts with a
+// valid FLV header: "FLV" signature, version 1, only the audio/video flags set
+pub fn check_flv_header(data: &[u8]) -> Option<u32> {
+    if data.len() < 9 || &data[0..3] != b"FLV" || data[3] != 1 || data[4] & !0x05 != 0 {
+        return None;
+    }
+
+    let offset = ((data[5] as u32) << 24) | ((data[6] as u32) << 16) | ((data[7] as u32) << 8)
+        | (data[8] as u32);
+    if offset < 9 {
+        return None;
+    }
+
+    Some(offset)
+}
+
+pub fn type_find(typefind: &mut TypeFind) {
+    let offset = match typefind.peek(0, 9).and_then(check_flv_header) {
+        None => return,
+        Some(offset) => offset,
+    };
+
+    // A zero first previous tag size followed by an audio, video or script tag
+    // makes it almost certain
+    let first_tag_valid = match typefind.peek(offset as i64, 5) {
+        Some(data) => {
+            data[0..4] == [0, 0, 0, 0] && match data[4] & 0x1f {
+                8 | 9 | 18 => true,
+                _ => false,
+            }
+        }
+        None => false,
+    };
+
+    let probability = if first_tag_valid {
+        gst::TypeFindProbability::Maximum
+    } else {
+        gst::TypeFindProbability::Likely
+    };
+
+    typefind.suggest(probability, &gst::Caps::new_simple("video/x-flv", &[]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_creation_date() {
+        assert_eq!(
+            parse_creation_date("Mon Jun 29 17:15:10 2009"),
+            Some((2009, 6, 29, 17, 15, 10.0))
+        );
+        assert_eq!(
+            parse_creation_date("Tue Mar  2 08:05:00 2010\n"),
+            Some((2010, 3, 2, 8, 5, 0.0))
+        );
+        assert_eq!(parse_creation_date("2009-06-29T17:15:10"), None);
+        assert_eq!(parse_creation_date("Mon Foo 29 17:15:10 2009"), None);
+        assert_eq!(parse_creation_date("Mon Jun 29 17:15 2009"), None);
+        assert_eq!(parse_creation_date("Mon Feb 31 17:15:10 2009"), None);
+        assert_eq!(parse_creation_date("Thu Apr 31 17:15:10 2009"), None);
+        assert_eq!(parse_creation_date("Sun Feb 29 17:15:10 2009"), None);
+        assert_eq!(parse_creation_date("Mon Feb 29 17:15:10 1900"), None);
+        assert_eq!(
+            parse_creation_date("Tue Feb 29 17:15:10 2000"),
+            Some((2000, 2, 29, 17, 15, 10.0))
+        );
+    }
+
+    #[test]
+    fn test_parse_ex_tag_body() {
+        // Key frame, CodedFramesX
+        assert_eq!(
+            parse_ex_tag_body(b"\x93hvc1abc", EX_VIDEO_PACKET_TYPE_MULTITRACK),
+            Some(ExTagBody {
+                packet_type: EX_PACKET_TYPE_CODED_FRAMES_X,
+                fourcc: *b"hvc1",
+                start: 5,
+                end: 8,
+            })
+        );
+
+        // Multitrack with many tracks, track 0 is the second one
+        assert_eq!(
+            parse_ex_tag_body(
+                b"\x96\x11av01\x01\x00\x00\x02ab\x00\x00\x00\x03cde",
+                EX_VIDEO_PACKET_TYPE_MULTITRACK
+            ),
+            Some(ExTagBody {
+                packet_type: EX_PACKET_TYPE_CODED_FRAMES,
+                fourcc: *b"av01",
+                start: 16,
+                end: 19,
+            })
+        );
+
+        // Multitrack with many codecs, one track per codec
+        assert_eq!(
+            parse_ex_tag_body(
+                b"\x96\x20\x01vp09\x00\x00\x01a\x00hvc1\x00\x00\x01b",
+                EX_VIDEO_PACKET_TYPE_MULTITRACK
+            ),
+            Some(ExTagBody {
+                packet_type: EX_PACKET_TYPE_SEQUENCE_START,
+                fourcc: *b"hvc1",
+                start: 19,
+                end: 20,
+            })
+        );
+
+        // Truncated track
+        assert_eq!(
+            parse_ex_tag_body(
+                b"\x96\x11av01\x00\x00\x00\x05ab",
+                EX_VIDEO_PACKET_TYPE_MULTITRACK
+            ),
+            None
+        );
+        assert_eq!(parse_ex_tag_body(b"\x90hv", EX_VIDEO_PACKET_TYPE_MULTITRACK), None);
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("aGVsbG8="), Some(b"hello".to_vec()));
+        assert_eq!(decode_base64("/Pz8"), Some(vec![0xfc, 0xfc, 0xfc]));
+        assert_eq!(decode_base64(""), Some(Vec::new()));
+        assert_eq!(decode_base64("aGV sbG8="), None);
+    }
+
+    #[test]
+    fn test_cc_data_to_cea608() {
+        assert_eq!(
+            cc_data_to_cea608(&[0xfc, 0x94, 0x2c, 0xfd, 0x80, 0x80, 0xf8, 0x01, 0x02, 0xfc]),
+            vec![0x94, 0x2c]
+        );
+        assert_eq!(cc_data_to_cea608(&[0xfa, 0x00, 0x00]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_check_flv_header() {
+        assert_eq!(check_flv_header(b"FLV\x01\x05\x00\x00\x00\x09"), Some(9));
+        assert_eq!(check_flv_header(b"FLV\x01\x01\x00\x00\x00\x0d"), Some(13));
+        assert_eq!(check_flv_header(b"FLV\x02\x05\x00\x00\x00\x09"), None);
+        assert_eq!(check_flv_header(b"FLV\x01\x0d\x00\x00\x00\x09"), None);
+        assert_eq!(check_flv_header(b"FLV\x01\x05\x00\x00\x00\x08"), None);
+        assert_eq!(check_flv_header(b"FLA\x01\x05\x00\x00\x00\x09"), None);
+        assert_eq!(check_flv_header(b"FLV\x01"), None);
+    }
+
+    #[test]
+    fn test_resync_candidate() {
+        // Video tag with 16 bytes after a tag with 32 bytes, which starts at
+        // offset 53
+        let data = b"\x00\x00\x00\x2b\x09\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(resync_candidate(data, 100, Some(13), &[(53, Some(32))]), Some(16));
+        assert_eq!(resync_candidate(data, 100, Some(13), &[(53, None)]), Some(16));
+        // The previous tag doesn't start at a known tag or has another size
+        assert_eq!(resync_candidate(data, 100, Some(13), &[]), None);
+        assert_eq!(resync_candidate(data, 100, Some(13), &[(52, None)]), None);
+        assert_eq!(resync_candidate(data, 100, Some(13), &[(53, Some(31))]), None);
+        // The previous tag doesn't fit after the header
+        assert_eq!(resync_candidate(data, 50, Some(13), &[(3, None)]), None);
+        // Only the first tag has no previous tag
+        assert_eq!(resync_candidate(data, 13, Some(13), &[]), None);
+        let data = b"\x00\x00\x00\x00\x09\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(resync_candidate(data, 13, Some(13), &[]), Some(16));
+        assert_eq!(resync_candidate(data, 100, Some(13), &[(100, None)]), None);
+
+        // Unknown tag type, filter bit and non-zero stream id
+        let starts = [(53, None)];
+        let data = b"\x00\x00\x00\x2b\x0a\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(resync_candidate(data, 100, Some(13), &starts), None);
+        let data = b"\x00\x00\x00\x2b\x28\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(resync_candidate(data, 100, Some(13), &starts), None);
+        let data = b"\x00\x00\x00\x2b\x09\x00\x00\x10\x00\x00\x00\x00\x00\x00\x01";
+        assert_eq!(resync_candidate(data, 100, Some(13), &starts), None);
+
+        // Garbage data size
+        let data = b"\x00\x00\x00\x2b\x09\xff\xff\xff\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(resync_candidate(data, 100, Some(13), &starts), None);
+        assert_eq!(resync_tag_header(data), None);
+    }
+
+    #[test]
+    fn test_aac_config() {
+        let config = AacConfig::parse(&[0x12, 0x10]).unwrap();
+        assert_eq!(
+            config,
+            AacConfig {
+                object_type: 2,
+                rate: 44100,
+                channels: Some(2),
+                sbr: false,
+                ps: false,
+            }
+        );
+        assert_eq!(config.profile(), Some("lc"));
+        assert_eq!(config.level(), Some(2));
+
+        // HE-AAC with explicit SBR signalling, 22.05kHz core
+        let config = AacConfig::parse(&[0x2b, 0x92, 0x08]).unwrap();
+        assert_eq!(config.rate, 44100);
+        assert_eq!(config.channels, Some(2));
+        assert!(config.sbr && !config.ps);
+        assert_eq!(config.profile(), Some("lc"));
+
+        // HE-AACv2, parametric stereo from a mono core
+        let config = AacConfig::parse(&[0xeb, 0x09, 0x88]).unwrap();
+        assert_eq!(config.rate, 48000);
+        assert_eq!(config.channels, Some(2));
+        assert!(config.sbr && config.ps);
+
+        // HE-AAC with backwards compatible signalling after a 24kHz LC core
+        let config = AacConfig::parse(&[0x13, 0x10, 0x56, 0xe5, 0x98]).unwrap();
+        assert_eq!(
+            config,
+            AacConfig {
+                object_type: 2,
+                rate: 48000,
+                channels: Some(2),
+                sbr: true,
+                ps: false,
+            }
+        );
+
+        // HE-AACv2 with backwards compatible signalling from a mono core
+        let config = AacConfig::parse(&[0x13, 0x08, 0x56, 0xe5, 0x9d, 0x48, 0x80]).unwrap();
+        assert_eq!(config.rate, 48000);
+        assert_eq!(config.channels, Some(2));
+        assert!(config.sbr && config.ps);
+
+        // Plain LC without sync extension is not HE-AAC
+        let config = AacConfig::parse(&[0x13, 0x10]).unwrap();
+        assert_eq!(config.rate, 24000);
+        assert!(!config.sbr && !config.ps);
+
+        assert_eq!(AacConfig::parse(&[0x12]), None);
     }
 }
@@ -0,0 +1,900 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::io::{Cursor, Write};
+
+use flavors::parser as flavors;
+
+use gst_plugin::error::*;
+use gst_plugin::bytes::*;
+use gst_plugin_simple::muxer::*;
+
+use gst;
+use gst::prelude::*;
+
+use flvdemux::{AudioCodec, AudioFormat, VideoCodec, VideoFormat};
+
+const TAG_TYPE_AUDIO: u8 = 8;
+const TAG_TYPE_VIDEO: u8 = 9;
+const TAG_TYPE_SCRIPT: u8 = 18;
+
+// Number of keyframes for which space is reserved in the metadata if
+// downstream is seekable and the index can be written at the end
+const KEYFRAMES_INDEX_SIZE: usize = 512;
+
+// Only legacy formats are accepted by from_caps(), enhanced FLV is not
+// written yet
+fn sound_format_id(format: AudioCodec) -> u8 {
+    match format {
+        AudioCodec::Legacy(flavors::SoundFormat::PCM_NE) => 0,
+        AudioCodec::Legacy(flavors::SoundFormat::ADPCM) => 1,
+        AudioCodec::Legacy(flavors::SoundFormat::MP3) => 2,
+        AudioCodec::Legacy(flavors::SoundFormat::PCM_LE) => 3,
+        AudioCodec::Legacy(flavors::SoundFormat::NELLYMOSER_16KHZ_MONO) => 4,
+        AudioCodec::Legacy(flavors::SoundFormat::NELLYMOSER_8KHZ_MONO) => 5,
+        AudioCodec::Legacy(flavors::SoundFormat::NELLYMOSER) => 6,
+        AudioCodec::Legacy(flavors::SoundFormat::PCM_ALAW) => 7,
+        AudioCodec::Legacy(flavors::SoundFormat::PCM_ULAW) => 8,
+        AudioCodec::Legacy(flavors::SoundFormat::AAC) => 10,
+        AudioCodec::Legacy(flavors::SoundFormat::SPEEX) => 11,
+        AudioCodec::Legacy(flavors::SoundFormat::MP3_8KHZ) => 14,
+        AudioCodec::Legacy(flavors::SoundFormat::DEVICE_SPECIFIC) => 15,
+        AudioCodec::Opus => unreachable!(),
+    }
+}
+
+fn codec_id(format: VideoCodec) -> u8 {
+    match format {
+        VideoCodec::Legacy(flavors::CodecId::JPEG) => 1,
+        VideoCodec::Legacy(flavors::CodecId::SORENSON_H263) => 2,
+        VideoCodec::Legacy(flavors::CodecId::SCREEN) => 3,
+        VideoCodec::Legacy(flavors::CodecId::VP6) => 4,
+        VideoCodec::Legacy(flavors::CodecId::VP6A) => 5,
+        VideoCodec::Legacy(flavors::CodecId::SCREEN2) => 6,
+        VideoCodec::Legacy(flavors::CodecId::H264) => 7,
+        VideoCodec::Legacy(flavors::CodecId::H263) => 8,
+        VideoCodec::Legacy(flavors::CodecId::MPEG4Part2) => 9,
+        VideoCodec::Hevc | VideoCodec::Av1 | VideoCodec::Vp9 => unreachable!(),
+    }
+}
+
+// First byte of each audio tag
+fn audio_tag_header(audio: &AudioFormat) -> u8 {
+    let (rate, width, stereo) = match audio.format {
+        // Always 44.1kHz stereo according to the spec
+        AudioCodec::Legacy(flavors::SoundFormat::AAC) => (3, 1, 1),
+        // Always 16kHz mono according to the spec, signalled with rate 0
+        AudioCodec::Legacy(flavors::SoundFormat::SPEEX) => (0, 1, 0),
+        _ => {
+            let rate = match audio.rate {
+                0...8000 => 0,
+                8001...11025 => 1,
+                11026...22050 => 2,
+                _ => 3,
+            };
+            (
+                rate,
+                if audio.width == 8 { 0 } else { 1 },
+                if audio.channels == 2 { 1 } else { 0 },
+            )
+        }
+    };
+
+    (sound_format_id(audio.format) << 4) | (rate << 2) | (width << 1) | stereo
+}
+
+// Simple AMF0 writer for the script data
+enum ScriptDataValue {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(&'static str, ScriptDataValue)>),
+    ECMAArray(Vec<(&'static str, ScriptDataValue)>),
+    StrictArray(Vec<ScriptDataValue>),
+}
+
+impl ScriptDataValue {
+    fn write_string<W: Write>(data: &mut W, s: &str) {
+        data.write_u16be(s.len() as u16).unwrap();
+        data.write_all(s.as_bytes()).unwrap();
+    }
+
+    fn write_properties<W: Write>(data: &mut W, properties: &[(&'static str, ScriptDataValue)]) {
+        for &(name, ref value) in properties {
+            Self::write_string(data, name);
+            value.write(data);
+        }
+        // Object end marker
+        data.write_all(&[0, 0, 9]).unwrap();
+    }
+
+    fn write<W: Write>(&self, data: &mut W) {
+        match *self {
+            ScriptDataValue::Number(n) => {
+                data.write_u8(0).unwrap();
+                data.write_f64be(n).unwrap();
+            }
+            ScriptDataValue::Boolean(b) => {
+                data.write_u8(1).unwrap();
+                data.write_u8(if b { 1 } else { 0 }).unwrap();
+            }
+            ScriptDataValue::String(ref s) => {
+                data.write_u8(2).unwrap();
+                Self::write_string(data, s);
+            }
+            ScriptDataValue::Object(ref properties) => {
+                data.write_u8(3).unwrap();
+                Self::write_properties(data, properties);
+            }
+            ScriptDataValue::ECMAArray(ref properties) => {
+                data.write_u8(8).unwrap();
+                data.write_u32be(properties.len() as u32).unwrap();
+                Self::write_properties(data, properties);
+            }
+            ScriptDataValue::StrictArray(ref values) => {
+                data.write_u8(10).unwrap();
+                data.write_u32be(values.len() as u32).unwrap();
+                for value in values {
+                    value.write(data);
+                }
+            }
+        }
+    }
+}
+
+fn write_tag<W: Write>(data: &mut W, tag_type: u8, timestamp: u32, payload: &[&[u8]]) {
+    let size = payload.iter().map(|p| p.len()).sum::<usize>() as u32;
+
+    data.write_u8(tag_type).unwrap();
+    data.write_uintbe(size as u64, 3).unwrap();
+    data.write_uintbe((timestamp & 0x00ff_ffff) as u64, 3).unwrap();
+    data.write_u8((timestamp >> 24) as u8).unwrap();
+    data.write_uintbe(0, 3).unwrap(); // stream id
+    for p in payload {
+        data.write_all(p).unwrap();
+    }
+    data.write_u32be(11 + size).unwrap(); // previous tag size
+}
+
+pub struct FlvMux {
+    cat: gst::DebugCategory,
+    audio: Option<(PadIndex, AudioFormat)>,
+    video: Option<(PadIndex, VideoFormat)>,
+    seekable: bool,
+    // Bytes written so far
+    offset: u64,
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+    // Timestamp in milliseconds of the last tag written
+    last_tag_timestamp: u32,
+    // (time, byte offset) of all video keyframes
+    keyframes: Vec<(u64, u64)>,
+    metadata_size: usize,
+}
+
+impl FlvMux {
+    pub fn new(_muxer: &RsMuxer) -> FlvMux {
+        FlvMux {
+            cat: gst::DebugCategory::new(
+                "rsflvmux",
+                gst::DebugColorFlags::empty(),
+                "Rust FLV muxer",
+            ),
+            audio: None,
+            video: None,
+            seekable: false,
+            offset: 0,
+            first_timestamp: None,
+            last_timestamp: None,
+            last_tag_timestamp: 0,
+            keyframes: Vec::new(),
+            metadata_size: 0,
+        }
+    }
+
+    pub fn new_boxed(muxer: &RsMuxer) -> Box<MuxerImpl> {
+        Box::new(Self::new(muxer))
+    }
+
+    fn write_file_header<W: Write>(&self, data: &mut W) {
+        let flags = if self.audio.is_some() { 0x04 } else { 0 } |
+            if self.video.is_some() { 0x01 } else { 0 };
+
+        data.write_all(b"FLV").unwrap();
+        data.write_u8(1).unwrap(); // version
+        data.write_u8(flags).unwrap();
+        data.write_u32be(9).unwrap(); // header size
+        data.write_u32be(0).unwrap(); // previous tag size
+    }
+
+    fn create_metadata(&self, duration: u64, keyframes: &[(u64, u64)]) -> Vec<u8> {
+        let mut properties = Vec::new();
+
+        properties.push((
+            "duration",
+            ScriptDataValue::Number(duration as f64 / 1_000_000_000.0),
+        ));
+
+        if let Some((_, ref video)) = self.video {
+            if let (Some(width), Some(height)) = (video.width, video.height) {
+                properties.push(("width", ScriptDataValue::Number(width as f64)));
+                properties.push(("height", ScriptDataValue::Number(height as f64)));
+            }
+            if let Some(framerate) = video.framerate {
+                if *framerate.denom() != 0 {
+                    properties.push((
+                        "framerate",
+                        ScriptDataValue::Number(
+                            *framerate.numer() as f64 / *framerate.denom() as f64,
+                        ),
+                    ));
+                }
+            }
+            properties.push((
+                "videocodecid",
+                ScriptDataValue::Number(codec_id(video.format) as f64),
+            ));
+        }
+
+        if let Some((_, ref audio)) = self.audio {
+            properties.push((
+                "audiocodecid",
+                ScriptDataValue::Number(sound_format_id(audio.format) as f64),
+            ));
+            if audio.rate != 0 {
+                properties.push(("audiosamplerate", ScriptDataValue::Number(audio.rate as f64)));
+            }
+            properties.push((
+                "audiosamplesize",
+                ScriptDataValue::Number(audio.width as f64),
+            ));
+            properties.push(("stereo", ScriptDataValue::Boolean(audio.channels == 2)));
+        }
+
+        properties.push((
+            "metadatacreator",
+            ScriptDataValue::String(String::from("GStreamer Rust FLV muxer")),
+        ));
+
+        if self.seekable {
+            properties.push((
+                "keyframes",
+                ScriptDataValue::Object(vec![
+                    (
+                        "times",
+                        ScriptDataValue::StrictArray(
+                            keyframes
+                                .iter()
+                                .map(|&(time, _)| {
+                                    ScriptDataValue::Number(time as f64 / 1_000_000_000.0)
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    (
+                        "filepositions",
+                        ScriptDataValue::StrictArray(
+                            keyframes
+                                .iter()
+                                .map(|&(_, offset)| ScriptDataValue::Number(offset as f64))
+                                .collect(),
+                        ),
+                    ),
+                ]),
+            ));
+        }
+
+        let mut script_data = Vec::new();
+        ScriptDataValue::String(String::from("onMetaData")).write(&mut script_data);
+        ScriptDataValue::ECMAArray(properties).write(&mut script_data);
+
+        script_data
+    }
+
+    fn create_metadata_tag(&self) -> Vec<u8> {
+        let duration = match (self.first_timestamp, self.last_timestamp) {
+            (Some(first), Some(last)) if last > first => last - first,
+            _ => 0,
+        };
+
+        // If we have more keyframes than space for them, only use every n-th
+        let step = cmp::max(
+            (self.keyframes.len() + KEYFRAMES_INDEX_SIZE - 1) / KEYFRAMES_INDEX_SIZE,
+            1,
+        );
+        let keyframes = self.keyframes
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i % step == 0)
+            .map(|(_, keyframe)| *keyframe)
+            .take(KEYFRAMES_INDEX_SIZE)
+            .collect::<Vec<_>>();
+
+        let mut script_data = self.create_metadata(duration, &keyframes);
+
+        // Fill the size reserved for rewriting the metadata later, the
+        // padding after the end of the array is ignored by readers
+        if script_data.len() < self.metadata_size {
+            script_data.resize(self.metadata_size, 0);
+        }
+
+        let mut data = Vec::with_capacity(script_data.len() + 15);
+        write_tag(&mut data, TAG_TYPE_SCRIPT, 0, &[&script_data]);
+        data
+    }
+
+    // Size needed for the metadata with a full keyframes index, and all
+    // fields that might only be known from caps after the header
+    fn reserved_metadata_size(&self) -> usize {
+        let mut optional_fields = Vec::new();
+        for name in &[
+            "width",
+            "height",
+            "framerate",
+            "videocodecid",
+            "audiocodecid",
+            "audiosamplerate",
+            "audiosamplesize",
+        ] {
+            ScriptDataValue::write_string(&mut optional_fields, name);
+            ScriptDataValue::Number(0.0).write(&mut optional_fields);
+        }
+        ScriptDataValue::write_string(&mut optional_fields, "stereo");
+        ScriptDataValue::Boolean(false).write(&mut optional_fields);
+
+        self.create_metadata(0, &[(0, 0); KEYFRAMES_INDEX_SIZE])
+            .len() + optional_fields.len()
+    }
+
+    fn timestamp_ms(&self, timestamp: u64) -> u32 {
+        let first = self.first_timestamp.unwrap_or(0);
+        if timestamp < first {
+            0
+        } else {
+            ((timestamp - first) / 1_000_000) as u32
+        }
+    }
+
+    // Buffers without timestamp get the one of the previous tag
+    fn tag_timestamp(&mut self, timestamp: u64) -> u32 {
+        if timestamp != gst::CLOCK_TIME_NONE {
+            self.last_tag_timestamp = self.timestamp_ms(timestamp);
+        }
+        self.last_tag_timestamp
+    }
+
+    fn output_buffer(&mut self, data: Vec<u8>, buffer: &gst::Buffer) -> gst::Buffer {
+        self.offset += data.len() as u64;
+
+        let mut outbuf = gst::Buffer::from_vec(data).unwrap();
+        {
+            let outbuf = outbuf.get_mut().unwrap();
+            outbuf.set_pts(buffer.get_pts());
+            outbuf.set_dts(buffer.get_dts());
+            outbuf.set_flags(buffer.get_flags() & gst::BufferFlags::DELTA_UNIT);
+        }
+        outbuf
+    }
+
+    fn handle_audio_buffer(
+        &mut self,
+        buffer: gst::Buffer,
+    ) -> Result<Option<gst::Buffer>, FlowError> {
+        let header = match self.audio {
+            Some((_, ref audio)) => {
+                // Speex stream headers are not stored in FLV
+                if audio.format == AudioCodec::Legacy(flavors::SoundFormat::SPEEX) &&
+                    buffer.get_flags().contains(gst::BufferFlags::HEADER)
+                {
+                    return Ok(None);
+                }
+
+                let header = audio_tag_header(audio);
+                if audio.format == AudioCodec::Legacy(flavors::SoundFormat::AAC) {
+                    vec![header, 1]
+                } else {
+                    vec![header]
+                }
+            }
+            None => unreachable!(),
+        };
+
+        let pts = if buffer.get_pts() != gst::CLOCK_TIME_NONE {
+            buffer.get_pts()
+        } else {
+            buffer.get_dts()
+        };
+        let timestamp = self.tag_timestamp(pts);
+        let mut data = Vec::with_capacity(buffer.get_size() + header.len() + 15);
+        {
+            let map = buffer.map_readable().unwrap();
+            write_tag(&mut data, TAG_TYPE_AUDIO, timestamp, &[&header, map.as_slice()]);
+        }
+
+        Ok(Some(self.output_buffer(data, &buffer)))
+    }
+
+    fn handle_video_buffer(
+        &mut self,
+        buffer: gst::Buffer,
+    ) -> Result<Option<gst::Buffer>, FlowError> {
+        let is_keyframe = !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+        let header = match self.video {
+            Some((_, ref video)) => {
+                let header = ((if is_keyframe { 1 } else { 2 }) << 4) | codec_id(video.format);
+                if video.format == VideoCodec::Legacy(flavors::CodecId::H264) {
+                    let cts = if buffer.get_pts() != gst::CLOCK_TIME_NONE &&
+                        buffer.get_dts() != gst::CLOCK_TIME_NONE
+                    {
+                        (buffer.get_pts() as i64 - buffer.get_dts() as i64) / 1_000_000
+                    } else {
+                        0
+                    };
+
+                    vec![
+                        header,
+                        1,
+                        (cts >> 16) as u8,
+                        (cts >> 8) as u8,
+                        cts as u8,
+                    ]
+                } else {
+                    vec![header]
+                }
+            }
+            None => unreachable!(),
+        };
+
+        let dts = if buffer.get_dts() != gst::CLOCK_TIME_NONE {
+            buffer.get_dts()
+        } else {
+            buffer.get_pts()
+        };
+        let timestamp = self.tag_timestamp(dts);
+
+        if is_keyframe && dts != gst::CLOCK_TIME_NONE {
+            let first = self.first_timestamp.unwrap_or(0);
+            let time = if dts > first { dts - first } else { 0 };
+            self.keyframes.push((time, self.offset));
+        }
+
+        let mut data = Vec::with_capacity(buffer.get_size() + header.len() + 15);
+        {
+            let map = buffer.map_readable().unwrap();
+            write_tag(&mut data, TAG_TYPE_VIDEO, timestamp, &[&header, map.as_slice()]);
+        }
+
+        Ok(Some(self.output_buffer(data, &buffer)))
+    }
+}
+
+impl MuxerImpl for FlvMux {
+    fn start(&mut self, _muxer: &RsMuxer) -> Result<(), ErrorMessage> {
+        self.seekable = false;
+        self.offset = 0;
+        self.first_timestamp = None;
+        self.last_timestamp = None;
+        self.last_tag_timestamp = 0;
+        self.keyframes.clear();
+        self.metadata_size = 0;
+
+        Ok(())
+    }
+
+    fn stop(&mut self, _muxer: &RsMuxer) -> Result<(), ErrorMessage> {
+        self.audio = None;
+        self.video = None;
+
+        Ok(())
+    }
+
+    fn set_caps(
+        &mut self,
+        muxer: &RsMuxer,
+        index: PadIndex,
+        caps: &gst::Caps,
+    ) -> Result<(), ErrorMessage> {
+        gst_debug!(self.cat, obj: muxer, "Got caps {:?} for pad {}", caps, index);
+
+        let name = caps.get_structure(0).map(|s| String::from(s.get_name()));
+        match name {
+            Some(ref name) if name.starts_with("audio/") => {
+                let format = match AudioFormat::from_caps(caps) {
+                    Some(format) => format,
+                    None => {
+                        return Err(error_msg!(
+                            gst::StreamError::Format,
+                            ["Unsupported audio caps {:?}", caps]
+                        ))
+                    }
+                };
+                if let Some((other, _)) = self.audio {
+                    if other != index {
+                        return Err(error_msg!(
+                            gst::StreamError::Mux,
+                            ["Only a single audio stream is supported"]
+                        ));
+                    }
+                }
+                self.audio = Some((index, format));
+            }
+            Some(ref name) if name.starts_with("video/") => {
+                let format = match VideoFormat::from_caps(caps) {
+                    Some(format) => format,
+                    None => {
+                        return Err(error_msg!(
+                            gst::StreamError::Format,
+                            ["Unsupported video caps {:?}", caps]
+                        ))
+                    }
+                };
+                if let Some((other, _)) = self.video {
+                    if other != index {
+                        return Err(error_msg!(
+                            gst::StreamError::Mux,
+                            ["Only a single video stream is supported"]
+                        ));
+                    }
+                }
+                self.video = Some((index, format));
+            }
+            _ => {
+                return Err(error_msg!(
+                    gst::StreamError::Format,
+                    ["Unsupported caps {:?}", caps]
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_header(
+        &mut self,
+        muxer: &RsMuxer,
+        seekable: bool,
+    ) -> Result<Option<gst::Buffer>, FlowError> {
+        gst_debug!(self.cat, obj: muxer, "Writing header, seekable {}", seekable);
+
+        self.seekable = seekable;
+        if seekable {
+            self.metadata_size = self.reserved_metadata_size();
+        }
+
+        let metadata_tag = self.create_metadata_tag();
+        if seekable {
+            self.metadata_size = metadata_tag.len() - 15;
+        }
+
+        let mut data = Cursor::new(Vec::new());
+        self.write_file_header(&mut data);
+        data.write_all(&metadata_tag).unwrap();
+
+        if let Some((_, ref audio)) = self.audio {
+            if let Some(ref sequence_header) = audio.sequence_header {
+                let map = sequence_header.map_readable().unwrap();
+                write_tag(
+                    &mut data,
+                    TAG_TYPE_AUDIO,
+                    0,
+                    &[&[audio_tag_header(audio), 0], map.as_slice()],
+                );
+            }
+        }
+
+        if let Some((_, ref video)) = self.video {
+            if let Some(ref sequence_header) = video.sequence_header {
+                let map = sequence_header.map_readable().unwrap();
+                write_tag(
+                    &mut data,
+                    TAG_TYPE_VIDEO,
+                    0,
+                    &[&[0x10 | codec_id(video.format), 0, 0, 0, 0], map.as_slice()],
+                );
+            }
+        }
+
+        let data = data.into_inner();
+        self.offset += data.len() as u64;
+
+        let mut buffer = gst::Buffer::from_vec(data).unwrap();
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_flags(gst::BufferFlags::HEADER);
+
+        Ok(Some(buffer))
+    }
+
+    fn handle_buffer(
+        &mut self,
+        muxer: &RsMuxer,
+        index: PadIndex,
+        buffer: gst::Buffer,
+    ) -> Result<Option<gst::Buffer>, FlowError> {
+        let timestamp = if buffer.get_dts() != gst::CLOCK_TIME_NONE {
+            buffer.get_dts()
+        } else {
+            buffer.get_pts()
+        };
+
+        if timestamp != gst::CLOCK_TIME_NONE {
+            if self.first_timestamp.is_none() {
+                gst_debug!(self.cat, obj: muxer, "First timestamp {}", timestamp);
+                self.first_timestamp = Some(timestamp);
+            }
+
+            let end = if buffer.get_duration() != gst::CLOCK_TIME_NONE {
+                timestamp + buffer.get_duration()
+            } else {
+                timestamp
+            };
+            self.last_timestamp = Some(cmp::max(self.last_timestamp.unwrap_or(0), end));
+        }
+
+        match (self.audio.as_ref(), self.video.as_ref()) {
+            (Some(&(audio_index, _)), _) if audio_index == index => {
+                self.handle_audio_buffer(buffer)
+            }
+            (_, Some(&(video_index, _))) if video_index == index => {
+                self.handle_video_buffer(buffer)
+            }
+            _ => Err(FlowError::NotNegotiated(error_msg!(
+                gst::StreamError::Format,
+                ["No caps for pad {}", index]
+            ))),
+        }
+    }
+
+    fn write_trailer(&mut self, _muxer: &RsMuxer) -> Result<Option<gst::Buffer>, FlowError> {
+        Ok(None)
+    }
+
+    fn rewrite_header(&mut self, muxer: &RsMuxer) -> Result<Option<gst::Buffer>, FlowError> {
+        if !self.seekable {
+            return Ok(None);
+        }
+
+        gst_debug!(
+            self.cat,
+            obj: muxer,
+            "Rewriting metadata with {} keyframes",
+            self.keyframes.len()
+        );
+
+        // Only rewrite it in place if it doesn't overwrite the next tags
+        let metadata_tag = self.create_metadata_tag();
+        if metadata_tag.len() != self.metadata_size + 15 {
+            gst_warning!(
+                self.cat,
+                obj: muxer,
+                "Metadata of {} bytes does not fit into {} bytes",
+                metadata_tag.len() - 15,
+                self.metadata_size
+            );
+            error_msg!(
+                gst::StreamError::Mux,
+                ["Metadata does not fit into the reserved space, not rewriting it"]
+            ).post_warning(muxer);
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        self.write_file_header(&mut data);
+        data.write_all(&metadata_tag).unwrap();
+
+        Ok(Some(gst::Buffer::from_vec(data).unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::IResult;
+    use flvdemux::check_flv_header;
+
+    fn new_mux() -> FlvMux {
+        gst::init().unwrap();
+
+        FlvMux {
+            cat: gst::DebugCategory::new(
+                "rsflvmux",
+                gst::DebugColorFlags::empty(),
+                "Rust FLV muxer",
+            ),
+            audio: Some((
+                0,
+                AudioFormat {
+                    format: AudioCodec::Legacy(flavors::SoundFormat::MP3),
+                    rate: 44100,
+                    width: 16,
+                    channels: 2,
+                    bitrate: None,
+                    sequence_header: None,
+                },
+            )),
+            video: Some((
+                1,
+                VideoFormat {
+                    format: VideoCodec::Legacy(flavors::CodecId::H264),
+                    width: Some(320),
+                    height: Some(240),
+                    pixel_aspect_ratio: None,
+                    framerate: None,
+                    bitrate: None,
+                    sequence_header: None,
+                },
+            )),
+            seekable: false,
+            offset: 0,
+            first_timestamp: Some(0),
+            last_timestamp: Some(2_000_000_000),
+            last_tag_timestamp: 0,
+            keyframes: Vec::new(),
+            metadata_size: 0,
+        }
+    }
+
+    fn new_buffer(pts: u64, keyframe: bool) -> gst::Buffer {
+        let mut buffer = gst::Buffer::from_vec(b"abcd".to_vec()).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts);
+            if !keyframe {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+        buffer
+    }
+
+    // Checks the tag header and previous tag size and returns the tag data
+    fn parse_tag(data: &[u8], tag_type: flavors::TagType, timestamp: u32) -> &[u8] {
+        let tag_header = match flavors::tag_header(data) {
+            IResult::Done(_, tag_header) => tag_header,
+            _ => panic!("Invalid tag header"),
+        };
+        assert_eq!(tag_header.tag_type, tag_type);
+        assert_eq!(tag_header.timestamp, timestamp);
+        assert_eq!(tag_header.stream_id, 0);
+
+        let size = tag_header.data_size as usize;
+        assert_eq!(data.len(), 11 + size + 4);
+        assert_eq!(
+            &data[11 + size..],
+            &[0, 0, ((11 + size) >> 8) as u8, (11 + size) as u8]
+        );
+
+        &data[11..11 + size]
+    }
+
+    #[test]
+    fn test_file_header() {
+        let mux = new_mux();
+
+        let mut data = Vec::new();
+        mux.write_file_header(&mut data);
+        assert_eq!(check_flv_header(&data), Some(9));
+        match flavors::header(&data) {
+            IResult::Done(rest, header) => {
+                assert_eq!(header.version, 1);
+                assert!(header.audio);
+                assert!(header.video);
+                assert_eq!(header.offset, 9);
+                assert_eq!(rest, &[0, 0, 0, 0]);
+            }
+            _ => panic!("Invalid header"),
+        }
+    }
+
+    #[test]
+    fn test_audio_tags() {
+        let mut mux = new_mux();
+
+        let buffer = mux.handle_audio_buffer(new_buffer(1_500_000_000, true))
+            .unwrap()
+            .unwrap();
+        let map = buffer.map_readable().unwrap();
+        let data = parse_tag(map.as_slice(), flavors::TagType::Audio, 1500);
+        match flavors::audio_data_header(data) {
+            IResult::Done(rest, header) => {
+                assert_eq!(header.sound_format, flavors::SoundFormat::MP3);
+                assert_eq!(header.sound_rate, flavors::SoundRate::_44KHZ);
+                assert_eq!(header.sound_size, flavors::SoundSize::Snd16bit);
+                assert_eq!(header.sound_type, flavors::SoundType::SndStereo);
+                assert_eq!(rest, b"abcd");
+            }
+            _ => panic!("Invalid audio data header"),
+        }
+
+        // Speex is 16kHz mono with a rate field of 0
+        let speex = AudioFormat {
+            format: AudioCodec::Legacy(flavors::SoundFormat::SPEEX),
+            rate: 16000,
+            width: 16,
+            channels: 1,
+            bitrate: None,
+            sequence_header: None,
+        };
+        match flavors::audio_data_header(&[audio_tag_header(&speex)]) {
+            IResult::Done(_, header) => {
+                assert_eq!(header.sound_format, flavors::SoundFormat::SPEEX);
+                assert_eq!(header.sound_rate, flavors::SoundRate::_5_5KHZ);
+                assert_eq!(header.sound_type, flavors::SoundType::SndMono);
+            }
+            _ => panic!("Invalid audio data header"),
+        }
+    }
+
+    #[test]
+    fn test_video_tags() {
+        let mut mux = new_mux();
+
+        for &(pts, keyframe) in &[(0, true), (40_000_000, false)] {
+            let buffer = mux.handle_video_buffer(new_buffer(pts, keyframe))
+                .unwrap()
+                .unwrap();
+            let map = buffer.map_readable().unwrap();
+            let timestamp = (pts / 1_000_000) as u32;
+            let data = parse_tag(map.as_slice(), flavors::TagType::Video, timestamp);
+            match flavors::video_data_header(data) {
+                IResult::Done(rest, header) => {
+                    let frame_type = if keyframe {
+                        flavors::FrameType::Key
+                    } else {
+                        flavors::FrameType::Inter
+                    };
+                    assert_eq!(header.frame_type, frame_type);
+                    assert_eq!(header.codec_id, flavors::CodecId::H264);
+                    // NAL units with a composition time of 0
+                    assert_eq!(rest, b"\x01\x00\x00\x00abcd");
+                }
+                _ => panic!("Invalid video data header"),
+            }
+        }
+
+        // Only the first buffer is a keyframe
+        assert_eq!(mux.keyframes, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_metadata_tag() {
+        let mut mux = new_mux();
+        mux.metadata_size = mux.reserved_metadata_size();
+
+        let tag = mux.create_metadata_tag();
+        let data = parse_tag(&tag, flavors::TagType::Script, 0);
+        assert_eq!(data.len(), mux.metadata_size);
+
+        let script_data = match flavors::script_data(data) {
+            IResult::Done(_, script_data) => script_data,
+            _ => panic!("Invalid script data"),
+        };
+        assert_eq!(script_data.name, "onMetaData");
+
+        let args = match script_data.arguments {
+            flavors::ScriptDataValue::ECMAArray(ref args) => args,
+            _ => panic!("No ECMA array"),
+        };
+        let number = |name| {
+            args.iter()
+                .filter_map(|arg| match (arg.name, &arg.data) {
+                    (n, &flavors::ScriptDataValue::Number(value)) if n == name => Some(value),
+                    _ => None,
+                })
+                .next()
+        };
+        assert_eq!(number("duration"), Some(2.0));
+        assert_eq!(number("width"), Some(320.0));
+        assert_eq!(number("height"), Some(240.0));
+        assert_eq!(number("videocodecid"), Some(7.0));
+        assert_eq!(number("audiocodecid"), Some(2.0));
+        assert_eq!(number("audiosamplerate"), Some(44100.0));
+    }
+}
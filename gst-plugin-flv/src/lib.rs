@@ -9,6 +9,7 @@
 #![crate_type = "cdylib"]
 
 extern crate flavors;
+extern crate glib;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
@@ -19,12 +20,16 @@ extern crate nom;
 extern crate num_rational;
 extern crate url;
 
+use gst_plugin::typefind::*;
 use gst_plugin_simple::demuxer::*;
+use gst_plugin_simple::muxer::*;
 use gst::prelude::*;
 
 mod flvdemux;
+mod flvmux;
 
 use flvdemux::FlvDemux;
+use flvmux::FlvMux;
 
 fn plugin_init(plugin: &gst::Plugin) -> bool {
     demuxer_register(
@@ -39,6 +44,45 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: FlvDemux::new_boxed,
             input_caps: gst::Caps::new_simple("video/x-flv", &[]),
             output_caps: gst::Caps::new_any(),
+            properties: &flvdemux::PROPERTIES,
+        },
+    );
+
+    type_find_register(
+        plugin,
+        "rsflv",
+        256,
+        Some("flv"),
+        Some(&gst::Caps::new_simple("video/x-flv", &[])),
+        flvdemux::type_find,
+    );
+
+    muxer_register(
+        plugin,
+        MuxerInfo {
+            name: "rsflvmux".into(),
+            long_name: "FLV Muxer".into(),
+            description: "Muxes FLV Streams".into(),
+            classification: "Codec/Muxer".into(),
+            author: "Sebastian Dröge <sebastian@centricular.com>".into(),
+            rank: 0,
+            create_instance: FlvMux::new_boxed,
+            sink_pad_templates: vec![
+                (
+                    "audio".into(),
+                    gst::Caps::from_string(
+                        "audio/mpeg, mpegversion = (int) 1, layer = (int) 3; \
+                         audio/mpeg, mpegversion = (int) { 2, 4 }, stream-format = (string) raw; \
+                         audio/x-speex",
+                    ).unwrap(),
+                ),
+                (
+                    "video".into(),
+                    gst::Caps::from_string("video/x-h264, stream-format = (string) avc")
+                        .unwrap(),
+                ),
+            ],
+            output_caps: gst::Caps::new_simple("video/x-flv", &[]),
         },
     );
 
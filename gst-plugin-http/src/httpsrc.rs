@@ -7,25 +7,47 @@
 // except according to those terms.
 
 use std::u64;
-use std::io::Read;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 use url::Url;
 use reqwest::{Client, Response};
 use reqwest::header::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec,
-                      Range, RangeUnit};
+                      Range, RangeUnit, UserAgent};
 
 use gst_plugin::error::*;
+use gst_plugin::object::*;
 use gst_plugin_simple::source::*;
 use gst_plugin_simple::UriValidator;
 
+use glib;
 use gst;
 use gst::prelude::*;
 
+const DEFAULT_USER_AGENT: &str = "GStreamer rshttpsrc";
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+// Reads time out regularly, so that readers of responses that are not
+// needed anymore don't keep stalled connections open
+const READ_TIMEOUT_SECS: u64 = 5;
+
+pub static PROPERTIES: [Property; 1] = [
+    Property::String(
+        "user-agent",
+        "User-Agent",
+        "Value of the User-Agent HTTP request header",
+        Some(DEFAULT_USER_AGENT),
+        PropertyMutability::ReadWriteReady,
+    ),
+];
+
 #[derive(Debug)]
 enum StreamingState {
     Stopped,
     Started {
         uri: Url,
-        response: Response,
         seekable: bool,
         position: u64,
         size: Option<u64>,
@@ -34,11 +56,140 @@ enum StreamingState {
     },
 }
 
+#[derive(Debug, Default)]
+struct BodyState {
+    flushing: bool,
+    // Identifies the current response, readers of previous ones stop
+    response: u64,
+    // Next data of the response, empty at its end
+    chunk: Option<io::Result<Vec<u8>>>,
+}
+
+// Reads the response body on a separate thread, so that fill() can stop
+// waiting for data when unlocking without interrupting a read
+#[derive(Debug, Clone, Default)]
+struct Body(Arc<(Mutex<BodyState>, Condvar)>);
+
+impl Body {
+    fn start(&self, mut response: Response) {
+        let id = {
+            let &(ref lock, ref cond) = &*self.0;
+            let mut state = lock.lock().unwrap();
+            state.response += 1;
+            state.chunk = None;
+            cond.notify_all();
+            state.response
+        };
+
+        let body = self.clone();
+        thread::spawn(move || loop {
+            let mut data = vec![0; READ_CHUNK_SIZE];
+            let res = match response.read(&mut data) {
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::TimedOut ||
+                        err.kind() == io::ErrorKind::WouldBlock =>
+                {
+                    if body.is_current(id) {
+                        continue;
+                    }
+                    return;
+                }
+                res => res.map(|size| {
+                    data.truncate(size);
+                    data
+                }),
+            };
+            let done = match res {
+                Ok(ref data) => data.is_empty(),
+                Err(_) => true,
+            };
+
+            let &(ref lock, ref cond) = &*body.0;
+            let mut state = lock.lock().unwrap();
+            while state.response == id && state.chunk.is_some() {
+                state = cond.wait(state).unwrap();
+            }
+
+            // Dropping the response closes the connection
+            if state.response != id {
+                return;
+            }
+
+            state.chunk = Some(res);
+            cond.notify_all();
+
+            if done {
+                return;
+            }
+        });
+    }
+
+    fn is_current(&self, id: u64) -> bool {
+        let &(ref lock, _) = &*self.0;
+        lock.lock().unwrap().response == id
+    }
+
+    fn stop(&self) {
+        let &(ref lock, ref cond) = &*self.0;
+        let mut state = lock.lock().unwrap();
+        state.response += 1;
+        state.chunk = None;
+        cond.notify_all();
+    }
+
+    // Returns None if unlocked while waiting for data
+    fn read(&self, data: &mut [u8]) -> Option<io::Result<usize>> {
+        let &(ref lock, ref cond) = &*self.0;
+        let mut state = lock.lock().unwrap();
+
+        loop {
+            if state.flushing {
+                return None;
+            }
+
+            match state.chunk.take() {
+                Some(Ok(mut chunk)) => {
+                    let size = chunk.len().min(data.len());
+                    data[..size].copy_from_slice(&chunk[..size]);
+                    chunk.drain(..size);
+
+                    // Keep the end of the response for the next reads
+                    if !chunk.is_empty() || size == 0 {
+                        state.chunk = Some(Ok(chunk));
+                    }
+                    cond.notify_all();
+
+                    return Some(Ok(size));
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => (),
+            }
+
+            state = cond.wait(state).unwrap();
+        }
+    }
+}
+
+impl UnlockHandler for Body {
+    fn unlock(&self) {
+        let &(ref lock, ref cond) = &*self.0;
+        lock.lock().unwrap().flushing = true;
+        cond.notify_all();
+    }
+
+    fn unlock_stop(&self) {
+        let &(ref lock, _) = &*self.0;
+        lock.lock().unwrap().flushing = false;
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpSrc {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
     client: Client,
+    user_agent: String,
+    body: Body,
 }
 
 impl HttpSrc {
@@ -50,7 +201,13 @@ impl HttpSrc {
                 gst::DebugColorFlags::empty(),
                 "Rust HTTP source",
             ),
-            client: Client::new().unwrap(),
+            client: Client::builder()
+                .unwrap()
+                .timeout(Duration::from_secs(READ_TIMEOUT_SECS))
+                .build()
+                .unwrap(),
+            user_agent: DEFAULT_USER_AGENT.into(),
+            body: Body::default(),
         }
     }
 
@@ -67,6 +224,7 @@ impl HttpSrc {
     ) -> Result<StreamingState, ErrorMessage> {
         let cat = self.cat;
         let mut req = self.client.get(uri.clone()).unwrap();
+        req.header(UserAgent::new(self.user_agent.clone()));
 
         match (start != 0, stop) {
             (false, None) => (),
@@ -130,9 +288,10 @@ impl HttpSrc {
 
         gst_debug!(cat, obj: src, "Request successful: {:?}", response);
 
+        self.body.start(response);
+
         Ok(StreamingState::Started {
             uri: uri,
-            response: response,
             seekable: seekable,
             position: 0,
             size: size,
@@ -158,6 +317,10 @@ impl SourceImpl for HttpSrc {
         Box::new(validate_uri)
     }
 
+    fn unlock_handler(&self) -> Option<Box<UnlockHandler>> {
+        Some(Box::new(self.body.clone()))
+    }
+
     fn is_seekable(&self, _src: &RsBaseSrc) -> bool {
         match self.streaming_state {
             StreamingState::Started { seekable, .. } => seekable,
@@ -174,6 +337,7 @@ impl SourceImpl for HttpSrc {
 
     fn start(&mut self, src: &RsBaseSrc, uri: Url) -> Result<(), ErrorMessage> {
         self.streaming_state = StreamingState::Stopped;
+        self.body.stop();
         self.streaming_state = try!(self.do_request(src, uri, 0, None));
 
         Ok(())
@@ -181,6 +345,7 @@ impl SourceImpl for HttpSrc {
 
     fn stop(&mut self, _src: &RsBaseSrc) -> Result<(), ErrorMessage> {
         self.streaming_state = StreamingState::Stopped;
+        self.body.stop();
 
         Ok(())
     }
@@ -203,6 +368,7 @@ impl SourceImpl for HttpSrc {
         }
 
         self.streaming_state = StreamingState::Stopped;
+        self.body.stop();
         self.streaming_state = try!(self.do_request(src, uri, start, stop));
 
         Ok(())
@@ -216,13 +382,12 @@ impl SourceImpl for HttpSrc {
         buffer: &mut gst::BufferRef,
     ) -> Result<(), FlowError> {
         let cat = self.cat;
+        let body = &self.body;
 
-        let (response, position) = match self.streaming_state {
+        let position = match self.streaming_state {
             StreamingState::Started {
-                ref mut response,
-                ref mut position,
-                ..
-            } => (response, position),
+                ref mut position, ..
+            } => position,
             StreamingState::Stopped => {
                 return Err(FlowError::Error(
                     error_msg!(gst::LibraryError::Failed, ["Not started yet"]),
@@ -248,15 +413,20 @@ impl SourceImpl for HttpSrc {
                 Some(map) => map,
             };
 
-            let data = map.as_mut_slice();
-
-            try!(response.read(data).or_else(|err| {
-                gst_error!(cat, obj: src, "Failed to read: {:?}", err);
-                Err(FlowError::Error(error_msg!(
-                    gst::ResourceError::Read,
-                    ["Failed to read at {}: {}", offset, err.to_string()]
-                )))
-            }))
+            match body.read(map.as_mut_slice()) {
+                Some(Ok(size)) => size,
+                Some(Err(err)) => {
+                    gst_error!(cat, obj: src, "Failed to read: {:?}", err);
+                    return Err(FlowError::Error(error_msg!(
+                        gst::ResourceError::Read,
+                        ["Failed to read at {}: {}", offset, err.to_string()]
+                    )));
+                }
+                None => {
+                    gst_debug!(cat, obj: src, "Flushing");
+                    return Err(FlowError::Flushing);
+                }
+            }
         };
 
         if size == 0 {
@@ -269,4 +439,22 @@ impl SourceImpl for HttpSrc {
 
         Ok(())
     }
+
+    fn set_property(&mut self, _src: &RsBaseSrc, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::String("user-agent", ..) => {
+                self.user_agent = value
+                    .get::<String>()
+                    .unwrap_or_else(|| DEFAULT_USER_AGENT.into());
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _src: &RsBaseSrc, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::String("user-agent", ..) => Ok(self.user_agent.to_value()),
+            _ => unimplemented!(),
+        }
+    }
 }
@@ -8,6 +8,7 @@
 
 #![crate_type = "cdylib"]
 
+extern crate glib;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
@@ -35,6 +36,10 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: HttpSrc::new_boxed,
             protocols: vec!["http".into(), "https".into()],
             push_only: true,
+            output_caps: gst::Caps::new_any(),
+            is_live: false,
+            format: gst::Format::Bytes,
+            properties: &httpsrc::PROPERTIES,
         },
     );
 
@@ -16,6 +16,7 @@ use gst_plugin::error::*;
 use gst_plugin::object::*;
 use gst_plugin::element::*;
 
+use glib;
 use gst;
 use gst::prelude::*;
 use gst_base;
@@ -23,6 +24,8 @@ use gst_base::prelude::*;
 
 pub type StreamIndex = u32;
 
+const PULL_BLOCKSIZE: u32 = 4096;
+
 #[derive(Debug)]
 pub enum SeekResult {
     TooEarly,
@@ -34,20 +37,43 @@ pub enum SeekResult {
 pub enum HandleBufferResult {
     NeedMoreData,
     Again,
-    // NeedDataFromOffset(u64),
+    // The implementation has to drop all data it still has queued up, new data
+    // will start at the given byte offset
+    NeedDataFromOffset(u64),
+    // After HaveAllStreams this starts a new group of streams
     StreamAdded(Stream),
     HaveAllStreams,
     StreamChanged(Stream),
     // StreamsAdded(Vec<Stream>), // Implies HaveAllStreams
     StreamsChanged(Vec<Stream>),
-    // TODO need something to replace/add new streams
-    // TODO should probably directly implement the GstStreams new world order
+    StreamRemoved(StreamIndex),
     BufferForStream(StreamIndex, gst::Buffer),
+    // Global tags if no stream is given
+    Tags(Option<StreamIndex>, gst::TagList),
+    // Serialized custom downstream event, for all streams if no stream is given
+    Event(Option<StreamIndex>, gst::Event),
+    // The value returned by get_duration() changed
+    DurationChanged,
     Eos(Option<StreamIndex>),
 }
 
 pub type RsDemuxer = RsElement;
 
+fn stream_type_from_caps(caps: &gst::Caps) -> gst::StreamType {
+    match caps.get_structure(0).map(|s| s.get_name()) {
+        Some(name) if name.starts_with("audio/") => gst::StreamType::AUDIO,
+        Some(name) if name.starts_with("video/") || name.starts_with("image/") => {
+            gst::StreamType::VIDEO
+        }
+        Some(name) if name.starts_with("text/") || name.starts_with("subtitle/") ||
+            name.starts_with("closedcaption/") =>
+        {
+            gst::StreamType::TEXT
+        }
+        _ => gst::StreamType::UNKNOWN,
+    }
+}
+
 pub trait DemuxerImpl: Send + 'static {
     fn start(
         &mut self,
@@ -63,16 +89,29 @@ pub trait DemuxerImpl: Send + 'static {
         start: u64,
         stop: Option<u64>,
     ) -> Result<SeekResult, ErrorMessage>;
+    // Only in push mode, if upstream could not seek to the offset returned by
+    // seek() and continues sending data from the previous position
+    fn seek_failed(&mut self, demuxer: &RsDemuxer);
     fn handle_buffer(
         &mut self,
         demuxer: &RsDemuxer,
         buffer: Option<gst::Buffer>,
     ) -> Result<HandleBufferResult, FlowError>;
+    // Afterwards handle_buffer() is called once more without buffer
     fn end_of_stream(&mut self, demuxer: &RsDemuxer) -> Result<(), ErrorMessage>;
 
     fn is_seekable(&self, demuxer: &RsDemuxer) -> bool;
     fn get_position(&self, demuxer: &RsDemuxer) -> Option<u64>;
     fn get_duration(&self, demuxer: &RsDemuxer) -> Option<u64>;
+
+    // Only called for the properties from DemuxerInfo
+    fn set_property(&mut self, _demuxer: &RsDemuxer, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _demuxer: &RsDemuxer, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 #[derive(Debug)]
@@ -102,6 +141,7 @@ pub struct DemuxerInfo {
     pub create_instance: fn(&RsDemuxer) -> Box<DemuxerImpl>,
     pub input_caps: gst::Caps,
     pub output_caps: gst::Caps,
+    pub properties: &'static [Property<'static>],
 }
 
 pub struct Demuxer {
@@ -109,10 +149,37 @@ pub struct Demuxer {
     sinkpad: gst::Pad,
     flow_combiner: Mutex<UniqueFlowCombiner>,
     group_id: Mutex<u32>,
+    // All streams, including the ones that are currently not selected
+    streams: Mutex<BTreeMap<u32, StreamState>>,
+    collection: Mutex<Option<gst::StreamCollection>>,
+    // Latest global (None) and per-stream tags, also for streams without pad yet
+    tags: Mutex<BTreeMap<Option<u32>, gst::TagList>>,
+    // Serialized events for streams that have no pad yet, global (None)
+    // ones for the pads that are added until all streams are known
+    pending_events: Mutex<Vec<(Option<u32>, gst::Event)>>,
+    // Pads of the currently selected streams
     srcpads: Mutex<BTreeMap<u32, gst::Pad>>,
+    // Read offset if operating in pull mode
+    pull_offset: Mutex<Option<u64>>,
+    // Seqnum of our own upstream seek from NeedDataFromOffset in push mode,
+    // its flush events are not forwarded downstream
+    offset_seek_seqnum: Mutex<Option<u32>>,
+    // Current output segment and if it still has to be pushed downstream
+    segment: Mutex<(gst::Segment, bool)>,
+    properties: &'static [Property<'static>],
+    // READY-only properties can't be changed while started
+    started: Mutex<bool>,
     imp: Mutex<Box<DemuxerImpl>>,
 }
 
+#[derive(Clone)]
+struct StreamState {
+    stream: gst::Stream,
+    pad: gst::Pad,
+    group_id: u32,
+    selected: bool,
+}
+
 #[derive(Default)]
 pub struct UniqueFlowCombiner(gst_base::FlowCombiner);
 
@@ -121,10 +188,18 @@ impl UniqueFlowCombiner {
         self.0.add_pad(pad);
     }
 
+    fn remove_pad(&mut self, pad: &gst::Pad) {
+        self.0.remove_pad(pad);
+    }
+
     fn clear(&mut self) {
         self.0.clear();
     }
 
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+
     fn update_flow(&mut self, flow_ret: gst::FlowReturn) -> gst::FlowReturn {
         self.0.update_flow(flow_ret)
     }
@@ -135,6 +210,9 @@ unsafe impl Sync for UniqueFlowCombiner {}
 
 impl Demuxer {
     fn new(demuxer: &RsDemuxer, sinkpad: gst::Pad, demuxer_info: &DemuxerInfo) -> Self {
+        let mut segment = gst::Segment::default();
+        segment.init(gst::Format::Time);
+
         Self {
             cat: gst::DebugCategory::new(
                 "rsdemux",
@@ -144,7 +222,16 @@ impl Demuxer {
             sinkpad: sinkpad,
             flow_combiner: Mutex::new(Default::default()),
             group_id: Mutex::new(gst::util_group_id_next()),
+            streams: Mutex::new(BTreeMap::new()),
+            collection: Mutex::new(None),
+            tags: Mutex::new(BTreeMap::new()),
+            pending_events: Mutex::new(Vec::new()),
             srcpads: Mutex::new(BTreeMap::new()),
+            pull_offset: Mutex::new(None),
+            offset_seek_seqnum: Mutex::new(None),
+            segment: Mutex::new((segment, false)),
+            properties: demuxer_info.properties,
+            started: Mutex::new(false),
             imp: Mutex::new((demuxer_info.create_instance)(demuxer)),
         }
     }
@@ -172,6 +259,8 @@ impl Demuxer {
             &demuxer_info.output_caps,
         );
         klass.add_pad_template(pad_template);
+
+        klass.install_properties(demuxer_info.properties);
     }
 
     fn init(element: &RsElement, demuxer_info: &DemuxerInfo) -> Box<ElementImpl<RsElement>> {
@@ -188,8 +277,7 @@ impl Demuxer {
     }
 
     fn add_stream(&self, element: &RsElement, index: u32, caps: gst::Caps, stream_id: &str) {
-        let mut srcpads = self.srcpads.lock().unwrap();
-        assert!(!srcpads.contains_key(&index));
+        gst_debug!(self.cat, obj: element, "Adding stream {} with caps {:?}", index, caps);
 
         let templ = element.get_pad_template("src_%u").unwrap();
         let name = format!("src_{}", index);
@@ -197,32 +285,244 @@ impl Demuxer {
         pad.set_query_function(Demuxer::src_query);
         pad.set_event_function(Demuxer::src_event);
 
-        pad.set_active(true).unwrap();
-
         let full_stream_id = pad.create_stream_id(element, stream_id).unwrap();
-        pad.push_event(
-            gst::Event::new_stream_start(&full_stream_id)
-                .group_id(*self.group_id.lock().unwrap())
-                .build(),
+        let stream = gst::Stream::new(
+            Some(full_stream_id.as_str()),
+            Some(&caps),
+            stream_type_from_caps(&caps),
+            gst::StreamFlags::empty(),
         );
-        pad.push_event(gst::Event::new_caps(&caps).build());
 
-        let mut segment = gst::Segment::default();
-        segment.init(gst::Format::Time);
-        pad.push_event(gst::Event::new_segment(&segment).build());
+        let group_id = *self.group_id.lock().unwrap();
+
+        // Streams added after all others were announced already are a new
+        // group, which the exposed pads have to join. Events are only pushed
+        // without the locks as downstream can call back into us
+        let mut moved_pads = Vec::new();
+        let stream_state = {
+            let mut streams = self.streams.lock().unwrap();
+            assert!(!streams.contains_key(&index));
+
+            if self.collection.lock().unwrap().is_some() {
+                let srcpads = self.srcpads.lock().unwrap();
+                for (other_index, stream_state) in streams.iter_mut() {
+                    stream_state.group_id = group_id;
+                    if let Some(pad) = srcpads.get(other_index) {
+                        moved_pads.push((pad.clone(), Self::stream_start_event(stream_state)));
+                    }
+                }
+            }
+
+            let stream_state = StreamState {
+                stream: stream,
+                pad: pad,
+                group_id: group_id,
+                selected: true,
+            };
+            streams.insert(index, stream_state.clone());
+            stream_state
+        };
+
+        for (pad, event) in moved_pads {
+            gst_debug!(
+                self.cat,
+                obj: element,
+                "Moving pad {} to group {}",
+                pad.get_name(),
+                group_id
+            );
+            pad.push_event(event);
+        }
+
+        self.expose_stream(element, index, &stream_state);
+
+        if self.collection.lock().unwrap().is_some() {
+            self.added_all_streams(element);
+        }
+    }
+
+    fn remove_stream(&self, element: &RsElement, index: u32) {
+        if self.streams.lock().unwrap().remove(&index).is_none() {
+            gst_warning!(self.cat, obj: element, "Removing unknown stream {}", index);
+            return;
+        }
+
+        gst_debug!(self.cat, obj: element, "Removing stream {}", index);
+
+        self.hide_stream(element, index);
+        self.tags.lock().unwrap().remove(&Some(index));
+
+        if self.collection.lock().unwrap().is_some() {
+            self.update_collection(element);
+        }
+    }
 
-        self.flow_combiner.lock().unwrap().add_pad(&pad);
-        element.add_pad(&pad).unwrap();
+    fn expose_stream(&self, element: &RsElement, index: u32, stream_state: &StreamState) {
+        let pad = &stream_state.pad;
 
-        srcpads.insert(index, pad);
+        gst_debug!(self.cat, obj: element, "Exposing pad {}", pad.get_name());
+
+        pad.set_active(true).unwrap();
+
+        pad.push_event(Self::stream_start_event(stream_state));
+        if let Some(collection) = self.collection.lock().unwrap().as_ref() {
+            pad.push_event(gst::Event::new_stream_collection(collection).build());
+        }
+        if let Some(caps) = stream_state.stream.get_caps() {
+            pad.push_event(gst::Event::new_caps(&caps).build());
+        }
+        pad.push_event(gst::Event::new_segment(&self.segment.lock().unwrap().0).build());
+        {
+            let tags = self.tags.lock().unwrap();
+            for tags in tags.get(&None).into_iter().chain(tags.get(&Some(index))) {
+                pad.push_event(gst::Event::new_tag(tags.clone()).build());
+            }
+        }
+
+        // Non-sticky events would be dropped by the unlinked pad, they are
+        // only pushed once the pad is added and linked from pad-added
+        let mut events = Vec::new();
+        self.pending_events.lock().unwrap().retain(|&(event_index, ref event)| {
+            match event_index {
+                None => {
+                    events.push(event.clone());
+                }
+                Some(event_index) if event_index == index => {
+                    events.push(event.clone());
+                    return false;
+                }
+                Some(_) => (),
+            }
+            true
+        });
+
+        self.flow_combiner.lock().unwrap().add_pad(pad);
+        element.add_pad(pad).unwrap();
+
+        for event in events {
+            pad.push_event(event);
+        }
+
+        self.srcpads.lock().unwrap().insert(index, pad.clone());
+    }
+
+    fn stream_start_event(stream_state: &StreamState) -> gst::Event {
+        let stream_id = stream_state.stream.get_stream_id().unwrap();
+        gst::Event::new_stream_start(&stream_id)
+            .group_id(stream_state.group_id)
+            .stream(stream_state.stream.clone())
+            .build()
+    }
+
+    fn hide_stream(&self, element: &RsElement, index: u32) {
+        let pad = match self.srcpads.lock().unwrap().remove(&index) {
+            Some(pad) => pad,
+            None => return,
+        };
+
+        gst_debug!(self.cat, obj: element, "Removing pad {}", pad.get_name());
+
+        pad.push_event(gst::Event::new_eos().build());
+        self.flow_combiner.lock().unwrap().remove_pad(&pad);
+        let _ = pad.set_active(false);
+        element.remove_pad(&pad).unwrap();
     }
 
     fn added_all_streams(&self, element: &RsElement) {
+        self.update_collection(element);
+
         element.no_more_pads();
         *self.group_id.lock().unwrap() = gst::util_group_id_next();
+
+        // All pads that could get them are exposed now
+        self.pending_events
+            .lock()
+            .unwrap()
+            .retain(|&(index, _)| index.is_some());
+    }
+
+    fn update_collection(&self, element: &RsElement) {
+        let collection = gst::StreamCollection::new(None);
+        for stream_state in self.streams.lock().unwrap().values() {
+            collection.add_stream(&stream_state.stream);
+        }
+        *self.collection.lock().unwrap() = Some(collection.clone());
+
+        gst_debug!(self.cat, obj: element, "Posting stream collection");
+        element.post_message(
+            &gst::Message::new_stream_collection(&collection)
+                .src(Some(element))
+                .build(),
+        );
+
+        let event = gst::Event::new_stream_collection(&collection).build();
+        for pad in self.srcpads.lock().unwrap().values() {
+            pad.push_event(event.clone());
+        }
+    }
+
+    fn select_streams(&self, element: &RsElement, stream_ids: &[String]) -> bool {
+        let collection = match *self.collection.lock().unwrap() {
+            Some(ref collection) => collection.clone(),
+            None => {
+                gst_debug!(self.cat, obj: element, "No stream collection yet");
+                return false;
+            }
+        };
+
+        gst_debug!(self.cat, obj: element, "Selecting streams {:?}", stream_ids);
+
+        // Pads are only added and removed after unlocking, as downstream can
+        // call back into us from that
+        let mut selected = Vec::new();
+        let mut exposed = Vec::new();
+        let mut hidden = Vec::new();
+        {
+            let mut streams = self.streams.lock().unwrap();
+            for (&index, stream_state) in streams.iter_mut() {
+                let select = stream_state
+                    .stream
+                    .get_stream_id()
+                    .map(|id| stream_ids.contains(&id))
+                    .unwrap_or(false);
+
+                if select && !stream_state.selected {
+                    exposed.push((index, stream_state.clone()));
+                } else if !select && stream_state.selected {
+                    hidden.push(index);
+                }
+                stream_state.selected = select;
+
+                if select {
+                    selected.push(stream_state.stream.clone());
+                }
+            }
+        }
+
+        for index in hidden {
+            self.hide_stream(element, index);
+        }
+        for (index, stream_state) in exposed {
+            self.expose_stream(element, index, &stream_state);
+        }
+
+        let selected = selected.iter().collect::<Vec<_>>();
+        element.post_message(
+            &gst::Message::new_streams_selected(&collection)
+                .streams(&selected)
+                .src(Some(element))
+                .build(),
+        );
+        element.no_more_pads();
+
+        true
     }
 
     fn stream_format_changed(&self, _element: &RsElement, index: u32, caps: gst::Caps) {
+        if let Some(stream_state) = self.streams.lock().unwrap().get(&index) {
+            stream_state.stream.set_caps(Some(&caps));
+        }
+
         let srcpads = self.srcpads.lock().unwrap();
 
         if let Some(pad) = srcpads.get(&index) {
@@ -230,6 +530,65 @@ impl Demuxer {
         }
     }
 
+    fn stream_push_tags(&self, element: &RsElement, index: Option<u32>, tags: gst::TagList) {
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Got tags {:?} for stream {:?}",
+            tags,
+            index
+        );
+
+        self.tags.lock().unwrap().insert(index, tags.clone());
+
+        // Pads that are not exposed yet get them once they are
+        let srcpads = self.srcpads.lock().unwrap();
+        match index {
+            Some(index) => if let Some(pad) = srcpads.get(&index) {
+                pad.push_event(gst::Event::new_tag(tags).build());
+            },
+            None => for pad in srcpads.values() {
+                pad.push_event(gst::Event::new_tag(tags.clone()).build());
+            },
+        };
+    }
+
+    fn stream_push_event(&self, element: &RsElement, index: Option<u32>, event: gst::Event) {
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Got event {:?} for stream {:?}",
+            event,
+            index
+        );
+
+        // Keep events until the pads are exposed, e.g. cue points before the
+        // first buffers. Streams that are not selected don't get them
+        let queue = match index {
+            Some(index) => !self.streams.lock().unwrap().contains_key(&index),
+            None => self.collection.lock().unwrap().is_none(),
+        };
+        if queue && (event.is_sticky() || event.is_serialized()) {
+            gst_debug!(self.cat, obj: element, "Queueing event for pads exposed later");
+            self.pending_events
+                .lock()
+                .unwrap()
+                .push((index, event.clone()));
+        }
+
+        let srcpads = self.srcpads.lock().unwrap();
+        self.push_pending_segment(element, &srcpads);
+
+        match index {
+            Some(index) => if let Some(pad) = srcpads.get(&index) {
+                pad.push_event(event);
+            },
+            None => for pad in srcpads.values() {
+                pad.push_event(event.clone());
+            },
+        };
+    }
+
     fn stream_eos(&self, _element: &RsElement, index: Option<u32>) {
         let srcpads = self.srcpads.lock().unwrap();
 
@@ -246,46 +605,88 @@ impl Demuxer {
 
     fn stream_push_buffer(
         &self,
-        _element: &RsElement,
+        element: &RsElement,
         index: u32,
         buffer: gst::Buffer,
     ) -> gst::FlowReturn {
         let srcpads = self.srcpads.lock().unwrap();
+        self.push_pending_segment(element, &srcpads);
 
         if let Some(pad) = srcpads.get(&index) {
-            self.flow_combiner
+            return self.flow_combiner
                 .lock()
                 .unwrap()
-                .update_flow(pad.push(buffer))
+                .update_flow(pad.push(buffer));
+        }
+        drop(srcpads);
+
+        // Streams that are not selected have no pad, just drop their buffers
+        if self.streams.lock().unwrap().contains_key(&index) {
+            gst::FlowReturn::Ok
         } else {
             gst::FlowReturn::Error
         }
     }
 
+    fn push_pending_segment(&self, element: &RsElement, srcpads: &BTreeMap<u32, gst::Pad>) {
+        let mut segment = self.segment.lock().unwrap();
+        if segment.1 {
+            gst_debug!(self.cat, obj: element, "Pushing new segment {:?}", segment.0);
+            for pad in srcpads.values() {
+                pad.push_event(gst::Event::new_segment(&segment.0).build());
+            }
+            segment.1 = false;
+        }
+    }
+
     fn remove_all_streams(&self, element: &RsElement) {
+        let mut streams = self.streams.lock().unwrap();
+
         self.flow_combiner.lock().unwrap().clear();
         let mut srcpads = self.srcpads.lock().unwrap();
-        for (_, pad) in srcpads.iter().by_ref() {
+        for pad in srcpads.values() {
             element.remove_pad(pad).unwrap();
         }
         srcpads.clear();
+
+        streams.clear();
+        *self.collection.lock().unwrap() = None;
+        self.tags.lock().unwrap().clear();
+        self.pending_events.lock().unwrap().clear();
     }
 
-    fn sink_activate(pad: &gst::Pad, _parent: &Option<gst::Object>) -> bool {
+    fn sink_activate(pad: &gst::Pad, parent: &Option<gst::Object>) -> bool {
+        use gst::QueryView;
+
+        let element = parent
+            .as_ref()
+            .cloned()
+            .unwrap()
+            .downcast::<RsElement>()
+            .unwrap();
+        let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+
         let mode = {
             let query = gst::Query::new_scheduling();
             if !pad.peer_query(query.get_mut().unwrap()) {
                 return false;
             }
 
-            // TODO
-            //if (gst_query_has_scheduling_mode_with_flags (query, GST_PAD_MODE_PULL, GST_SCHEDULING_FLAG_SEEKABLE)) {
-            //  GST_DEBUG_OBJECT (demuxer, "Activating in PULL mode");
-            //  mode = GST_PAD_MODE_PULL;
-            //} else {
-            //GST_DEBUG_OBJECT (demuxer, "Activating in PUSH mode");
-            //}
-            gst::PadMode::Push
+            let pull_mode = match query.view() {
+                QueryView::Scheduling(ref q) => q.has_scheduling_mode_with_flags(
+                    gst::PadMode::Pull,
+                    gst::SchedulingFlags::SEEKABLE,
+                ),
+                _ => unreachable!(),
+            };
+
+            if pull_mode {
+                gst_debug!(demuxer.cat, obj: &element, "Activating in pull mode");
+                gst::PadMode::Pull
+            } else {
+                gst_debug!(demuxer.cat, obj: &element, "Activating in push mode");
+                gst::PadMode::Push
+            }
         };
 
         match pad.activate_mode(mode, true) {
@@ -308,6 +709,7 @@ impl Demuxer {
         match demuxer_impl.start(element, upstream_size, random_access) {
             Ok(..) => {
                 gst_trace!(self.cat, obj: element, "Successfully started",);
+                *self.started.lock().unwrap() = true;
                 true
             }
             Err(ref msg) => {
@@ -323,6 +725,7 @@ impl Demuxer {
 
         gst_debug!(self.cat, obj: element, "Stopping");
 
+        *self.started.lock().unwrap() = false;
         match demuxer_impl.stop(element) {
             Ok(..) => {
                 gst_trace!(self.cat, obj: element, "Successfully stop");
@@ -368,14 +771,15 @@ impl Demuxer {
             }
 
             if mode == gst::PadMode::Pull {
-                // TODO
-                // demuxer.sinkpad.start_task(...)
+                *demuxer.pull_offset.lock().unwrap() = Some(0);
+                return demuxer.start_task();
             }
 
             true
         } else {
             if mode == gst::PadMode::Pull {
                 let _ = demuxer.sinkpad.stop_task();
+                *demuxer.pull_offset.lock().unwrap() = None;
             }
 
             demuxer.stop(&element)
@@ -395,23 +799,93 @@ impl Demuxer {
             .unwrap();
         let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
 
+        demuxer.handle_buffer(&element, Some(buffer))
+    }
+
+    fn sink_loop(&self, element: &RsDemuxer) {
+        let offset = match *self.pull_offset.lock().unwrap() {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        gst_trace!(
+            self.cat,
+            obj: element,
+            "Pulling {} bytes at offset {}",
+            PULL_BLOCKSIZE,
+            offset
+        );
+
+        let flow_ret = match self.sinkpad.pull_range(offset, PULL_BLOCKSIZE) {
+            Ok(buffer) => {
+                if let Some(ref mut offset) = *self.pull_offset.lock().unwrap() {
+                    *offset += buffer.get_size() as u64;
+                }
+                self.handle_buffer(element, Some(buffer))
+            }
+            Err(gst::FlowReturn::Eos) => {
+                // Upstream has no more data, the implementation might still
+                // have some queued up
+                self.end_of_stream(element);
+                gst::FlowReturn::Eos
+            }
+            Err(flow_ret) => flow_ret,
+        };
+
+        if flow_ret == gst::FlowReturn::Ok {
+            return;
+        }
+
+        gst_debug!(self.cat, obj: element, "Pausing task: {:?}", flow_ret);
+        let _ = self.sinkpad.pause_task();
+
+        match flow_ret {
+            gst::FlowReturn::Flushing => (),
+            gst::FlowReturn::Eos => {
+                self.stream_eos(element, None);
+            }
+            _ => {
+                error_msg!(
+                    gst::StreamError::Failed,
+                    ["Streaming stopped, reason {:?}", flow_ret]
+                ).post(element);
+                self.stream_eos(element, None);
+            }
+        }
+    }
+
+    fn start_task(&self) -> bool {
+        let pad = self.sinkpad.clone();
+        self.sinkpad
+            .start_task(move || {
+                let element = pad.get_parent()
+                    .unwrap()
+                    .downcast::<RsElement>()
+                    .unwrap();
+                let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+                demuxer.sink_loop(&element);
+            })
+            .is_ok()
+    }
+
+    fn handle_buffer(&self, element: &RsDemuxer, buffer: Option<gst::Buffer>) -> gst::FlowReturn {
         let mut res = {
-            let demuxer_impl = &mut demuxer.imp.lock().unwrap();
+            let demuxer_impl = &mut self.imp.lock().unwrap();
 
-            gst_trace!(demuxer.cat, obj: &element, "Handling buffer {:?}", buffer);
+            gst_trace!(self.cat, obj: element, "Handling buffer {:?}", buffer);
 
-            match demuxer_impl.handle_buffer(&element, Some(buffer)) {
+            match demuxer_impl.handle_buffer(element, buffer) {
                 Ok(res) => res,
                 Err(flow_error) => {
                     gst_error!(
-                        demuxer.cat,
-                        obj: &element,
+                        self.cat,
+                        obj: element,
                         "Failed handling buffer: {:?}",
                         flow_error
                     );
                     match flow_error {
                         FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
-                            msg.post(&element)
+                            msg.post(element)
                         }
                         _ => (),
                     }
@@ -422,33 +896,57 @@ impl Demuxer {
 
         // Loop until AllEos, NeedMoreData or error when pushing downstream
         loop {
-            gst_trace!(demuxer.cat, obj: &element, "Handled {:?}", res);
+            gst_trace!(self.cat, obj: element, "Handled {:?}", res);
 
             match res {
                 HandleBufferResult::NeedMoreData => {
                     return gst::FlowReturn::Ok;
                 }
+                HandleBufferResult::NeedDataFromOffset(offset) => {
+                    return self.seek_to_offset(element, offset);
+                }
                 HandleBufferResult::StreamAdded(stream) => {
-                    demuxer.add_stream(&element, stream.index, stream.caps, &stream.stream_id);
+                    self.add_stream(element, stream.index, stream.caps, &stream.stream_id);
                 }
                 HandleBufferResult::HaveAllStreams => {
-                    demuxer.added_all_streams(&element);
+                    self.added_all_streams(element);
                 }
                 HandleBufferResult::StreamChanged(stream) => {
-                    demuxer.stream_format_changed(&element, stream.index, stream.caps);
+                    self.stream_format_changed(element, stream.index, stream.caps);
                 }
                 HandleBufferResult::StreamsChanged(streams) => for stream in streams {
-                    demuxer.stream_format_changed(&element, stream.index, stream.caps);
+                    self.stream_format_changed(element, stream.index, stream.caps);
                 },
+                HandleBufferResult::StreamRemoved(index) => {
+                    self.remove_stream(element, index);
+                }
                 HandleBufferResult::BufferForStream(index, buffer) => {
-                    let flow_ret = demuxer.stream_push_buffer(&element, index, buffer);
+                    let flow_ret = self.stream_push_buffer(element, index, buffer);
 
                     if flow_ret != gst::FlowReturn::Ok {
                         return flow_ret;
                     }
                 }
-                HandleBufferResult::Eos(index) => {
-                    demuxer.stream_eos(&element, index);
+                HandleBufferResult::Tags(index, tags) => {
+                    self.stream_push_tags(element, index, tags);
+                }
+                HandleBufferResult::Event(index, event) => {
+                    self.stream_push_event(element, index, event);
+                }
+                HandleBufferResult::DurationChanged => {
+                    gst_debug!(self.cat, obj: element, "Posting duration changed");
+                    element.post_message(
+                        &gst::Message::new_duration_changed()
+                            .src(Some(element))
+                            .build(),
+                    );
+                }
+                HandleBufferResult::Eos(Some(index)) => {
+                    self.stream_eos(element, Some(index));
+                }
+                // EOS is pushed downstream by the streaming task in pull mode,
+                // or after upstream's EOS event in push mode
+                HandleBufferResult::Eos(None) => {
                     return gst::FlowReturn::Eos;
                 }
                 HandleBufferResult::Again => {
@@ -456,22 +954,22 @@ impl Demuxer {
                 }
             };
 
-            gst_trace!(demuxer.cat, obj: &element, "Calling again");
+            gst_trace!(self.cat, obj: element, "Calling again");
 
             res = {
-                let demuxer_impl = &mut demuxer.imp.lock().unwrap();
-                match demuxer_impl.handle_buffer(&element, None) {
+                let demuxer_impl = &mut self.imp.lock().unwrap();
+                match demuxer_impl.handle_buffer(element, None) {
                     Ok(res) => res,
                     Err(flow_error) => {
                         gst_error!(
-                            demuxer.cat,
-                            obj: &element,
+                            self.cat,
+                            obj: element,
                             "Failed calling again: {:?}",
                             flow_error
                         );
                         match flow_error {
                             FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
-                                msg.post(&element)
+                                msg.post(element)
                             }
                             _ => (),
                         }
@@ -482,6 +980,60 @@ impl Demuxer {
         }
     }
 
+    fn seek_to_offset(&self, element: &RsDemuxer, offset: u64) -> gst::FlowReturn {
+        // In pull mode we simply continue reading from the new offset
+        if let Some(ref mut pull_offset) = *self.pull_offset.lock().unwrap() {
+            gst_debug!(self.cat, obj: element, "Continuing at offset {}", offset);
+            *pull_offset = offset;
+            return gst::FlowReturn::Ok;
+        }
+
+        gst_debug!(self.cat, obj: element, "Seeking upstream to offset {}", offset);
+
+        let seek_event = gst::Event::new_seek(
+            1.0,
+            gst::Format::Bytes,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            offset as i64,
+            gst::SeekType::None,
+            -1,
+        ).build();
+
+        *self.offset_seek_seqnum.lock().unwrap() = Some(seek_event.get_seqnum());
+
+        if self.sinkpad.push_event(seek_event) {
+            gst::FlowReturn::Ok
+        } else {
+            *self.offset_seek_seqnum.lock().unwrap() = None;
+            error_msg!(
+                gst::StreamError::Demux,
+                ["Failed to seek upstream to offset {}", offset]
+            ).post(element);
+            gst::FlowReturn::Error
+        }
+    }
+
+    fn end_of_stream(&self, element: &RsDemuxer) {
+        {
+            let demuxer_impl = &mut self.imp.lock().unwrap();
+
+            gst_debug!(self.cat, obj: element, "End of stream");
+            match demuxer_impl.end_of_stream(element) {
+                Ok(_) => (),
+                Err(ref msg) => {
+                    gst_error!(self.cat, obj: element, "Failed end of stream: {:?}", msg);
+                    msg.post(element);
+                    return;
+                }
+            }
+        }
+
+        // Let the implementation output whatever it can still get out of
+        // the data it has queued up
+        self.handle_buffer(element, None);
+    }
+
     fn sink_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
         use gst::EventView;
 
@@ -495,24 +1047,31 @@ impl Demuxer {
 
         match event.view() {
             EventView::Eos(..) => {
-                let demuxer_impl = &mut demuxer.imp.lock().unwrap();
-
-                gst_debug!(demuxer.cat, obj: &element, "End of stream");
-                match demuxer_impl.end_of_stream(&element) {
-                    Ok(_) => (),
-                    Err(ref msg) => {
-                        gst_error!(
-                            demuxer.cat,
-                            obj: &element,
-                            "Failed end of stream: {:?}",
-                            msg
-                        );
-                        msg.post(&element);
-                    }
+                demuxer.end_of_stream(&element);
+                pad.event_default(parent.as_ref(), event)
+            }
+            EventView::Segment(..) => {
+                // Upstream segments are in bytes, we push our own time segments downstream
+                true
+            }
+            EventView::FlushStart(..) | EventView::FlushStop(..)
+                if *demuxer.offset_seek_seqnum.lock().unwrap() == Some(event.get_seqnum()) =>
+            {
+                gst_debug!(
+                    demuxer.cat,
+                    obj: &element,
+                    "Dropping flush event of our own seek"
+                );
+
+                if let EventView::FlushStop(..) = event.view() {
+                    *demuxer.offset_seek_seqnum.lock().unwrap() = None;
                 }
+                true
+            }
+            EventView::FlushStop(..) => {
+                demuxer.flow_combiner.lock().unwrap().reset();
                 pad.event_default(parent.as_ref(), event)
             }
-            EventView::Segment(..) => pad.event_default(parent.as_ref(), event),
             _ => pad.event_default(parent.as_ref(), event),
         }
     }
@@ -553,6 +1112,45 @@ impl Demuxer {
                     return false;
                 }
             }
+            QueryView::Seeking(ref mut q) => {
+                let (fmt, _, _, _) = q.get();
+                if fmt == gst::Format::Time {
+                    let (seekable, duration) = {
+                        let demuxer_impl = &demuxer.imp.lock().unwrap();
+                        (
+                            demuxer_impl.is_seekable(&element),
+                            demuxer_impl.get_duration(&element),
+                        )
+                    };
+
+                    // In push mode we can only seek if upstream can seek in bytes for us
+                    let pull_mode = demuxer.pull_offset.lock().unwrap().is_some();
+                    let seekable = seekable && (pull_mode || {
+                        let query = gst::Query::new_seeking(gst::Format::Bytes);
+                        if demuxer.sinkpad.peer_query(query.get_mut().unwrap()) {
+                            match query.view() {
+                                QueryView::Seeking(ref q) => q.get().1,
+                                _ => unreachable!(),
+                            }
+                        } else {
+                            false
+                        }
+                    });
+
+                    gst_trace!(
+                        demuxer.cat,
+                        obj: &element,
+                        "Returning seekable {} with duration {:?}",
+                        seekable,
+                        duration
+                    );
+
+                    q.set(seekable, 0, duration.map(|d| d as i64).unwrap_or(-1));
+                    return true;
+                } else {
+                    return false;
+                }
+            }
             QueryView::Duration(ref mut q) => {
                 let (fmt, _) = q.get();
                 if fmt == gst::Format::Time {
@@ -588,15 +1186,165 @@ impl Demuxer {
     fn src_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
         use gst::EventView;
 
+        let element = parent
+            .as_ref()
+            .cloned()
+            .unwrap()
+            .downcast::<RsElement>()
+            .unwrap();
+        let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+
         match event.view() {
-            EventView::Seek(..) => {
-                // TODO: Implement
-                false
+            EventView::Seek(..) => demuxer.handle_seek(&element, &event),
+            EventView::SelectStreams(ref e) => {
+                let stream_ids = e.get_streams()
+                    .iter()
+                    .map(|id| String::from(&id[..]))
+                    .collect::<Vec<_>>();
+                demuxer.select_streams(&element, &stream_ids)
             }
             _ => pad.event_default(parent.as_ref(), event),
         }
     }
 
+    fn handle_seek(&self, element: &RsDemuxer, event: &gst::Event) -> bool {
+        use gst::EventView;
+
+        let (rate, format, flags, start_type, start, stop_type, stop) = match event.view() {
+            EventView::Seek(ref seek) => seek.get(),
+            _ => unreachable!(),
+        };
+
+        if format != gst::Format::Time {
+            gst_debug!(self.cat, obj: element, "Can only seek in time format");
+            return false;
+        }
+
+        if rate <= 0.0 {
+            gst_debug!(self.cat, obj: element, "Reverse playback not supported");
+            return false;
+        }
+
+        let start = if start_type == gst::SeekType::Set && start >= 0 {
+            start as u64
+        } else {
+            0
+        };
+        let stop = if stop_type == gst::SeekType::Set && stop >= 0 {
+            stop as u64
+        } else {
+            u64::MAX
+        };
+
+        if self.pull_offset.lock().unwrap().is_some() {
+            return self.handle_pull_seek(element, event, rate, flags, start, stop);
+        }
+
+        // Data from the new position might arrive before pushing the seek
+        // returns, so switch to it now and go back if upstream fails to seek
+        let old_segment = self.segment.lock().unwrap().clone();
+
+        let mut offset = 0;
+        if !self.seek(element, start, stop, &mut offset) {
+            return false;
+        }
+
+        if offset == u64::MAX {
+            // Already pushed EOS, nothing to get from upstream anymore
+            return true;
+        }
+
+        self.update_segment(rate, start, stop);
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Seeking upstream to byte offset {}",
+            offset
+        );
+
+        let seek_event = gst::Event::new_seek(
+            rate,
+            gst::Format::Bytes,
+            flags,
+            gst::SeekType::Set,
+            offset as i64,
+            gst::SeekType::None,
+            -1,
+        ).seqnum(event.get_seqnum())
+            .build();
+
+        if self.sinkpad.push_event(seek_event) {
+            return true;
+        }
+
+        gst_debug!(self.cat, obj: element, "Upstream failed to seek");
+        *self.segment.lock().unwrap() = old_segment;
+        self.imp.lock().unwrap().seek_failed(element);
+
+        false
+    }
+
+    fn update_segment(&self, rate: f64, start: u64, stop: u64) {
+        let mut segment = self.segment.lock().unwrap();
+        segment.0.init(gst::Format::Time);
+        segment.0.set_rate(rate);
+        segment.0.set_start(start);
+        segment.0.set_stop(stop);
+        segment.0.set_time(start);
+        segment.0.set_position(start);
+        segment.1 = true;
+    }
+
+    fn push_flush_event(&self, event: gst::Event) {
+        self.sinkpad.push_event(event.clone());
+        for pad in self.srcpads.lock().unwrap().values() {
+            pad.push_event(event.clone());
+        }
+    }
+
+    fn handle_pull_seek(
+        &self,
+        element: &RsDemuxer,
+        event: &gst::Event,
+        rate: f64,
+        flags: gst::SeekFlags,
+        start: u64,
+        stop: u64,
+    ) -> bool {
+        let flush = flags.contains(gst::SeekFlags::FLUSH);
+        let seqnum = event.get_seqnum();
+
+        if flush {
+            self.push_flush_event(gst::Event::new_flush_start().seqnum(seqnum).build());
+        }
+
+        // Waits until the streaming thread has stopped
+        let _ = self.sinkpad.pause_task();
+
+        let mut offset = 0;
+        let res = self.seek(element, start, stop, &mut offset);
+
+        if flush {
+            self.push_flush_event(gst::Event::new_flush_stop(true).seqnum(seqnum).build());
+            self.flow_combiner.lock().unwrap().reset();
+        }
+
+        if res && offset != u64::MAX {
+            self.update_segment(rate, start, stop);
+
+            gst_debug!(self.cat, obj: element, "Continuing at byte offset {}", offset);
+            *self.pull_offset.lock().unwrap() = Some(offset);
+        }
+
+        // Continue from wherever we are now, even if seeking failed
+        if offset != u64::MAX {
+            self.start_task();
+        }
+
+        res
+    }
+
     fn seek(&self, element: &RsDemuxer, start: u64, stop: u64, offset: &mut u64) -> bool {
         let stop = if stop == u64::MAX { None } else { Some(stop) };
 
@@ -637,7 +1385,37 @@ impl Demuxer {
     }
 }
 
-impl ObjectImpl<RsElement> for Demuxer {}
+impl ObjectImpl<RsElement> for Demuxer {
+    fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
+        let element = obj.clone().dynamic_cast::<RsDemuxer>().unwrap();
+        let prop = &self.properties[id as usize];
+
+        // Keep the lock until the property is set so that the demuxer
+        // can't be started in the meantime
+        let demuxer_impl = &mut self.imp.lock().unwrap();
+        if prop.get_mutability() == PropertyMutability::ReadWriteReady &&
+            *self.started.lock().unwrap()
+        {
+            gst_warning!(
+                self.cat,
+                obj: &element,
+                "Can't change property '{}' while started",
+                prop.get_name()
+            );
+            return;
+        }
+
+        demuxer_impl.set_property(&element, prop, value);
+    }
+
+    fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
+        let element = obj.clone().dynamic_cast::<RsDemuxer>().unwrap();
+        let prop = &self.properties[id as usize];
+
+        let demuxer_impl = &self.imp.lock().unwrap();
+        demuxer_impl.get_property(&element, prop)
+    }
+}
 
 impl ElementImpl<RsElement> for Demuxer {
     fn change_state(
@@ -653,6 +1431,12 @@ impl ElementImpl<RsElement> for Demuxer {
             gst::StateChange::ReadyToPaused => {
                 // TODO
                 *self.group_id.lock().unwrap() = gst::util_group_id_next();
+                *self.offset_seek_seqnum.lock().unwrap() = None;
+
+                let mut segment = self.segment.lock().unwrap();
+                segment.0 = gst::Segment::default();
+                segment.0.init(gst::Format::Time);
+                segment.1 = false;
             }
             _ => (),
         }
@@ -664,12 +1448,7 @@ impl ElementImpl<RsElement> for Demuxer {
 
         match transition {
             gst::StateChange::PausedToReady => {
-                self.flow_combiner.lock().unwrap().clear();
-                let mut srcpads = self.srcpads.lock().unwrap();
-                for (_, pad) in srcpads.iter().by_ref() {
-                    element.remove_pad(pad).unwrap();
-                }
-                srcpads.clear();
+                self.remove_all_streams(element);
             }
             _ => (),
         }
@@ -709,3 +1488,172 @@ pub fn demuxer_register(plugin: &gst::Plugin, demuxer_info: DemuxerInfo) {
     let type_ = register_type(demuxer_static);
     gst::Element::register(plugin, &name, rank, type_);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    // Sends a cue point before its only stream is known, then one buffer
+    struct TestDemuxer {
+        results: Vec<HandleBufferResult>,
+    }
+
+    impl DemuxerImpl for TestDemuxer {
+        fn start(
+            &mut self,
+            _demuxer: &RsDemuxer,
+            _upstream_size: Option<u64>,
+            _random_access: bool,
+        ) -> Result<(), ErrorMessage> {
+            Ok(())
+        }
+
+        fn stop(&mut self, _demuxer: &RsDemuxer) -> Result<(), ErrorMessage> {
+            Ok(())
+        }
+
+        fn seek(
+            &mut self,
+            _demuxer: &RsDemuxer,
+            _start: u64,
+            _stop: Option<u64>,
+        ) -> Result<SeekResult, ErrorMessage> {
+            Ok(SeekResult::Eos)
+        }
+
+        fn seek_failed(&mut self, _demuxer: &RsDemuxer) {}
+
+        fn handle_buffer(
+            &mut self,
+            _demuxer: &RsDemuxer,
+            buffer: Option<gst::Buffer>,
+        ) -> Result<HandleBufferResult, FlowError> {
+            if let Some(buffer) = buffer {
+                let caps = gst::Caps::new_simple("test/x-buffers", &[]);
+                let cue_point = gst::Structure::new_empty("cue-point");
+                self.results = vec![
+                    HandleBufferResult::Event(
+                        None,
+                        gst::Event::new_custom_downstream(cue_point).build(),
+                    ),
+                    HandleBufferResult::StreamAdded(Stream::new(0, caps, String::from("0"))),
+                    HandleBufferResult::HaveAllStreams,
+                    HandleBufferResult::BufferForStream(0, buffer),
+                ];
+                self.results.reverse();
+            }
+
+            Ok(self.results.pop().unwrap_or(HandleBufferResult::NeedMoreData))
+        }
+
+        fn end_of_stream(&mut self, _demuxer: &RsDemuxer) -> Result<(), ErrorMessage> {
+            Ok(())
+        }
+
+        fn is_seekable(&self, _demuxer: &RsDemuxer) -> bool {
+            false
+        }
+
+        fn get_position(&self, _demuxer: &RsDemuxer) -> Option<u64> {
+            None
+        }
+
+        fn get_duration(&self, _demuxer: &RsDemuxer) -> Option<u64> {
+            None
+        }
+    }
+
+    fn create_test_demuxer(_demuxer: &RsDemuxer) -> Box<DemuxerImpl> {
+        Box::new(TestDemuxer { results: Vec::new() })
+    }
+
+    #[test]
+    fn test_pending_events() {
+        use gst::EventView;
+
+        gst::init().unwrap();
+
+        let demuxer_static = DemuxerStatic {
+            name: String::from("Demuxer-test"),
+            demuxer_info: DemuxerInfo {
+                name: String::from("testdemux"),
+                long_name: String::from("Test demuxer"),
+                description: String::from("Test demuxer"),
+                classification: String::from("Codec/Demuxer"),
+                author: String::from("Test"),
+                rank: 0,
+                create_instance: create_test_demuxer,
+                input_caps: gst::Caps::new_any(),
+                output_caps: gst::Caps::new_any(),
+                properties: &[],
+            },
+        };
+        let type_ = register_type(demuxer_static);
+        let demux = glib::Object::new(type_, &[])
+            .unwrap()
+            .downcast::<gst::Element>()
+            .unwrap();
+
+        // Names of the custom events and buffer data in the order they arrive
+        let (sender, receiver) = mpsc::channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let sender_clone = sender.clone();
+        let sinkpad = gst::Pad::new("sink", gst::PadDirection::Sink);
+        sinkpad.set_chain_function(move |_, _, buffer| {
+            let map = buffer.map_readable().unwrap();
+            sender.lock().unwrap().send(map.as_slice().to_vec()).unwrap();
+            gst::FlowReturn::Ok
+        });
+        sinkpad.set_event_function(move |_, _, event| {
+            if let EventView::CustomDownstream(..) = event.view() {
+                let name = event.get_structure().unwrap().get_name().to_string();
+                sender_clone.lock().unwrap().send(name.into_bytes()).unwrap();
+            }
+            true
+        });
+        sinkpad.set_active(true).unwrap();
+        demux.connect_pad_added(move |_, pad| {
+            assert_eq!(pad.link(&sinkpad), gst::PadLinkReturn::Ok);
+        });
+
+        let srcpad = gst::Pad::new("src", gst::PadDirection::Src);
+        srcpad.set_query_function(|_, _, query| {
+            use gst::QueryView;
+
+            match query.view_mut() {
+                QueryView::Scheduling(ref mut q) => {
+                    q.set(gst::SchedulingFlags::empty(), 1, -1, 0);
+                    q.add_scheduling_modes(&[gst::PadMode::Push]);
+                    true
+                }
+                _ => false,
+            }
+        });
+        assert_eq!(
+            srcpad.link(&demux.get_static_pad("sink").unwrap()),
+            gst::PadLinkReturn::Ok
+        );
+        srcpad.set_active(true).unwrap();
+
+        assert_ne!(
+            demux.set_state(gst::State::Playing),
+            gst::StateChangeReturn::Failure
+        );
+
+        let mut segment = gst::Segment::default();
+        segment.init(gst::Format::Bytes);
+        srcpad.push_event(gst::Event::new_stream_start("test").build());
+        srcpad.push_event(gst::Event::new_segment(&segment).build());
+
+        let buffer = gst::Buffer::from_vec(b"abcd".to_vec()).unwrap();
+        assert_eq!(srcpad.push(buffer), gst::FlowReturn::Ok);
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), b"cue-point".to_vec());
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), b"abcd".to_vec());
+
+        demux.set_state(gst::State::Null);
+    }
+}
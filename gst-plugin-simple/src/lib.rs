@@ -18,5 +18,6 @@ extern crate url;
 pub mod source;
 pub mod sink;
 pub mod demuxer;
+pub mod muxer;
 
 pub type UriValidator = Fn(&url::Url) -> Result<(), gst_plugin::error::UriError> + Send + Sync + 'static;
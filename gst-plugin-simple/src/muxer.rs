@@ -0,0 +1,922 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use std::collections::BTreeMap;
+
+use gst_plugin::error::*;
+use gst_plugin::object::*;
+use gst_plugin::element::*;
+
+use gst;
+use gst::prelude::*;
+
+pub type PadIndex = u32;
+
+pub type RsMuxer = RsElement;
+
+pub trait MuxerImpl: Send + 'static {
+    fn start(&mut self, muxer: &RsMuxer) -> Result<(), ErrorMessage>;
+    fn stop(&mut self, muxer: &RsMuxer) -> Result<(), ErrorMessage>;
+
+    // Called for every caps event on a sink pad, once all previous buffers of
+    // that pad were handled and before any buffer with these caps
+    fn set_caps(
+        &mut self,
+        muxer: &RsMuxer,
+        index: PadIndex,
+        caps: &gst::Caps,
+    ) -> Result<(), ErrorMessage>;
+
+    // Called once all sink pads have caps and a first buffer, or are EOS
+    fn write_header(
+        &mut self,
+        muxer: &RsMuxer,
+        seekable: bool,
+    ) -> Result<Option<gst::Buffer>, FlowError>;
+    // Called with the buffers of all sink pads in timestamp order
+    fn handle_buffer(
+        &mut self,
+        muxer: &RsMuxer,
+        index: PadIndex,
+        buffer: gst::Buffer,
+    ) -> Result<Option<gst::Buffer>, FlowError>;
+    fn write_trailer(&mut self, muxer: &RsMuxer) -> Result<Option<gst::Buffer>, FlowError>;
+
+    // Called after the trailer if downstream is seekable, the returned buffer
+    // is written at offset 0
+    fn rewrite_header(&mut self, _muxer: &RsMuxer) -> Result<Option<gst::Buffer>, FlowError> {
+        Ok(None)
+    }
+}
+
+pub struct MuxerInfo {
+    pub name: String,
+    pub long_name: String,
+    pub description: String,
+    pub classification: String,
+    pub author: String,
+    pub rank: u32,
+    pub create_instance: fn(&RsMuxer) -> Box<MuxerImpl>,
+    // Name templates and caps of the request sink pads
+    pub sink_pad_templates: Vec<(String, gst::Caps)>,
+    pub output_caps: gst::Caps,
+}
+
+struct SinkPad {
+    pad: gst::Pad,
+    segment: gst::Segment,
+    // Queued buffer together with its running time
+    buffer: Option<(gst::Buffer, u64)>,
+    eos: bool,
+}
+
+struct State {
+    sinkpads: BTreeMap<PadIndex, SinkPad>,
+    next_index: PadIndex,
+    flushing: bool,
+    flow_ret: gst::FlowReturn,
+    started: bool,
+    finished: bool,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            sinkpads: BTreeMap::new(),
+            next_index: 0,
+            flushing: true,
+            flow_ret: gst::FlowReturn::Ok,
+            started: false,
+            finished: false,
+        }
+    }
+
+    fn find_sinkpad(&self, pad: &gst::Pad) -> Option<PadIndex> {
+        self.sinkpads
+            .iter()
+            .find(|&(_, sinkpad)| &sinkpad.pad == pad)
+            .map(|(&index, _)| index)
+    }
+
+    fn reset(&mut self) {
+        for sinkpad in self.sinkpads.values_mut() {
+            sinkpad.segment = gst::Segment::default();
+            sinkpad.buffer = None;
+            sinkpad.eos = false;
+        }
+        self.flow_ret = gst::FlowReturn::Ok;
+        self.started = false;
+        self.finished = false;
+    }
+}
+
+pub struct Muxer {
+    cat: gst::DebugCategory,
+    srcpad: gst::Pad,
+    sink_pad_templates: Vec<String>,
+    output_caps: gst::Caps,
+    state: Mutex<State>,
+    // Signalled whenever a queued buffer was taken from a sink pad
+    cond: Condvar,
+    imp: Mutex<Box<MuxerImpl>>,
+}
+
+// Buffers without timestamp or outside the segment are handled as early as possible
+fn buffer_running_time(segment: &gst::Segment, buffer: &gst::Buffer) -> u64 {
+    let timestamp = if buffer.get_dts() != gst::CLOCK_TIME_NONE {
+        buffer.get_dts()
+    } else {
+        buffer.get_pts()
+    };
+
+    if timestamp == gst::CLOCK_TIME_NONE || segment.get_format() != gst::Format::Time {
+        return 0;
+    }
+
+    let running_time = segment.to_running_time(gst::Format::Time, timestamp);
+    if running_time == gst::CLOCK_TIME_NONE {
+        0
+    } else {
+        running_time
+    }
+}
+
+impl Muxer {
+    fn new(muxer: &RsMuxer, srcpad: gst::Pad, muxer_info: &MuxerInfo) -> Self {
+        Self {
+            cat: gst::DebugCategory::new(
+                "rsmux",
+                gst::DebugColorFlags::empty(),
+                "Rust muxer base class",
+            ),
+            srcpad: srcpad,
+            sink_pad_templates: muxer_info
+                .sink_pad_templates
+                .iter()
+                .map(|&(ref name, _)| name.clone())
+                .collect(),
+            output_caps: muxer_info.output_caps.clone(),
+            state: Mutex::new(State::new()),
+            cond: Condvar::new(),
+            imp: Mutex::new((muxer_info.create_instance)(muxer)),
+        }
+    }
+
+    fn class_init(klass: &mut RsElementClass, muxer_info: &MuxerInfo) {
+        klass.set_metadata(
+            &muxer_info.long_name,
+            &muxer_info.classification,
+            &muxer_info.description,
+            &muxer_info.author,
+        );
+
+        for &(ref name, ref caps) in &muxer_info.sink_pad_templates {
+            let pad_template = gst::PadTemplate::new(
+                name,
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                caps,
+            );
+            klass.add_pad_template(pad_template);
+        }
+
+        let pad_template = gst::PadTemplate::new(
+            "src",
+            gst::PadDirection::Src,
+            gst::PadPresence::Always,
+            &muxer_info.output_caps,
+        );
+        klass.add_pad_template(pad_template);
+    }
+
+    fn init(element: &RsElement, muxer_info: &MuxerInfo) -> Box<ElementImpl<RsElement>> {
+        let templ = element.get_pad_template("src").unwrap();
+        let srcpad = gst::Pad::new_from_template(&templ, "src");
+        srcpad.set_event_function(Muxer::src_event);
+        element.add_pad(&srcpad).unwrap();
+
+        let imp = Self::new(element, srcpad, muxer_info);
+        Box::new(imp)
+    }
+
+    fn is_downstream_seekable(&self) -> bool {
+        use gst::QueryView;
+
+        let query = gst::Query::new_seeking(gst::Format::Bytes);
+        if !self.srcpad.peer_query(query.get_mut().unwrap()) {
+            return false;
+        }
+
+        match query.view() {
+            QueryView::Seeking(ref q) => q.get().1,
+            _ => unreachable!(),
+        }
+    }
+
+    fn handle_flow_error(&self, element: &RsMuxer, flow_error: FlowError) -> gst::FlowReturn {
+        gst_error!(self.cat, obj: element, "Failed muxing: {:?}", flow_error);
+        match flow_error {
+            FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => msg.post(element),
+            _ => (),
+        }
+        flow_error.to_native()
+    }
+
+    fn push_output(
+        &self,
+        element: &RsMuxer,
+        res: Result<Option<gst::Buffer>, FlowError>,
+    ) -> gst::FlowReturn {
+        match res {
+            Ok(Some(buffer)) => {
+                gst_trace!(self.cat, obj: element, "Pushing buffer {:?}", buffer);
+                self.srcpad.push(buffer)
+            }
+            Ok(None) => gst::FlowReturn::Ok,
+            Err(flow_error) => self.handle_flow_error(element, flow_error),
+        }
+    }
+
+    fn start_output(
+        &self,
+        element: &RsMuxer,
+        muxer_impl: &mut Box<MuxerImpl>,
+    ) -> gst::FlowReturn {
+        gst_debug!(self.cat, obj: element, "Starting output");
+
+        let stream_id = self.srcpad.create_stream_id(element, None).unwrap();
+        self.srcpad.push_event(
+            gst::Event::new_stream_start(&stream_id)
+                .group_id(gst::util_group_id_next())
+                .build(),
+        );
+        self.srcpad
+            .push_event(gst::Event::new_caps(&self.output_caps).build());
+
+        let mut segment = gst::Segment::default();
+        segment.init(gst::Format::Bytes);
+        self.srcpad
+            .push_event(gst::Event::new_segment(&segment).build());
+
+        let seekable = self.is_downstream_seekable();
+        let res = muxer_impl.write_header(element, seekable);
+        self.push_output(element, res)
+    }
+
+    fn finish_output(
+        &self,
+        element: &RsMuxer,
+        muxer_impl: &mut Box<MuxerImpl>,
+    ) -> gst::FlowReturn {
+        gst_debug!(self.cat, obj: element, "All pads are EOS, finishing");
+
+        let res = muxer_impl.write_trailer(element);
+        let flow_ret = self.push_output(element, res);
+        if flow_ret != gst::FlowReturn::Ok {
+            return flow_ret;
+        }
+
+        if self.is_downstream_seekable() {
+            match muxer_impl.rewrite_header(element) {
+                Ok(Some(buffer)) => {
+                    gst_debug!(self.cat, obj: element, "Rewriting header");
+
+                    let mut segment = gst::Segment::default();
+                    segment.init(gst::Format::Bytes);
+                    self.srcpad
+                        .push_event(gst::Event::new_segment(&segment).build());
+
+                    let flow_ret = self.srcpad.push(buffer);
+                    if flow_ret != gst::FlowReturn::Ok {
+                        return flow_ret;
+                    }
+                }
+                Ok(None) => (),
+                Err(flow_error) => return self.handle_flow_error(element, flow_error),
+            }
+        }
+
+        self.srcpad.push_event(gst::Event::new_eos().build());
+
+        gst::FlowReturn::Eos
+    }
+
+    // Muxes queued buffers as long as every sink pad has one or is EOS
+    fn collect(&self, element: &RsMuxer) -> gst::FlowReturn {
+        // Only one thread at a time takes buffers so that they stay in order
+        let muxer_impl = &mut self.imp.lock().unwrap();
+
+        loop {
+            let (start, next) = {
+                let mut state = self.state.lock().unwrap();
+
+                if state.flushing {
+                    return gst::FlowReturn::Flushing;
+                } else if state.flow_ret != gst::FlowReturn::Ok {
+                    return state.flow_ret;
+                } else if state.finished {
+                    return gst::FlowReturn::Eos;
+                }
+
+                if state.sinkpads.is_empty() ||
+                    state
+                        .sinkpads
+                        .values()
+                        .any(|sinkpad| sinkpad.buffer.is_none() && !sinkpad.eos)
+                {
+                    return gst::FlowReturn::Ok;
+                }
+
+                let next = state
+                    .sinkpads
+                    .iter()
+                    .filter_map(|(&index, sinkpad)| {
+                        sinkpad
+                            .buffer
+                            .as_ref()
+                            .map(|&(_, running_time)| (index, running_time))
+                    })
+                    .min_by_key(|&(_, running_time)| running_time)
+                    .map(|(index, _)| index);
+
+                let next = next.map(|index| {
+                    let buffer = state
+                        .sinkpads
+                        .get_mut(&index)
+                        .unwrap()
+                        .buffer
+                        .take()
+                        .unwrap()
+                        .0;
+                    (index, buffer)
+                });
+                self.cond.notify_all();
+
+                let start = !state.started;
+                state.started = true;
+                if next.is_none() {
+                    state.finished = true;
+                }
+
+                (start, next)
+            };
+
+            let mut flow_ret = gst::FlowReturn::Ok;
+            if start {
+                flow_ret = self.start_output(element, muxer_impl);
+            }
+
+            if flow_ret == gst::FlowReturn::Ok {
+                flow_ret = match next {
+                    Some((index, buffer)) => {
+                        gst_trace!(
+                            self.cat,
+                            obj: element,
+                            "Handling buffer {:?} from pad {}",
+                            buffer,
+                            index
+                        );
+                        let res = muxer_impl.handle_buffer(element, index, buffer);
+                        self.push_output(element, res)
+                    }
+                    None => self.finish_output(element, muxer_impl),
+                };
+            }
+
+            if flow_ret != gst::FlowReturn::Ok {
+                gst_debug!(self.cat, obj: element, "Stopping collecting: {:?}", flow_ret);
+
+                let mut state = self.state.lock().unwrap();
+                if state.flow_ret == gst::FlowReturn::Ok && !state.flushing {
+                    state.flow_ret = flow_ret;
+                }
+                self.cond.notify_all();
+
+                return flow_ret;
+            }
+        }
+    }
+
+    // Waits until the previous buffer of the pad was taken for muxing
+    fn wait_queued_buffer<'a>(
+        &self,
+        mut state: MutexGuard<'a, State>,
+        index: PadIndex,
+    ) -> Result<MutexGuard<'a, State>, gst::FlowReturn> {
+        loop {
+            if state.flushing {
+                return Err(gst::FlowReturn::Flushing);
+            } else if state.flow_ret != gst::FlowReturn::Ok {
+                return Err(state.flow_ret);
+            }
+
+            match state.sinkpads.get(&index) {
+                None => return Err(gst::FlowReturn::NotLinked),
+                Some(sinkpad) if sinkpad.buffer.is_none() => return Ok(state),
+                Some(_) => (),
+            }
+
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn sink_chain(
+        pad: &gst::Pad,
+        parent: &Option<gst::Object>,
+        buffer: gst::Buffer,
+    ) -> gst::FlowReturn {
+        let element = parent
+            .as_ref()
+            .cloned()
+            .unwrap()
+            .downcast::<RsElement>()
+            .unwrap();
+        let muxer = element.get_impl().downcast_ref::<Muxer>().unwrap();
+
+        {
+            let state = muxer.state.lock().unwrap();
+            let index = match state.find_sinkpad(pad) {
+                Some(index) => index,
+                None => return gst::FlowReturn::NotLinked,
+            };
+
+            let mut state = match muxer.wait_queued_buffer(state, index) {
+                Ok(state) => state,
+                Err(flow_ret) => return flow_ret,
+            };
+
+            gst_trace!(
+                muxer.cat,
+                obj: &element,
+                "Queueing buffer {:?} on pad {}",
+                buffer,
+                index
+            );
+            let sinkpad = state.sinkpads.get_mut(&index).unwrap();
+            let running_time = buffer_running_time(&sinkpad.segment, &buffer);
+            sinkpad.buffer = Some((buffer, running_time));
+        }
+
+        muxer.collect(&element)
+    }
+
+    fn sink_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        let element = parent
+            .as_ref()
+            .cloned()
+            .unwrap()
+            .downcast::<RsElement>()
+            .unwrap();
+        let muxer = element.get_impl().downcast_ref::<Muxer>().unwrap();
+
+        let index = match muxer.state.lock().unwrap().find_sinkpad(pad) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        match event.view() {
+            EventView::Caps(ref e) => {
+                let caps = e.get_caps().to_owned();
+
+                // The previous buffer of this pad must be muxed with the old caps. Once it
+                // was taken, the collecting thread only releases the implementation after
+                // handling it
+                if let Err(flow_ret) =
+                    muxer.wait_queued_buffer(muxer.state.lock().unwrap(), index)
+                {
+                    gst_debug!(
+                        muxer.cat,
+                        obj: &element,
+                        "Not setting caps on pad {}: {:?}",
+                        index,
+                        flow_ret
+                    );
+                    return false;
+                }
+                let muxer_impl = &mut muxer.imp.lock().unwrap();
+
+                gst_debug!(
+                    muxer.cat,
+                    obj: &element,
+                    "Got caps {:?} on pad {}",
+                    caps,
+                    index
+                );
+                match muxer_impl.set_caps(&element, index, &caps) {
+                    Ok(..) => true,
+                    Err(ref msg) => {
+                        gst_error!(muxer.cat, obj: &element, "Failed to set caps: {:?}", msg);
+                        msg.post(&element);
+                        false
+                    }
+                }
+            }
+            EventView::Segment(ref e) => {
+                let segment = e.get_segment().clone();
+                gst_debug!(
+                    muxer.cat,
+                    obj: &element,
+                    "Got segment {:?} on pad {}",
+                    segment,
+                    index
+                );
+
+                // Only used for ordering the buffers of all pads by running time, we
+                // produce our own byte segment downstream
+                if let Some(sinkpad) = muxer.state.lock().unwrap().sinkpads.get_mut(&index) {
+                    sinkpad.segment = segment;
+                }
+                true
+            }
+            EventView::StreamStart(..) => {
+                // We produce our own stream downstream
+                true
+            }
+            EventView::Eos(..) => {
+                gst_debug!(muxer.cat, obj: &element, "Pad {} is EOS", index);
+                {
+                    let mut state = muxer.state.lock().unwrap();
+                    if let Some(sinkpad) = state.sinkpads.get_mut(&index) {
+                        sinkpad.eos = true;
+                    }
+                }
+                muxer.collect(&element);
+                true
+            }
+            EventView::FlushStart(..) => {
+                muxer.state.lock().unwrap().flushing = true;
+                muxer.cond.notify_all();
+                pad.event_default(parent.as_ref(), event)
+            }
+            EventView::FlushStop(..) => {
+                {
+                    let mut state = muxer.state.lock().unwrap();
+                    state.flushing = false;
+                    state.reset();
+                }
+                pad.event_default(parent.as_ref(), event)
+            }
+            _ => pad.event_default(parent.as_ref(), event),
+        }
+    }
+
+    fn src_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            EventView::Seek(..) => false,
+            _ => pad.event_default(parent.as_ref(), event),
+        }
+    }
+}
+
+impl ObjectImpl<RsElement> for Muxer {}
+
+impl ElementImpl<RsElement> for Muxer {
+    fn change_state(
+        &self,
+        element: &RsElement,
+        transition: gst::StateChange,
+    ) -> gst::StateChangeReturn {
+        gst_trace!(self.cat, obj: element, "Changing state {:?}", transition);
+
+        match transition {
+            gst::StateChange::ReadyToPaused => {
+                let muxer_impl = &mut self.imp.lock().unwrap();
+                if let Err(ref msg) = muxer_impl.start(element) {
+                    gst_error!(self.cat, obj: element, "Failed to start: {:?}", msg);
+                    msg.post(element);
+                    return gst::StateChangeReturn::Failure;
+                }
+
+                let mut state = self.state.lock().unwrap();
+                state.flushing = false;
+                state.reset();
+            }
+            gst::StateChange::PausedToReady => {
+                // Unblock all streaming threads waiting for their buffer to be muxed
+                self.state.lock().unwrap().flushing = true;
+                self.cond.notify_all();
+            }
+            _ => (),
+        }
+
+        let ret = element.parent_change_state(transition);
+        if ret == gst::StateChangeReturn::Failure {
+            return ret;
+        }
+
+        match transition {
+            gst::StateChange::PausedToReady => {
+                self.state.lock().unwrap().reset();
+
+                let muxer_impl = &mut self.imp.lock().unwrap();
+                if let Err(ref msg) = muxer_impl.stop(element) {
+                    gst_error!(self.cat, obj: element, "Failed to stop: {:?}", msg);
+                    msg.post(element);
+                    return gst::StateChangeReturn::Failure;
+                }
+            }
+            _ => (),
+        }
+
+        ret
+    }
+
+    fn request_new_pad(
+        &self,
+        element: &RsElement,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+        _caps: Option<&gst::CapsRef>,
+    ) -> Option<gst::Pad> {
+        let name_template = match self.sink_pad_templates
+            .iter()
+            .find(|name| element.get_pad_template(name).as_ref() == Some(templ))
+        {
+            Some(name_template) => name_template,
+            None => return None,
+        };
+
+        let (index, name) = {
+            let mut state = self.state.lock().unwrap();
+            let index = state.next_index;
+
+            let name = if name_template.contains("%u") {
+                name.unwrap_or_else(|| name_template.replace("%u", &index.to_string()))
+            } else {
+                name_template.clone()
+            };
+
+            if state.sinkpads.values().any(|sinkpad| sinkpad.pad.get_name() == name) {
+                gst_debug!(self.cat, obj: element, "Pad {} already exists", name);
+                return None;
+            }
+
+            state.next_index += 1;
+            (index, name)
+        };
+
+        let pad = gst::Pad::new_from_template(templ, Some(name.as_str()));
+        pad.set_chain_function(Muxer::sink_chain);
+        pad.set_event_function(Muxer::sink_event);
+
+        self.state.lock().unwrap().sinkpads.insert(
+            index,
+            SinkPad {
+                pad: pad.clone(),
+                segment: gst::Segment::default(),
+                buffer: None,
+                eos: false,
+            },
+        );
+
+        gst_debug!(self.cat, obj: element, "Adding pad {} with index {}", name, index);
+        element.add_pad(&pad).unwrap();
+
+        Some(pad)
+    }
+
+    fn release_pad(&self, element: &RsElement, pad: &gst::Pad) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let index = match state.find_sinkpad(pad) {
+                Some(index) => index,
+                None => return,
+            };
+
+            gst_debug!(self.cat, obj: element, "Removing pad {}", pad.get_name());
+            state.sinkpads.remove(&index);
+            self.cond.notify_all();
+        }
+
+        let _ = pad.set_active(false);
+        element.remove_pad(pad).unwrap();
+
+        // The remaining pads might have everything needed now
+        self.collect(element);
+    }
+}
+
+struct MuxerStatic {
+    name: String,
+    muxer_info: MuxerInfo,
+}
+
+impl ImplTypeStatic<RsElement> for MuxerStatic {
+    fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn new(&self, element: &RsElement) -> Box<ElementImpl<RsElement>> {
+        Muxer::init(element, &self.muxer_info)
+    }
+
+    fn class_init(&self, klass: &mut RsElementClass) {
+        Muxer::class_init(klass, &self.muxer_info);
+    }
+}
+
+pub fn muxer_register(plugin: &gst::Plugin, muxer_info: MuxerInfo) {
+    let name = muxer_info.name.clone();
+    let rank = muxer_info.rank;
+
+    let muxer_static = MuxerStatic {
+        name: format!("Muxer-{}", name),
+        muxer_info: muxer_info,
+    };
+
+    let type_ = register_type(muxer_static);
+    gst::Element::register(plugin, &name, rank, type_);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use glib;
+
+    // Outputs the pad index, caps name and data of every buffer
+    struct TestMuxer {
+        caps: BTreeMap<PadIndex, String>,
+    }
+
+    impl MuxerImpl for TestMuxer {
+        fn start(&mut self, _muxer: &RsMuxer) -> Result<(), ErrorMessage> {
+            Ok(())
+        }
+
+        fn stop(&mut self, _muxer: &RsMuxer) -> Result<(), ErrorMessage> {
+            self.caps.clear();
+            Ok(())
+        }
+
+        fn set_caps(
+            &mut self,
+            _muxer: &RsMuxer,
+            index: PadIndex,
+            caps: &gst::Caps,
+        ) -> Result<(), ErrorMessage> {
+            let name = caps.get_structure(0).unwrap().get_name().to_string();
+            self.caps.insert(index, name);
+            Ok(())
+        }
+
+        fn write_header(
+            &mut self,
+            _muxer: &RsMuxer,
+            _seekable: bool,
+        ) -> Result<Option<gst::Buffer>, FlowError> {
+            Ok(Some(gst::Buffer::from_vec(b"header".to_vec()).unwrap()))
+        }
+
+        fn handle_buffer(
+            &mut self,
+            _muxer: &RsMuxer,
+            index: PadIndex,
+            buffer: gst::Buffer,
+        ) -> Result<Option<gst::Buffer>, FlowError> {
+            let data = {
+                let map = buffer.map_readable().unwrap();
+                String::from_utf8(map.as_slice().to_vec()).unwrap()
+            };
+            let caps = self.caps.get(&index).cloned().unwrap_or_default();
+            let output = format!("{}:{}:{}", index, caps, data);
+            Ok(Some(gst::Buffer::from_vec(output.into_bytes()).unwrap()))
+        }
+
+        fn write_trailer(&mut self, _muxer: &RsMuxer) -> Result<Option<gst::Buffer>, FlowError> {
+            Ok(Some(gst::Buffer::from_vec(b"trailer".to_vec()).unwrap()))
+        }
+    }
+
+    fn create_test_muxer(_muxer: &RsMuxer) -> Box<MuxerImpl> {
+        Box::new(TestMuxer {
+            caps: BTreeMap::new(),
+        })
+    }
+
+    fn new_buffer(data: &str, pts: u64) -> gst::Buffer {
+        let mut buffer = gst::Buffer::from_vec(data.as_bytes().to_vec()).unwrap();
+        buffer.get_mut().unwrap().set_pts(pts);
+        buffer
+    }
+
+    #[test]
+    fn test_mux() {
+        gst::init().unwrap();
+
+        let muxer_static = MuxerStatic {
+            name: String::from("Muxer-test"),
+            muxer_info: MuxerInfo {
+                name: String::from("testmux"),
+                long_name: String::from("Test muxer"),
+                description: String::from("Test muxer"),
+                classification: String::from("Codec/Muxer"),
+                author: String::from("Test"),
+                rank: 0,
+                create_instance: create_test_muxer,
+                sink_pad_templates: vec![(String::from("sink_%u"), gst::Caps::new_any())],
+                output_caps: gst::Caps::new_any(),
+            },
+        };
+        let type_ = register_type(muxer_static);
+        let mux = glib::Object::new(type_, &[])
+            .unwrap()
+            .downcast::<gst::Element>()
+            .unwrap();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let output_clone = output.clone();
+        let sinkpad = gst::Pad::new("sink", gst::PadDirection::Sink);
+        sinkpad.set_chain_function(move |_, _, buffer| {
+            let map = buffer.map_readable().unwrap();
+            let data = String::from_utf8(map.as_slice().to_vec()).unwrap();
+            output_clone.lock().unwrap().push(data);
+            gst::FlowReturn::Ok
+        });
+        sinkpad.set_active(true).unwrap();
+        assert_eq!(
+            mux.get_static_pad("src").unwrap().link(&sinkpad),
+            gst::PadLinkReturn::Ok
+        );
+
+        let mux_sinkpads = (0..3)
+            .map(|_| mux.get_request_pad("sink_%u").unwrap())
+            .collect::<Vec<_>>();
+        let srcpads = mux_sinkpads
+            .iter()
+            .map(|mux_sinkpad| {
+                let srcpad = gst::Pad::new(None, gst::PadDirection::Src);
+                assert_eq!(srcpad.link(mux_sinkpad), gst::PadLinkReturn::Ok);
+                srcpad.set_active(true).unwrap();
+                srcpad
+            })
+            .collect::<Vec<_>>();
+
+        assert_ne!(
+            mux.set_state(gst::State::Playing),
+            gst::StateChangeReturn::Failure
+        );
+
+        // The second pad's timestamps are 1s later but map to running times
+        // between the ones of the first pad
+        for (index, srcpad) in srcpads.iter().enumerate() {
+            let mut segment = gst::Segment::default();
+            segment.init(gst::Format::Time);
+            if index == 1 {
+                segment.set_start(1_000_000_000);
+            }
+            srcpad.push_event(gst::Event::new_stream_start(&index.to_string()).build());
+            let caps = gst::Caps::new_simple(&format!("test/x-pad{}", index), &[]);
+            srcpad.push_event(gst::Event::new_caps(&caps).build());
+            srcpad.push_event(gst::Event::new_segment(&segment).build());
+        }
+
+        // Nothing is muxed until the third pad has data or is gone, and no
+        // pad gets a buffer while its previous one is still queued
+        assert_eq!(srcpads[0].push(new_buffer("a0", 0)), gst::FlowReturn::Ok);
+        assert_eq!(
+            srcpads[1].push(new_buffer("b0", 1_010_000_000)),
+            gst::FlowReturn::Ok
+        );
+        assert!(output.lock().unwrap().is_empty());
+
+        mux.release_request_pad(&mux_sinkpads[2]);
+        assert_eq!(
+            srcpads[0].push(new_buffer("a1", 20_000_000)),
+            gst::FlowReturn::Ok
+        );
+        assert_eq!(
+            srcpads[1].push(new_buffer("b1", 1_030_000_000)),
+            gst::FlowReturn::Ok
+        );
+        srcpads[0].push_event(gst::Event::new_eos().build());
+        srcpads[1].push_event(gst::Event::new_eos().build());
+
+        assert_eq!(
+            *output.lock().unwrap(),
+            vec![
+                "header",
+                "0:test/x-pad0:a0",
+                "1:test/x-pad1:b0",
+                "0:test/x-pad0:a1",
+                "1:test/x-pad1:b1",
+                "trailer",
+            ]
+        );
+
+        mux.set_state(gst::State::Null);
+        mux.release_request_pad(&mux_sinkpads[0]);
+        mux.release_request_pad(&mux_sinkpads[1]);
+    }
+}
@@ -7,6 +7,8 @@
 // except according to those terms.
 
 use std::sync::Mutex;
+use std::cmp;
+use std::i64;
 
 use url::Url;
 
@@ -25,19 +27,79 @@ use gst_plugin::error::*;
 pub use gst_plugin::base_sink::RsBaseSink;
 
 use UriValidator;
+use source::UnlockHandler;
 
 pub trait SinkImpl: Send + 'static {
     fn uri_validator(&self) -> Box<UriValidator>;
 
+    // Interrupts a blocking render(), e.g. before flushing. Sinks without one
+    // delay flushes and out-of-band queries until rendering returns
+    fn unlock_handler(&self) -> Option<Box<UnlockHandler>> {
+        None
+    }
+
     fn start(&mut self, sink: &RsBaseSink, uri: Url) -> Result<(), ErrorMessage>;
     fn stop(&mut self, sink: &RsBaseSink) -> Result<(), ErrorMessage>;
     fn render(&mut self, sink: &RsBaseSink, buffer: &gst::BufferRef) -> Result<(), FlowError>;
+
+    // Called before waiting for the clock, render() is called when the
+    // buffer is due
+    fn prepare(&mut self, _sink: &RsBaseSink, _buffer: &gst::BufferRef) -> Result<(), FlowError> {
+        Ok(())
+    }
+
+    fn render_list(
+        &mut self,
+        sink: &RsBaseSink,
+        list: &gst::BufferListRef,
+    ) -> Result<(), FlowError> {
+        for buffer in list.iter() {
+            try!(self.render(sink, buffer));
+        }
+
+        Ok(())
+    }
+
+    fn prepare_list(
+        &mut self,
+        sink: &RsBaseSink,
+        list: &gst::BufferListRef,
+    ) -> Result<(), FlowError> {
+        for buffer in list.iter() {
+            try!(self.prepare(sink, buffer));
+        }
+
+        Ok(())
+    }
+
+    // Called for every event before the base class handles it. Flush-start
+    // is passed on after unlocking a running render()
+    fn event(&mut self, _sink: &RsBaseSink, _event: &gst::Event) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    // Returns true if the query was answered, otherwise the base class
+    // handles it
+    fn query(&mut self, _sink: &RsBaseSink, _query: &mut gst::QueryRef) -> bool {
+        false
+    }
+
+    // Only called for the properties from SinkInfo
+    fn set_property(&mut self, _sink: &RsBaseSink, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _sink: &RsBaseSink, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 struct Sink {
     cat: gst::DebugCategory,
     uri: Mutex<(Option<Url>, bool)>,
     uri_validator: Box<UriValidator>,
+    unlock_handler: Option<Box<UnlockHandler>>,
+    properties: &'static [Property<'static>],
     imp: Mutex<Box<SinkImpl>>,
 }
 
@@ -63,6 +125,8 @@ impl Sink {
             ),
             uri: Mutex::new((None, false)),
             uri_validator: sink_impl.uri_validator(),
+            unlock_handler: sink_impl.unlock_handler(),
+            properties: sink_info.properties,
             imp: Mutex::new(sink_impl),
         }
     }
@@ -84,11 +148,21 @@ impl Sink {
         );
         klass.add_pad_template(pad_template);
 
-        klass.install_properties(&PROPERTIES);
+        let mut properties = PROPERTIES.to_vec();
+        properties.extend_from_slice(sink_info.properties);
+        klass.install_properties(&properties);
     }
 
     fn init(element: &RsBaseSink, sink_info: &SinkInfo) -> Box<BaseSinkImpl<RsBaseSink>> {
         element.set_blocksize(4096);
+        element.set_sync(sink_info.sync);
+        element.set_async_enabled(sink_info.async_enabled);
+        element.set_max_lateness(
+            sink_info
+                .max_lateness
+                .map(|l| cmp::min(l, i64::MAX as u64) as i64)
+                .unwrap_or(-1),
+        );
 
         let imp = Self::new(element, sink_info);
         Box::new(imp)
@@ -132,11 +206,36 @@ impl Sink {
             Ok(())
         }
     }
+
+    // READY-only properties from the info can't be changed after starting
+    fn set_impl_property(&self, element: &glib::Object, property: &Property, value: &glib::Value) {
+        let sink = element.clone().dynamic_cast::<RsBaseSink>().unwrap();
+
+        if property.get_mutability() == PropertyMutability::ReadWriteReady &&
+            self.uri.lock().unwrap().1
+        {
+            gst_warning!(
+                self.cat,
+                obj: &sink,
+                "Can't change property '{}' while started",
+                property.get_name()
+            );
+            return;
+        }
+
+        let sink_impl = &mut self.imp.lock().unwrap();
+        sink_impl.set_property(&sink, property, value);
+    }
 }
 
 impl ObjectImpl<RsBaseSink> for Sink {
     fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+        if id >= PROPERTIES.len() {
+            return self.set_impl_property(obj, &self.properties[id - PROPERTIES.len()], value);
+        }
+
+        let prop = &PROPERTIES[id];
 
         match *prop {
             Property::String("uri", ..) => {
@@ -147,7 +246,14 @@ impl ObjectImpl<RsBaseSink> for Sink {
     }
 
     fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+        if id >= PROPERTIES.len() {
+            let sink = obj.clone().dynamic_cast::<RsBaseSink>().unwrap();
+            let sink_impl = &self.imp.lock().unwrap();
+            return sink_impl.get_property(&sink, &self.properties[id - PROPERTIES.len()]);
+        }
+
+        let prop = &PROPERTIES[id];
 
         match *prop {
             Property::String("uri", ..) => Ok(self.get_uri(obj).to_value()),
@@ -211,6 +317,26 @@ impl BaseSinkImpl<RsBaseSink> for Sink {
         }
     }
 
+    fn unlock(&self, sink: &RsBaseSink) -> bool {
+        gst_debug!(self.cat, obj: sink, "Unlocking");
+
+        if let Some(ref unlock_handler) = self.unlock_handler {
+            unlock_handler.unlock();
+        }
+
+        true
+    }
+
+    fn unlock_stop(&self, sink: &RsBaseSink) -> bool {
+        gst_debug!(self.cat, obj: sink, "Stopping unlocking");
+
+        if let Some(ref unlock_handler) = self.unlock_handler {
+            unlock_handler.unlock_stop();
+        }
+
+        true
+    }
+
     fn render(&self, sink: &RsBaseSink, buffer: &gst::BufferRef) -> gst::FlowReturn {
         let sink_impl = &mut self.imp.lock().unwrap();
 
@@ -230,6 +356,121 @@ impl BaseSinkImpl<RsBaseSink> for Sink {
             }
         }
     }
+
+    fn prepare(&self, sink: &RsBaseSink, buffer: &gst::BufferRef) -> gst::FlowReturn {
+        let sink_impl = &mut self.imp.lock().unwrap();
+
+        gst_trace!(self.cat, obj: sink, "Preparing buffer {:?}", buffer);
+
+        match sink_impl.prepare(sink, buffer) {
+            Ok(()) => gst::FlowReturn::Ok,
+            Err(flow_error) => {
+                gst_error!(self.cat, obj: sink, "Failed to prepare: {:?}", flow_error);
+                match flow_error {
+                    FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
+                        msg.post(sink);
+                    }
+                    _ => (),
+                }
+                flow_error.to_native()
+            }
+        }
+    }
+
+    fn render_list(&self, sink: &RsBaseSink, list: &gst::BufferListRef) -> gst::FlowReturn {
+        let sink_impl = &mut self.imp.lock().unwrap();
+
+        gst_trace!(self.cat, obj: sink, "Rendering buffer list {:?}", list);
+
+        match sink_impl.render_list(sink, list) {
+            Ok(()) => gst::FlowReturn::Ok,
+            Err(flow_error) => {
+                gst_error!(
+                    self.cat,
+                    obj: sink,
+                    "Failed to render list: {:?}",
+                    flow_error
+                );
+                match flow_error {
+                    FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
+                        msg.post(sink);
+                    }
+                    _ => (),
+                }
+                flow_error.to_native()
+            }
+        }
+    }
+
+    fn prepare_list(&self, sink: &RsBaseSink, list: &gst::BufferListRef) -> gst::FlowReturn {
+        let sink_impl = &mut self.imp.lock().unwrap();
+
+        gst_trace!(self.cat, obj: sink, "Preparing buffer list {:?}", list);
+
+        match sink_impl.prepare_list(sink, list) {
+            Ok(()) => gst::FlowReturn::Ok,
+            Err(flow_error) => {
+                gst_error!(
+                    self.cat,
+                    obj: sink,
+                    "Failed to prepare list: {:?}",
+                    flow_error
+                );
+                match flow_error {
+                    FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
+                        msg.post(sink);
+                    }
+                    _ => (),
+                }
+                flow_error.to_native()
+            }
+        }
+    }
+
+    fn event(&self, sink: &RsBaseSink, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        // Flushing must not wait for a blocking render to finish, the base
+        // class unlocks again and stops unlocking once it flushes
+        if let EventView::FlushStart(..) = event.view() {
+            self.unlock(sink);
+        }
+
+        {
+            let sink_impl = &mut self.imp.lock().unwrap();
+
+            gst_trace!(self.cat, obj: sink, "Handling event {:?}", event);
+
+            if let Err(ref msg) = sink_impl.event(sink, &event) {
+                gst_error!(self.cat, obj: sink, "Failed to handle event: {:?}", msg);
+                msg.post(sink);
+                return false;
+            }
+        }
+
+        BaseSink::parent_event(sink, event)
+    }
+
+    fn query(&self, sink: &RsBaseSink, query: &mut gst::QueryRef) -> bool {
+        use gst::QueryView;
+
+        {
+            let sink_impl = &mut self.imp.lock().unwrap();
+            if sink_impl.query(sink, query) {
+                return true;
+            }
+        }
+
+        match query.view_mut() {
+            QueryView::Uri(ref mut q) => if let Some(ref uri) = self.uri.lock().unwrap().0 {
+                q.set_uri(uri.as_str());
+                return true;
+            },
+            _ => (),
+        }
+
+        BaseSink::parent_query(sink, query)
+    }
 }
 
 impl URIHandlerImpl for Sink {
@@ -251,6 +492,12 @@ pub struct SinkInfo {
     pub rank: u32,
     pub create_instance: fn(&RsBaseSink) -> Box<SinkImpl>,
     pub protocols: Vec<String>,
+    pub properties: &'static [Property<'static>],
+    // Synchronize rendering to the clock and wait for preroll on state
+    // changes, maximum lateness before dropping buffers (None for unlimited)
+    pub sync: bool,
+    pub async_enabled: bool,
+    pub max_lateness: Option<u64>,
 }
 
 struct SinkStatic {
@@ -8,7 +8,9 @@
 
 use std::u64;
 
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use url::Url;
 
@@ -28,9 +30,46 @@ pub use gst_plugin::base_src::RsBaseSrc;
 
 use UriValidator;
 
+// Called from other threads while fill() might be blocking, so it can't be
+// part of the SourceImpl itself
+pub trait UnlockHandler: Send + Sync + 'static {
+    fn unlock(&self);
+    fn unlock_stop(&self);
+}
+
+// Flag for implementations that can regularly check if they should stop
+// blocking, e.g. between reads with a timeout
+#[derive(Debug, Clone, Default)]
+pub struct FlushingFlag(Arc<AtomicBool>);
+
+impl FlushingFlag {
+    pub fn new() -> FlushingFlag {
+        FlushingFlag::default()
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl UnlockHandler for FlushingFlag {
+    fn unlock(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn unlock_stop(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 pub trait SourceImpl: Send + 'static {
     fn uri_validator(&self) -> Box<UriValidator>;
 
+    // Sources without one can't be interrupted while filling a buffer
+    fn unlock_handler(&self) -> Option<Box<UnlockHandler>> {
+        None
+    }
+
     fn is_seekable(&self, src: &RsBaseSrc) -> bool;
     fn get_size(&self, src: &RsBaseSrc) -> Option<u64>;
 
@@ -44,14 +83,44 @@ pub trait SourceImpl: Send + 'static {
         buffer: &mut gst::BufferRef,
     ) -> Result<(), FlowError>;
     fn seek(&mut self, src: &RsBaseSrc, start: u64, stop: Option<u64>) -> Result<(), ErrorMessage>;
+
+    // By default the caps of the pad template are used as is
+    fn get_caps(&self, src: &RsBaseSrc, filter: Option<&gst::CapsRef>) -> Option<gst::Caps> {
+        src.parent_get_caps(filter)
+    }
+
+    fn set_caps(&mut self, _src: &RsBaseSrc, _caps: &gst::CapsRef) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    fn fixate(&self, src: &RsBaseSrc, caps: gst::Caps) -> gst::Caps {
+        src.parent_fixate(caps)
+    }
+
+    // Minimum and maximum (None if unlimited) latency of live sources
+    fn get_latency(&self, _src: &RsBaseSrc) -> Option<(u64, Option<u64>)> {
+        None
+    }
+
+    // Only called for the properties from SourceInfo
+    fn set_property(&mut self, _src: &RsBaseSrc, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _src: &RsBaseSrc, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 struct Source {
     cat: gst::DebugCategory,
     uri: Mutex<(Option<Url>, bool)>,
     uri_validator: Box<UriValidator>,
+    unlock_handler: Option<Box<UnlockHandler>>,
+    properties: &'static [Property<'static>],
     imp: Mutex<Box<SourceImpl>>,
     push_only: bool,
+    is_live: bool,
 }
 
 static PROPERTIES: [Property; 1] = [
@@ -76,8 +145,11 @@ impl Source {
             ),
             uri: Mutex::new((None, false)),
             uri_validator: source_impl.uri_validator(),
+            unlock_handler: source_impl.unlock_handler(),
+            properties: source_info.properties,
             imp: Mutex::new(source_impl),
             push_only: source_info.push_only,
+            is_live: source_info.is_live,
         }
     }
 
@@ -89,20 +161,23 @@ impl Source {
             &source_info.author,
         );
 
-        let caps = gst::Caps::new_any();
         let pad_template = gst::PadTemplate::new(
             "src",
             gst::PadDirection::Src,
             gst::PadPresence::Always,
-            &caps,
+            &source_info.output_caps,
         );
         klass.add_pad_template(pad_template);
 
-        klass.install_properties(&PROPERTIES);
+        let mut properties = PROPERTIES.to_vec();
+        properties.extend_from_slice(source_info.properties);
+        klass.install_properties(&properties);
     }
 
     fn init(element: &RsBaseSrc, source_info: &SourceInfo) -> Box<BaseSrcImpl<RsBaseSrc>> {
         element.set_blocksize(4096);
+        element.set_live(source_info.is_live);
+        element.set_format(source_info.format);
 
         let imp = Self::new(element, source_info);
         Box::new(imp)
@@ -146,11 +221,36 @@ impl Source {
             Ok(())
         }
     }
+
+    // READY-only properties from the info can't be changed after starting
+    fn set_impl_property(&self, element: &glib::Object, property: &Property, value: &glib::Value) {
+        let src = element.clone().dynamic_cast::<RsBaseSrc>().unwrap();
+
+        if property.get_mutability() == PropertyMutability::ReadWriteReady &&
+            self.uri.lock().unwrap().1
+        {
+            gst_warning!(
+                self.cat,
+                obj: &src,
+                "Can't change property '{}' while started",
+                property.get_name()
+            );
+            return;
+        }
+
+        let source_impl = &mut self.imp.lock().unwrap();
+        source_impl.set_property(&src, property, value);
+    }
 }
 
 impl ObjectImpl<RsBaseSrc> for Source {
     fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+        if id >= PROPERTIES.len() {
+            return self.set_impl_property(obj, &self.properties[id - PROPERTIES.len()], value);
+        }
+
+        let prop = &PROPERTIES[id];
 
         match *prop {
             Property::String("uri", ..) => {
@@ -161,7 +261,14 @@ impl ObjectImpl<RsBaseSrc> for Source {
     }
 
     fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+        if id >= PROPERTIES.len() {
+            let src = obj.clone().dynamic_cast::<RsBaseSrc>().unwrap();
+            let source_impl = &self.imp.lock().unwrap();
+            return source_impl.get_property(&src, &self.properties[id - PROPERTIES.len()]);
+        }
+
+        let prop = &PROPERTIES[id];
 
         match *prop {
             Property::String("uri", ..) => Ok(self.get_uri(obj).to_value()),
@@ -234,6 +341,20 @@ impl BaseSrcImpl<RsBaseSrc> for Source {
                 q.add_scheduling_modes(&[gst::PadMode::Push]);
                 return true;
             }
+            QueryView::Latency(ref mut q) if self.is_live => {
+                let source_impl = &self.imp.lock().unwrap();
+                if let Some((min, max)) = source_impl.get_latency(src) {
+                    gst_debug!(
+                        self.cat,
+                        obj: src,
+                        "Returning latency {}-{:?}",
+                        min,
+                        max
+                    );
+                    q.set(true, min, max.unwrap_or(gst::CLOCK_TIME_NONE));
+                    return true;
+                }
+            }
             _ => (),
         }
 
@@ -303,6 +424,51 @@ impl BaseSrcImpl<RsBaseSrc> for Source {
         let source_impl = &self.imp.lock().unwrap();
         source_impl.get_size(src)
     }
+
+    fn unlock(&self, src: &RsBaseSrc) -> bool {
+        gst_debug!(self.cat, obj: src, "Unlocking");
+
+        if let Some(ref unlock_handler) = self.unlock_handler {
+            unlock_handler.unlock();
+        }
+
+        true
+    }
+
+    fn unlock_stop(&self, src: &RsBaseSrc) -> bool {
+        gst_debug!(self.cat, obj: src, "Stopping unlocking");
+
+        if let Some(ref unlock_handler) = self.unlock_handler {
+            unlock_handler.unlock_stop();
+        }
+
+        true
+    }
+
+    fn get_caps(&self, src: &RsBaseSrc, filter: Option<&gst::CapsRef>) -> Option<gst::Caps> {
+        let source_impl = &self.imp.lock().unwrap();
+        source_impl.get_caps(src, filter)
+    }
+
+    fn set_caps(&self, src: &RsBaseSrc, caps: &gst::CapsRef) -> bool {
+        let source_impl = &mut self.imp.lock().unwrap();
+
+        gst_debug!(self.cat, obj: src, "Setting caps {:?}", caps);
+
+        match source_impl.set_caps(src, caps) {
+            Ok(..) => true,
+            Err(ref msg) => {
+                gst_error!(self.cat, obj: src, "Failed to set caps {:?}", msg);
+                msg.post(src);
+                false
+            }
+        }
+    }
+
+    fn fixate(&self, src: &RsBaseSrc, caps: gst::Caps) -> gst::Caps {
+        let source_impl = &self.imp.lock().unwrap();
+        source_impl.fixate(src, caps)
+    }
 }
 
 impl URIHandlerImpl for Source {
@@ -315,6 +481,21 @@ impl URIHandlerImpl for Source {
     }
 }
 
+// Current running time according to the pipeline clock, to be used as
+// timestamp for buffers of live sources
+pub fn get_running_time(src: &RsBaseSrc) -> Option<u64> {
+    src.get_clock().map(|clock| {
+        let now = clock.get_time();
+        let base_time = src.get_base_time();
+
+        if now > base_time {
+            now - base_time
+        } else {
+            0
+        }
+    })
+}
+
 pub struct SourceInfo {
     pub name: String,
     pub long_name: String,
@@ -325,6 +506,12 @@ pub struct SourceInfo {
     pub create_instance: fn(&RsBaseSrc) -> Box<SourceImpl>,
     pub protocols: Vec<String>,
     pub push_only: bool,
+    pub output_caps: gst::Caps,
+    // Live sources usually also produce buffers in time format, timestamped
+    // with get_running_time()
+    pub is_live: bool,
+    pub format: gst::Format,
+    pub properties: &'static [Property<'static>],
 }
 
 struct SourceStatic {
@@ -0,0 +1,710 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ptr;
+
+use libc;
+
+use glib_ffi;
+use gst_ffi;
+use gst_base_ffi;
+
+use glib;
+use glib::translate::*;
+use gst;
+use gst::prelude::*;
+use gst_base;
+
+use object::*;
+use element::*;
+use anyimpl::*;
+
+glib_wrapper! {
+    pub struct RsAggregatorPad(Object<gst_base_ffi::GstAggregatorPad>): [gst::Pad => gst_ffi::GstPad,
+                                                                        gst::Object => gst_ffi::GstObject];
+
+    match fn {
+        get_type => || gst_base_ffi::gst_aggregator_pad_get_type(),
+    }
+}
+
+impl RsAggregatorPad {
+    pub fn pop_buffer(&self) -> Option<gst::Buffer> {
+        unsafe {
+            from_glib_full(gst_base_ffi::gst_aggregator_pad_pop_buffer(self.to_glib_none().0))
+        }
+    }
+
+    pub fn peek_buffer(&self) -> Option<gst::Buffer> {
+        unsafe {
+            from_glib_full(gst_base_ffi::gst_aggregator_pad_peek_buffer(self.to_glib_none().0))
+        }
+    }
+
+    pub fn drop_buffer(&self) -> bool {
+        unsafe { from_glib(gst_base_ffi::gst_aggregator_pad_drop_buffer(self.to_glib_none().0)) }
+    }
+
+    pub fn is_eos(&self) -> bool {
+        unsafe { from_glib(gst_base_ffi::gst_aggregator_pad_is_eos(self.to_glib_none().0)) }
+    }
+
+    // Segment of the buffers returned by pop_buffer(), only consistent when
+    // called from aggregate()
+    pub fn get_segment(&self) -> gst::Segment {
+        unsafe {
+            let pad: *mut gst_base_ffi::GstAggregatorPad = self.to_glib_none().0;
+            from_glib_none(&(*pad).segment as *const gst_ffi::GstSegment)
+        }
+    }
+}
+
+unsafe impl Send for RsAggregatorPad {}
+unsafe impl Sync for RsAggregatorPad {}
+
+pub trait AggregatorImpl<T: Aggregator>
+    : AnyImpl + ObjectImpl<T> + ElementImpl<T> + Send + Sync + 'static {
+    fn start(&self, _element: &T) -> bool {
+        true
+    }
+
+    fn stop(&self, _element: &T) -> bool {
+        true
+    }
+
+    fn flush(&self, _element: &T) -> gst::FlowReturn {
+        gst::FlowReturn::Ok
+    }
+
+    // Returning None drops the buffer
+    fn clip(
+        &self,
+        _element: &T,
+        _aggregator_pad: &RsAggregatorPad,
+        buffer: gst::Buffer,
+    ) -> Option<gst::Buffer> {
+        Some(buffer)
+    }
+
+    fn sink_event(
+        &self,
+        element: &T,
+        aggregator_pad: &RsAggregatorPad,
+        event: gst::Event,
+    ) -> bool {
+        element.parent_sink_event(aggregator_pad, event)
+    }
+
+    fn sink_query(
+        &self,
+        element: &T,
+        aggregator_pad: &RsAggregatorPad,
+        query: &mut gst::QueryRef,
+    ) -> bool {
+        element.parent_sink_query(aggregator_pad, query)
+    }
+
+    // Called once data is available on all sink pads. When live, i.e. if any
+    // upstream is live, it is also called with timeout set once the running
+    // time from get_next_time() plus the latency passed without data on all
+    // pads. Pads without data then have to be handled as missing data
+    fn aggregate(&self, element: &T, timeout: bool) -> gst::FlowReturn;
+
+    fn create_new_pad(
+        &self,
+        element: &T,
+        templ: &gst::PadTemplate,
+        req_name: Option<String>,
+        caps: Option<&gst::CapsRef>,
+    ) -> Option<RsAggregatorPad> {
+        element.parent_create_new_pad(templ, req_name, caps)
+    }
+
+    // Running time of the next output buffer, after which aggregate() is to
+    // be called with timeout when live. Usually the running time of the
+    // position of get_src_segment(). The default None never times out, so
+    // live sources that stop sending data would stall the aggregator
+    fn get_next_time(&self, element: &T) -> Option<u64> {
+        element.parent_get_next_time()
+    }
+}
+
+any_impl!(Aggregator, AggregatorImpl);
+
+pub unsafe trait Aggregator
+    : IsA<gst::Element> + IsA<gst_base::Aggregator> + ObjectType {
+    fn parent_sink_event(&self, aggregator_pad: &RsAggregatorPad, event: gst::Event) -> bool {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstAggregatorClass;
+            (*parent_klass)
+                .sink_event
+                .map(|f| {
+                    from_glib(f(
+                        self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                        aggregator_pad.to_glib_none().0,
+                        event.into_ptr(),
+                    ))
+                })
+                .unwrap_or(false)
+        }
+    }
+
+    fn parent_sink_query(
+        &self,
+        aggregator_pad: &RsAggregatorPad,
+        query: &mut gst::QueryRef,
+    ) -> bool {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstAggregatorClass;
+            (*parent_klass)
+                .sink_query
+                .map(|f| {
+                    from_glib(f(
+                        self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                        aggregator_pad.to_glib_none().0,
+                        query.as_mut_ptr(),
+                    ))
+                })
+                .unwrap_or(false)
+        }
+    }
+
+    fn parent_create_new_pad(
+        &self,
+        templ: &gst::PadTemplate,
+        req_name: Option<String>,
+        caps: Option<&gst::CapsRef>,
+    ) -> Option<RsAggregatorPad> {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstAggregatorClass;
+            (*parent_klass)
+                .create_new_pad
+                .map(|f| {
+                    from_glib_full(f(
+                        self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                        templ.to_glib_none().0,
+                        req_name.to_glib_none().0,
+                        caps.map(|caps| caps.as_ptr()).unwrap_or(ptr::null()),
+                    ))
+                })
+                .unwrap_or(None)
+        }
+    }
+
+    fn parent_get_next_time(&self) -> Option<u64> {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstAggregatorClass;
+            (*parent_klass)
+                .get_next_time
+                .map(|f| f(self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator))
+                .and_then(|time| if time == gst::CLOCK_TIME_NONE {
+                    None
+                } else {
+                    Some(time)
+                })
+        }
+    }
+
+    fn get_src_pad(&self) -> gst::Pad {
+        unsafe {
+            let agg = self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator;
+            from_glib_none((*agg).srcpad as *mut gst_ffi::GstPad)
+        }
+    }
+
+    // Segment of the output, its position is the end of the last buffer
+    // passed to finish_buffer()
+    fn get_src_segment(&self) -> gst::Segment {
+        unsafe {
+            let agg = self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator;
+            let srcpad = (*agg).srcpad as *mut gst_base_ffi::GstAggregatorPad;
+            from_glib_none(&(*srcpad).segment as *const gst_ffi::GstSegment)
+        }
+    }
+
+    fn finish_buffer(&self, buffer: gst::Buffer) -> gst::FlowReturn {
+        unsafe {
+            from_glib(gst_base_ffi::gst_aggregator_finish_buffer(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                buffer.into_ptr(),
+            ))
+        }
+    }
+
+    fn set_src_caps(&self, caps: &gst::Caps) {
+        unsafe {
+            gst_base_ffi::gst_aggregator_set_src_caps(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                caps.as_mut_ptr(),
+            );
+        }
+    }
+
+    // Latency added by the subclass on top of the upstream latency. The
+    // "latency" property additionally allows applications to wait longer for
+    // late data when live
+    fn set_latency(&self, min_latency: u64, max_latency: Option<u64>) {
+        unsafe {
+            gst_base_ffi::gst_aggregator_set_latency(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+                min_latency,
+                max_latency.unwrap_or(gst::CLOCK_TIME_NONE),
+            );
+        }
+    }
+
+    // None if not live
+    fn get_latency(&self) -> Option<u64> {
+        let latency = unsafe {
+            gst_base_ffi::gst_aggregator_get_latency(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstAggregator,
+            )
+        };
+
+        if latency == gst::CLOCK_TIME_NONE {
+            None
+        } else {
+            Some(latency)
+        }
+    }
+}
+
+pub unsafe trait AggregatorClass<T: Aggregator>
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    fn override_vfuncs(&mut self, _: &ClassInitToken) {
+        unsafe {
+            let klass = &mut *(self as *const Self as *mut gst_base_ffi::GstAggregatorClass);
+            klass.start = Some(aggregator_start::<T>);
+            klass.stop = Some(aggregator_stop::<T>);
+            klass.flush = Some(aggregator_flush::<T>);
+            klass.clip = Some(aggregator_clip::<T>);
+            klass.sink_event = Some(aggregator_sink_event::<T>);
+            klass.sink_query = Some(aggregator_sink_query::<T>);
+            klass.aggregate = Some(aggregator_aggregate::<T>);
+            klass.create_new_pad = Some(aggregator_create_new_pad::<T>);
+            klass.get_next_time = Some(aggregator_get_next_time::<T>);
+        }
+    }
+}
+
+glib_wrapper! {
+    pub struct RsAggregator(Object<InstanceStruct<RsAggregator>>): [gst_base::Aggregator => gst_base_ffi::GstAggregator,
+                                                                    gst::Element => gst_ffi::GstElement,
+                                                                    gst::Object => gst_ffi::GstObject];
+
+    match fn {
+        get_type => || get_type::<RsAggregator>(),
+    }
+}
+
+unsafe impl<T: IsA<gst::Element> + IsA<gst_base::Aggregator> + ObjectType> Aggregator for T {}
+pub type RsAggregatorClass = ClassStruct<RsAggregator>;
+
+// FIXME: Boilerplate
+unsafe impl AggregatorClass<RsAggregator> for RsAggregatorClass {}
+unsafe impl ElementClass<RsAggregator> for RsAggregatorClass {}
+
+#[macro_export]
+macro_rules! box_aggregator_impl(
+    ($name:ident) => {
+        box_element_impl!($name);
+
+        impl<T: Aggregator> AggregatorImpl<T> for Box<$name<T>> {
+            fn start(&self, element: &T) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.start(element)
+            }
+
+            fn stop(&self, element: &T) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.stop(element)
+            }
+
+            fn flush(&self, element: &T) -> gst::FlowReturn {
+                let imp: &$name<T> = self.as_ref();
+                imp.flush(element)
+            }
+
+            fn clip(&self, element: &T, aggregator_pad: &RsAggregatorPad, buffer: gst::Buffer) -> Option<gst::Buffer> {
+                let imp: &$name<T> = self.as_ref();
+                imp.clip(element, aggregator_pad, buffer)
+            }
+
+            fn sink_event(&self, element: &T, aggregator_pad: &RsAggregatorPad, event: gst::Event) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.sink_event(element, aggregator_pad, event)
+            }
+
+            fn sink_query(&self, element: &T, aggregator_pad: &RsAggregatorPad, query: &mut gst::QueryRef) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.sink_query(element, aggregator_pad, query)
+            }
+
+            fn aggregate(&self, element: &T, timeout: bool) -> gst::FlowReturn {
+                let imp: &$name<T> = self.as_ref();
+                imp.aggregate(element, timeout)
+            }
+
+            fn create_new_pad(&self, element: &T, templ: &gst::PadTemplate, req_name: Option<String>, caps: Option<&gst::CapsRef>) -> Option<RsAggregatorPad> {
+                let imp: &$name<T> = self.as_ref();
+                imp.create_new_pad(element, templ, req_name, caps)
+            }
+
+            fn get_next_time(&self, element: &T) -> Option<u64> {
+                let imp: &$name<T> = self.as_ref();
+                imp.get_next_time(element)
+            }
+        }
+    };
+);
+box_aggregator_impl!(AggregatorImpl);
+
+impl ObjectType for RsAggregator {
+    const NAME: &'static str = "RsAggregator";
+    type GlibType = gst_base_ffi::GstAggregator;
+    type GlibClassType = gst_base_ffi::GstAggregatorClass;
+    type ImplType = Box<AggregatorImpl<Self>>;
+
+    fn glib_type() -> glib::Type {
+        unsafe { from_glib(gst_base_ffi::gst_aggregator_get_type()) }
+    }
+
+    fn class_init(token: &ClassInitToken, klass: &mut RsAggregatorClass) {
+        ElementClass::override_vfuncs(klass, token);
+        AggregatorClass::override_vfuncs(klass, token);
+
+        // Request pads are created by GstAggregator via create_new_pad()
+        unsafe {
+            let parent_klass = klass.get_parent_class() as *const gst_ffi::GstElementClass;
+            let element_klass =
+                &mut *(klass as *mut RsAggregatorClass as *mut gst_ffi::GstElementClass);
+            element_klass.request_new_pad = (*parent_klass).request_new_pad;
+            element_klass.release_pad = (*parent_klass).release_pad;
+        }
+    }
+
+    object_type_fns!();
+}
+
+unsafe extern "C" fn aggregator_start<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, { imp.start(&wrap) }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_stop<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, { imp.stop(&wrap) }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_flush<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+) -> gst_ffi::GstFlowReturn
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, gst::FlowReturn::Error, {
+        imp.flush(&wrap)
+    }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_clip<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+    aggregator_pad: *mut gst_base_ffi::GstAggregatorPad,
+    buffer: *mut gst_ffi::GstBuffer,
+) -> *mut gst_ffi::GstBuffer
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, None, {
+        imp.clip(&wrap, &from_glib_borrow(aggregator_pad), from_glib_full(buffer))
+    }).map(|buffer| buffer.into_ptr())
+        .unwrap_or(ptr::null_mut())
+}
+
+unsafe extern "C" fn aggregator_sink_event<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+    aggregator_pad: *mut gst_base_ffi::GstAggregatorPad,
+    event: *mut gst_ffi::GstEvent,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, {
+        imp.sink_event(&wrap, &from_glib_borrow(aggregator_pad), from_glib_full(event))
+    }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_sink_query<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+    aggregator_pad: *mut gst_base_ffi::GstAggregatorPad,
+    query: *mut gst_ffi::GstQuery,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, {
+        imp.sink_query(
+            &wrap,
+            &from_glib_borrow(aggregator_pad),
+            gst::QueryRef::from_mut_ptr(query),
+        )
+    }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_aggregate<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+    timeout: glib_ffi::gboolean,
+) -> gst_ffi::GstFlowReturn
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, gst::FlowReturn::Error, {
+        imp.aggregate(&wrap, from_glib(timeout))
+    }).to_glib()
+}
+
+unsafe extern "C" fn aggregator_create_new_pad<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+    templ: *mut gst_ffi::GstPadTemplate,
+    req_name: *const libc::c_char,
+    caps: *const gst_ffi::GstCaps,
+) -> *mut gst_base_ffi::GstAggregatorPad
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+    let caps = if caps.is_null() {
+        None
+    } else {
+        Some(gst::CapsRef::from_ptr(caps))
+    };
+
+    panic_to_error!(&wrap, &element.panicked, None, {
+        imp.create_new_pad(&wrap, &from_glib_borrow(templ), from_glib_none(req_name), caps)
+    }).to_glib_full()
+}
+
+unsafe extern "C" fn aggregator_get_next_time<T: Aggregator>(
+    ptr: *mut gst_base_ffi::GstAggregator,
+) -> gst_ffi::GstClockTime
+where
+    T::ImplType: AggregatorImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, None, { imp.get_next_time(&wrap) })
+        .unwrap_or(gst::CLOCK_TIME_NONE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    // Forwards the buffers of its only sink pad
+    struct TestAggregator {
+        pad: Mutex<Option<RsAggregatorPad>>,
+    }
+
+    impl ObjectImpl<RsAggregator> for TestAggregator {}
+
+    impl ElementImpl<RsAggregator> for TestAggregator {}
+
+    impl AggregatorImpl<RsAggregator> for TestAggregator {
+        fn aggregate(&self, element: &RsAggregator, _timeout: bool) -> gst::FlowReturn {
+            let pad = match *self.pad.lock().unwrap() {
+                Some(ref pad) => pad.clone(),
+                None => return gst::FlowReturn::Error,
+            };
+
+            match pad.pop_buffer() {
+                Some(buffer) => {
+                    element.set_src_caps(&gst::Caps::new_simple("test/x-buffers", &[]));
+                    element.finish_buffer(buffer)
+                }
+                None if pad.is_eos() => gst::FlowReturn::Eos,
+                None => gst::FlowReturn::Ok,
+            }
+        }
+
+        fn create_new_pad(
+            &self,
+            element: &RsAggregator,
+            templ: &gst::PadTemplate,
+            req_name: Option<String>,
+            caps: Option<&gst::CapsRef>,
+        ) -> Option<RsAggregatorPad> {
+            let pad = element.parent_create_new_pad(templ, req_name, caps);
+            *self.pad.lock().unwrap() = pad.clone();
+            pad
+        }
+    }
+
+    struct TestAggregatorStatic;
+
+    impl ImplTypeStatic<RsAggregator> for TestAggregatorStatic {
+        fn get_name(&self) -> &str {
+            "TestAggregator"
+        }
+
+        fn new(&self, _element: &RsAggregator) -> Box<AggregatorImpl<RsAggregator>> {
+            Box::new(TestAggregator {
+                pad: Mutex::new(None),
+            })
+        }
+
+        fn class_init(&self, klass: &mut RsAggregatorClass) {
+            let caps = gst::Caps::new_any();
+            klass.add_pad_template(gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            ));
+            klass.add_pad_template(gst::PadTemplate::new(
+                "sink_%u",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            ));
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        use gst::EventView;
+
+        gst::init().unwrap();
+
+        let type_ = register_type(TestAggregatorStatic);
+        let agg = glib::Object::new(type_, &[])
+            .unwrap()
+            .downcast::<gst::Element>()
+            .unwrap();
+
+        // Buffer data and None for EOS
+        let (sender, receiver) = mpsc::channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let sender_clone = sender.clone();
+        let sinkpad = gst::Pad::new("sink", gst::PadDirection::Sink);
+        sinkpad.set_chain_function(move |_, _, buffer| {
+            let map = buffer.map_readable().unwrap();
+            sender.lock().unwrap().send(Some(map.as_slice().to_vec())).unwrap();
+            gst::FlowReturn::Ok
+        });
+        sinkpad.set_event_function(move |_, _, event| {
+            if let EventView::Eos(..) = event.view() {
+                sender_clone.lock().unwrap().send(None).unwrap();
+            }
+            true
+        });
+        sinkpad.set_active(true).unwrap();
+        assert_eq!(
+            agg.get_static_pad("src").unwrap().link(&sinkpad),
+            gst::PadLinkReturn::Ok
+        );
+
+        let agg_sinkpad = agg.get_request_pad("sink_%u").unwrap();
+        let srcpad = gst::Pad::new("src", gst::PadDirection::Src);
+        assert_eq!(srcpad.link(&agg_sinkpad), gst::PadLinkReturn::Ok);
+        srcpad.set_active(true).unwrap();
+
+        assert_ne!(
+            agg.set_state(gst::State::Playing),
+            gst::StateChangeReturn::Failure
+        );
+
+        let mut segment = gst::Segment::default();
+        segment.init(gst::Format::Time);
+        srcpad.push_event(gst::Event::new_stream_start("test").build());
+        let caps = gst::Caps::new_simple("test/x-buffers", &[]);
+        srcpad.push_event(gst::Event::new_caps(&caps).build());
+        srcpad.push_event(gst::Event::new_segment(&segment).build());
+
+        let mut buffer = gst::Buffer::from_vec(b"abcd".to_vec()).unwrap();
+        buffer.get_mut().unwrap().set_pts(0);
+        assert_eq!(srcpad.push(buffer), gst::FlowReturn::Ok);
+        srcpad.push_event(gst::Event::new_eos().build());
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), Some(b"abcd".to_vec()));
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), None);
+
+        agg.set_state(gst::State::Null);
+        agg.release_request_pad(&agg_sinkpad);
+    }
+}
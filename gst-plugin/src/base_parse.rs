@@ -0,0 +1,640 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use libc;
+
+use glib_ffi;
+use gst_ffi;
+use gst_base_ffi;
+
+use glib;
+use glib::translate::*;
+use gst;
+use gst::prelude::*;
+use gst_base;
+
+use object::*;
+use element::*;
+use anyimpl::*;
+
+// Frame of the data currently parsed, only valid during the vfunc call it
+// was passed to
+pub struct BaseParseFrame<'a>(&'a mut gst_base_ffi::GstBaseParseFrame);
+
+impl<'a> BaseParseFrame<'a> {
+    pub fn get_buffer(&self) -> &gst::BufferRef {
+        unsafe { gst::BufferRef::from_ptr(self.0.buffer) }
+    }
+
+    // The frame's buffer is always writable, all metadata set on it is
+    // kept for the output buffer
+    pub fn get_buffer_mut(&mut self) -> &mut gst::BufferRef {
+        unsafe { gst::BufferRef::from_mut_ptr(self.0.buffer) }
+    }
+
+    pub fn get_offset(&self) -> u64 {
+        self.0.offset
+    }
+
+    pub fn set_pts(&mut self, pts: u64) {
+        self.get_buffer_mut().set_pts(pts);
+    }
+
+    pub fn set_dts(&mut self, dts: u64) {
+        self.get_buffer_mut().set_dts(dts);
+    }
+
+    pub fn set_duration(&mut self, duration: u64) {
+        self.get_buffer_mut().set_duration(duration);
+    }
+
+    pub fn set_keyframe(&mut self, keyframe: bool) {
+        let buffer = self.get_buffer_mut();
+        if keyframe {
+            buffer.unset_flags(gst::BufferFlags::DELTA_UNIT);
+        } else {
+            buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+        }
+    }
+
+    // Combination of the GST_BASE_PARSE_FRAME_FLAG_* values
+    pub fn get_flags(&self) -> u32 {
+        self.0.flags
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        self.0.flags |= flags;
+    }
+
+    pub fn unset_flags(&mut self, flags: u32) {
+        self.0.flags &= !flags;
+    }
+}
+
+pub trait BaseParseImpl<T: BaseParse>
+    : AnyImpl + ObjectImpl<T> + ElementImpl<T> + Send + Sync + 'static {
+    fn start(&self, _element: &T) -> bool {
+        true
+    }
+
+    fn stop(&self, _element: &T) -> bool {
+        true
+    }
+
+    fn set_sink_caps(&self, _element: &T, _caps: &gst::Caps) -> bool {
+        true
+    }
+
+    // Either finishes a frame with BaseParse::finish_frame() or returns how
+    // many bytes to skip until the next possible frame start
+    fn handle_frame(&self, element: &T, frame: &mut BaseParseFrame) -> Result<u32, gst::FlowReturn>;
+
+    // By default the frame is clipped to the segment
+    fn pre_push_frame(&self, element: &T, frame: &mut BaseParseFrame) -> gst::FlowReturn {
+        element.parent_pre_push_frame(frame)
+    }
+
+    fn convert(
+        &self,
+        element: &T,
+        src_format: gst::Format,
+        src_value: i64,
+        dest_format: gst::Format,
+    ) -> Option<i64> {
+        element.parent_convert(src_format, src_value, dest_format)
+    }
+
+    // Called with the data collected so far until it returns Ok, e.g. to find
+    // the format before parsing frames. NeedData waits for more data. Only
+    // called if enabled with BaseParseClass::enable_detect()
+    fn detect(&self, _element: &T, _buffer: &gst::Buffer) -> gst::FlowReturn {
+        gst::FlowReturn::Ok
+    }
+}
+
+any_impl!(BaseParse, BaseParseImpl);
+
+pub unsafe trait BaseParse
+    : IsA<gst::Element> + IsA<gst_base::BaseParse> + ObjectType {
+    fn parent_convert(
+        &self,
+        src_format: gst::Format,
+        src_value: i64,
+        dest_format: gst::Format,
+    ) -> Option<i64> {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstBaseParseClass;
+            (*parent_klass)
+                .convert
+                .map(|f| {
+                    let mut dest_value = 0;
+                    let res: bool = from_glib(f(
+                        self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                        src_format.to_glib(),
+                        src_value,
+                        dest_format.to_glib(),
+                        &mut dest_value,
+                    ));
+                    if res {
+                        Some(dest_value)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(None)
+        }
+    }
+
+    // Clips the frame if the parent class has no pre_push_frame
+    fn parent_pre_push_frame(&self, frame: &mut BaseParseFrame) -> gst::FlowReturn {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass =
+                (*klass).get_parent_class() as *const gst_base_ffi::GstBaseParseClass;
+            match (*parent_klass).pre_push_frame {
+                Some(f) => from_glib(f(
+                    self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                    frame.0,
+                )),
+                None => {
+                    frame.set_flags(gst_base_ffi::GST_BASE_PARSE_FRAME_FLAG_CLIP as u32);
+                    gst::FlowReturn::Ok
+                }
+            }
+        }
+    }
+
+    fn finish_frame(&self, frame: &mut BaseParseFrame, size: u32) -> gst::FlowReturn {
+        unsafe {
+            from_glib(gst_base_ffi::gst_base_parse_finish_frame(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                frame.0,
+                size as libc::c_int,
+            ))
+        }
+    }
+
+    fn get_src_pad(&self) -> gst::Pad {
+        unsafe {
+            let parse = self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse;
+            from_glib_none((*parse).srcpad)
+        }
+    }
+
+    fn get_sink_pad(&self) -> gst::Pad {
+        unsafe {
+            let parse = self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse;
+            from_glib_none((*parse).sinkpad)
+        }
+    }
+
+    fn set_min_frame_size(&self, min_size: u32) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_min_frame_size(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                min_size,
+            );
+        }
+    }
+
+    // Interval in frames after which the duration is updated, 0 to never
+    // update it
+    fn set_duration(&self, format: gst::Format, duration: i64, interval: i32) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_duration(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                format.to_glib(),
+                duration,
+                interval,
+            );
+        }
+    }
+
+    fn set_frame_rate(&self, fps_num: u32, fps_den: u32, lead_in: u32, lead_out: u32) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_frame_rate(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                fps_num,
+                fps_den,
+                lead_in,
+                lead_out,
+            );
+        }
+    }
+
+    fn set_has_timing_info(&self, has_timing: bool) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_has_timing_info(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                has_timing.to_glib(),
+            );
+        }
+    }
+
+    fn set_syncable(&self, syncable: bool) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_syncable(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                syncable.to_glib(),
+            );
+        }
+    }
+
+    fn set_passthrough(&self, passthrough: bool) {
+        unsafe {
+            gst_base_ffi::gst_base_parse_set_passthrough(
+                self.to_glib_none().0 as *mut gst_base_ffi::GstBaseParse,
+                passthrough.to_glib(),
+            );
+        }
+    }
+}
+
+pub unsafe trait BaseParseClass<T: BaseParse>
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    fn override_vfuncs(&mut self, _: &ClassInitToken) {
+        unsafe {
+            let klass = &mut *(self as *const Self as *mut gst_base_ffi::GstBaseParseClass);
+            klass.start = Some(base_parse_start::<T>);
+            klass.stop = Some(base_parse_stop::<T>);
+            klass.set_sink_caps = Some(base_parse_set_sink_caps::<T>);
+            klass.handle_frame = Some(base_parse_handle_frame::<T>);
+            klass.pre_push_frame = Some(base_parse_pre_push_frame::<T>);
+            klass.convert = Some(base_parse_convert::<T>);
+        }
+    }
+
+    // Puts the parser into detection mode at the start, otherwise detect()
+    // is never called
+    fn enable_detect(&mut self) {
+        unsafe {
+            let klass = &mut *(self as *const Self as *mut gst_base_ffi::GstBaseParseClass);
+            klass.detect = Some(base_parse_detect::<T>);
+        }
+    }
+}
+
+glib_wrapper! {
+    pub struct RsBaseParse(Object<InstanceStruct<RsBaseParse>>): [gst_base::BaseParse => gst_base_ffi::GstBaseParse,
+                                                                  gst::Element => gst_ffi::GstElement,
+                                                                  gst::Object => gst_ffi::GstObject];
+
+    match fn {
+        get_type => || get_type::<RsBaseParse>(),
+    }
+}
+
+unsafe impl<T: IsA<gst::Element> + IsA<gst_base::BaseParse> + ObjectType> BaseParse for T {}
+pub type RsBaseParseClass = ClassStruct<RsBaseParse>;
+
+// FIXME: Boilerplate
+unsafe impl BaseParseClass<RsBaseParse> for RsBaseParseClass {}
+unsafe impl ElementClass<RsBaseParse> for RsBaseParseClass {}
+
+#[macro_export]
+macro_rules! box_base_parse_impl(
+    ($name:ident) => {
+        box_element_impl!($name);
+
+        impl<T: BaseParse> BaseParseImpl<T> for Box<$name<T>> {
+            fn start(&self, element: &T) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.start(element)
+            }
+
+            fn stop(&self, element: &T) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.stop(element)
+            }
+
+            fn set_sink_caps(&self, element: &T, caps: &gst::Caps) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.set_sink_caps(element, caps)
+            }
+
+            fn handle_frame(&self, element: &T, frame: &mut BaseParseFrame) -> Result<u32, gst::FlowReturn> {
+                let imp: &$name<T> = self.as_ref();
+                imp.handle_frame(element, frame)
+            }
+
+            fn pre_push_frame(&self, element: &T, frame: &mut BaseParseFrame) -> gst::FlowReturn {
+                let imp: &$name<T> = self.as_ref();
+                imp.pre_push_frame(element, frame)
+            }
+
+            fn convert(&self, element: &T, src_format: gst::Format, src_value: i64, dest_format: gst::Format) -> Option<i64> {
+                let imp: &$name<T> = self.as_ref();
+                imp.convert(element, src_format, src_value, dest_format)
+            }
+
+            fn detect(&self, element: &T, buffer: &gst::Buffer) -> gst::FlowReturn {
+                let imp: &$name<T> = self.as_ref();
+                imp.detect(element, buffer)
+            }
+        }
+    };
+);
+box_base_parse_impl!(BaseParseImpl);
+
+impl ObjectType for RsBaseParse {
+    const NAME: &'static str = "RsBaseParse";
+    type GlibType = gst_base_ffi::GstBaseParse;
+    type GlibClassType = gst_base_ffi::GstBaseParseClass;
+    type ImplType = Box<BaseParseImpl<Self>>;
+
+    fn glib_type() -> glib::Type {
+        unsafe { from_glib(gst_base_ffi::gst_base_parse_get_type()) }
+    }
+
+    fn class_init(token: &ClassInitToken, klass: &mut RsBaseParseClass) {
+        ElementClass::override_vfuncs(klass, token);
+        BaseParseClass::override_vfuncs(klass, token);
+    }
+
+    object_type_fns!();
+}
+
+unsafe extern "C" fn base_parse_start<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, { imp.start(&wrap) }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_stop<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, { imp.stop(&wrap) }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_set_sink_caps<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+    caps: *mut gst_ffi::GstCaps,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, {
+        imp.set_sink_caps(&wrap, &from_glib_borrow(caps))
+    }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_handle_frame<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+    frame: *mut gst_base_ffi::GstBaseParseFrame,
+    skipsize: *mut libc::c_int,
+) -> gst_ffi::GstFlowReturn
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, gst::FlowReturn::Error, {
+        match imp.handle_frame(&wrap, &mut BaseParseFrame(&mut *frame)) {
+            Ok(skip) => {
+                *skipsize = skip as libc::c_int;
+                gst::FlowReturn::Ok
+            }
+            Err(flow_ret) => flow_ret,
+        }
+    }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_pre_push_frame<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+    frame: *mut gst_base_ffi::GstBaseParseFrame,
+) -> gst_ffi::GstFlowReturn
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, gst::FlowReturn::Error, {
+        imp.pre_push_frame(&wrap, &mut BaseParseFrame(&mut *frame))
+    }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_convert<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+    src_format: gst_ffi::GstFormat,
+    src_value: i64,
+    dest_format: gst_ffi::GstFormat,
+    dest_value: *mut i64,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, false, {
+        match imp.convert(
+            &wrap,
+            from_glib(src_format),
+            src_value,
+            from_glib(dest_format),
+        ) {
+            Some(value) => {
+                *dest_value = value;
+                true
+            }
+            None => false,
+        }
+    }).to_glib()
+}
+
+unsafe extern "C" fn base_parse_detect<T: BaseParse>(
+    ptr: *mut gst_base_ffi::GstBaseParse,
+    buffer: *mut gst_ffi::GstBuffer,
+) -> gst_ffi::GstFlowReturn
+where
+    T::ImplType: BaseParseImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let element = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*element.imp;
+
+    panic_to_error!(&wrap, &element.panicked, gst::FlowReturn::Error, {
+        imp.detect(&wrap, &from_glib_borrow(buffer))
+    }).to_glib()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Frames are 4 bytes starting with 'F', everything in between is skipped
+    struct TestParse;
+
+    impl ObjectImpl<RsBaseParse> for TestParse {}
+
+    impl ElementImpl<RsBaseParse> for TestParse {}
+
+    impl BaseParseImpl<RsBaseParse> for TestParse {
+        fn start(&self, element: &RsBaseParse) -> bool {
+            element.set_min_frame_size(4);
+            true
+        }
+
+        fn handle_frame(
+            &self,
+            element: &RsBaseParse,
+            frame: &mut BaseParseFrame,
+        ) -> Result<u32, gst::FlowReturn> {
+            let skip = {
+                let map = frame.get_buffer().map_readable().unwrap();
+                let data = map.as_slice();
+                data.iter().position(|&b| b == b'F').unwrap_or(data.len())
+            };
+
+            if skip > 0 {
+                return Ok(skip as u32);
+            }
+
+            match element.finish_frame(frame, 4) {
+                gst::FlowReturn::Ok => Ok(0),
+                flow_ret => Err(flow_ret),
+            }
+        }
+
+        fn pre_push_frame(
+            &self,
+            element: &RsBaseParse,
+            frame: &mut BaseParseFrame,
+        ) -> gst::FlowReturn {
+            let flow_ret = element.parent_pre_push_frame(frame);
+            let clip = gst_base_ffi::GST_BASE_PARSE_FRAME_FLAG_CLIP as u32;
+            assert_eq!(frame.get_flags() & clip, clip);
+            flow_ret
+        }
+    }
+
+    struct TestParseStatic;
+
+    impl ImplTypeStatic<RsBaseParse> for TestParseStatic {
+        fn get_name(&self) -> &str {
+            "TestParse"
+        }
+
+        fn new(&self, _element: &RsBaseParse) -> Box<BaseParseImpl<RsBaseParse>> {
+            Box::new(TestParse)
+        }
+
+        fn class_init(&self, klass: &mut RsBaseParseClass) {
+            let caps = gst::Caps::new_any();
+            klass.add_pad_template(gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            ));
+            klass.add_pad_template(gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            ));
+        }
+    }
+
+    #[test]
+    fn test_handle_frame() {
+        gst::init().unwrap();
+
+        let type_ = register_type(TestParseStatic);
+        let parse = glib::Object::new(type_, &[])
+            .unwrap()
+            .downcast::<gst::Element>()
+            .unwrap();
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let frames_clone = frames.clone();
+        let sinkpad = gst::Pad::new("sink", gst::PadDirection::Sink);
+        sinkpad.set_chain_function(move |_, _, buffer| {
+            let map = buffer.map_readable().unwrap();
+            frames_clone.lock().unwrap().push(map.as_slice().to_vec());
+            gst::FlowReturn::Ok
+        });
+        sinkpad.set_active(true).unwrap();
+        assert_eq!(
+            parse.get_static_pad("src").unwrap().link(&sinkpad),
+            gst::PadLinkReturn::Ok
+        );
+
+        let srcpad = gst::Pad::new("src", gst::PadDirection::Src);
+        assert_eq!(
+            srcpad.link(&parse.get_static_pad("sink").unwrap()),
+            gst::PadLinkReturn::Ok
+        );
+        srcpad.set_active(true).unwrap();
+
+        assert_ne!(
+            parse.set_state(gst::State::Playing),
+            gst::StateChangeReturn::Failure
+        );
+
+        let mut segment = gst::Segment::default();
+        segment.init(gst::Format::Bytes);
+        srcpad.push_event(gst::Event::new_stream_start("test").build());
+        let caps = gst::Caps::new_simple("test/x-frames", &[]);
+        srcpad.push_event(gst::Event::new_caps(&caps).build());
+        srcpad.push_event(gst::Event::new_segment(&segment).build());
+
+        let buffer = gst::Buffer::from_vec(b"xxFabcFdefyyFghi".to_vec()).unwrap();
+        assert_eq!(srcpad.push(buffer), gst::FlowReturn::Ok);
+        srcpad.push_event(gst::Event::new_eos().build());
+
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec![b"Fabc".to_vec(), b"Fdef".to_vec(), b"Fghi".to_vec()]
+        );
+
+        parse.set_state(gst::State::Null);
+    }
+}
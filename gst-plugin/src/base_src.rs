@@ -344,7 +344,7 @@ macro_rules! box_base_src_impl(
 
             fn unlock_stop(&self, element: &T) -> bool {
                 let imp: &$name<T> = self.as_ref();
-                imp.unlock(element)
+                imp.unlock_stop(element)
             }
         }
     };
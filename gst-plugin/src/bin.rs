@@ -0,0 +1,325 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use glib_ffi;
+use gst_ffi;
+
+use glib;
+use glib::translate::*;
+use gst;
+use gst::prelude::*;
+
+use object::*;
+use element::*;
+use anyimpl::*;
+
+pub trait BinImpl<T: Bin>
+    : AnyImpl + ObjectImpl<T> + ElementImpl<T> + Send + Sync + 'static {
+    fn add_element(&self, bin: &T, element: &gst::Element) -> bool {
+        bin.parent_add_element(element)
+    }
+
+    fn remove_element(&self, bin: &T, element: &gst::Element) -> bool {
+        bin.parent_remove_element(element)
+    }
+
+    // Messages of the children, forwarding them to the parent class posts
+    // them on the bin's bus
+    fn handle_message(&self, bin: &T, message: gst::Message) {
+        bin.parent_handle_message(message)
+    }
+}
+
+any_impl!(Bin, BinImpl);
+
+pub unsafe trait Bin: IsA<gst::Element> + IsA<gst::Bin> + ObjectType {
+    fn parent_add_element(&self, element: &gst::Element) -> bool {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass = (*klass).get_parent_class() as *const gst_ffi::GstBinClass;
+            (*parent_klass)
+                .add_element
+                .map(|f| {
+                    from_glib(f(
+                        self.to_glib_none().0 as *mut gst_ffi::GstBin,
+                        element.to_glib_none().0,
+                    ))
+                })
+                .unwrap_or(false)
+        }
+    }
+
+    fn parent_remove_element(&self, element: &gst::Element) -> bool {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass = (*klass).get_parent_class() as *const gst_ffi::GstBinClass;
+            (*parent_klass)
+                .remove_element
+                .map(|f| {
+                    from_glib(f(
+                        self.to_glib_none().0 as *mut gst_ffi::GstBin,
+                        element.to_glib_none().0,
+                    ))
+                })
+                .unwrap_or(false)
+        }
+    }
+
+    fn parent_handle_message(&self, message: gst::Message) {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass = (*klass).get_parent_class() as *const gst_ffi::GstBinClass;
+            (*parent_klass)
+                .handle_message
+                .map(|f| {
+                    f(self.to_glib_none().0 as *mut gst_ffi::GstBin, message.into_ptr())
+                })
+                .unwrap_or(())
+        }
+    }
+
+    // Ghosts target on the bin and activates the new pad
+    fn add_ghost_pad(&self, name: Option<&str>, target: &gst::Pad) -> Option<gst::GhostPad> {
+        gst::GhostPad::new(name, target)
+            .and_then(|ghost_pad| activate_and_add_pad(self, ghost_pad))
+    }
+
+    // For pads that can only be linked to a child later, e.g. after typefinding.
+    // The target is set with set_ghost_pad_target()
+    fn add_ghost_pad_from_template(
+        &self,
+        name: Option<&str>,
+        templ: &gst::PadTemplate,
+    ) -> Option<gst::GhostPad> {
+        gst::GhostPad::new_no_target_from_template(name, templ)
+            .and_then(|ghost_pad| activate_and_add_pad(self, ghost_pad))
+    }
+
+    fn set_ghost_pad_target(&self, ghost_pad: &gst::GhostPad, target: Option<&gst::Pad>) -> bool {
+        ghost_pad.set_target(target)
+    }
+}
+
+fn activate_and_add_pad<T: Bin>(bin: &T, ghost_pad: gst::GhostPad) -> Option<gst::GhostPad> {
+    if ghost_pad.set_active(true).is_err() || bin.add_pad(&ghost_pad).is_err() {
+        return None;
+    }
+
+    Some(ghost_pad)
+}
+
+pub unsafe trait BinClass<T: Bin>
+where
+    T::ImplType: BinImpl<T>,
+{
+    fn override_vfuncs(&mut self, _: &ClassInitToken) {
+        unsafe {
+            let klass = &mut *(self as *const Self as *mut gst_ffi::GstBinClass);
+            klass.add_element = Some(bin_add_element::<T>);
+            klass.remove_element = Some(bin_remove_element::<T>);
+            klass.handle_message = Some(bin_handle_message::<T>);
+        }
+    }
+}
+
+glib_wrapper! {
+    pub struct RsBin(Object<InstanceStruct<RsBin>>): [gst::Bin => gst_ffi::GstBin,
+                                                      gst::Element => gst_ffi::GstElement,
+                                                      gst::Object => gst_ffi::GstObject];
+
+    match fn {
+        get_type => || get_type::<RsBin>(),
+    }
+}
+
+unsafe impl<T: IsA<gst::Element> + IsA<gst::Bin> + ObjectType> Bin for T {}
+pub type RsBinClass = ClassStruct<RsBin>;
+
+// FIXME: Boilerplate
+unsafe impl BinClass<RsBin> for RsBinClass {}
+unsafe impl ElementClass<RsBin> for RsBinClass {}
+
+#[macro_export]
+macro_rules! box_bin_impl(
+    ($name:ident) => {
+        box_element_impl!($name);
+
+        impl<T: Bin> BinImpl<T> for Box<$name<T>> {
+            fn add_element(&self, bin: &T, element: &gst::Element) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.add_element(bin, element)
+            }
+
+            fn remove_element(&self, bin: &T, element: &gst::Element) -> bool {
+                let imp: &$name<T> = self.as_ref();
+                imp.remove_element(bin, element)
+            }
+
+            fn handle_message(&self, bin: &T, message: gst::Message) {
+                let imp: &$name<T> = self.as_ref();
+                imp.handle_message(bin, message)
+            }
+        }
+    };
+);
+box_bin_impl!(BinImpl);
+
+impl ObjectType for RsBin {
+    const NAME: &'static str = "RsBin";
+    type GlibType = gst_ffi::GstBin;
+    type GlibClassType = gst_ffi::GstBinClass;
+    type ImplType = Box<BinImpl<Self>>;
+
+    fn glib_type() -> glib::Type {
+        unsafe { from_glib(gst_ffi::gst_bin_get_type()) }
+    }
+
+    fn class_init(token: &ClassInitToken, klass: &mut RsBinClass) {
+        ElementClass::override_vfuncs(klass, token);
+        BinClass::override_vfuncs(klass, token);
+    }
+
+    object_type_fns!();
+}
+
+unsafe extern "C" fn bin_add_element<T: Bin>(
+    ptr: *mut gst_ffi::GstBin,
+    element: *mut gst_ffi::GstElement,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BinImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let bin = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*bin.imp;
+
+    panic_to_error!(&wrap, &bin.panicked, false, {
+        imp.add_element(&wrap, &from_glib_borrow(element))
+    }).to_glib()
+}
+
+unsafe extern "C" fn bin_remove_element<T: Bin>(
+    ptr: *mut gst_ffi::GstBin,
+    element: *mut gst_ffi::GstElement,
+) -> glib_ffi::gboolean
+where
+    T::ImplType: BinImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let bin = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*bin.imp;
+
+    panic_to_error!(&wrap, &bin.panicked, false, {
+        imp.remove_element(&wrap, &from_glib_borrow(element))
+    }).to_glib()
+}
+
+unsafe extern "C" fn bin_handle_message<T: Bin>(
+    ptr: *mut gst_ffi::GstBin,
+    message: *mut gst_ffi::GstMessage,
+) where
+    T::ImplType: BinImpl<T>,
+{
+    callback_guard!();
+    floating_reference_guard!(ptr);
+    let bin = &*(ptr as *mut InstanceStruct<T>);
+    let wrap: T = from_glib_borrow(ptr as *mut InstanceStruct<T>);
+    let imp = &*bin.imp;
+
+    panic_to_error!(&wrap, &bin.panicked, (), {
+        imp.handle_message(&wrap, from_glib_full(message))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the children added to it
+    struct TestBin {
+        n_children: AtomicUsize,
+    }
+
+    impl ObjectImpl<RsBin> for TestBin {}
+
+    impl ElementImpl<RsBin> for TestBin {}
+
+    impl BinImpl<RsBin> for TestBin {
+        fn add_element(&self, bin: &RsBin, element: &gst::Element) -> bool {
+            self.n_children.fetch_add(1, Ordering::SeqCst);
+            bin.parent_add_element(element)
+        }
+    }
+
+    struct TestBinStatic;
+
+    impl ImplTypeStatic<RsBin> for TestBinStatic {
+        fn get_name(&self) -> &str {
+            "TestBin"
+        }
+
+        fn new(&self, _element: &RsBin) -> Box<BinImpl<RsBin>> {
+            Box::new(TestBin {
+                n_children: AtomicUsize::new(0),
+            })
+        }
+
+        fn class_init(&self, klass: &mut RsBinClass) {
+            klass.add_pad_template(gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &gst::Caps::new_any(),
+            ));
+        }
+    }
+
+    #[test]
+    fn test_ghost_pads() {
+        gst::init().unwrap();
+
+        let type_ = register_type(TestBinStatic);
+        let bin = glib::Object::new(type_, &[])
+            .unwrap()
+            .downcast::<RsBin>()
+            .unwrap();
+
+        // Child with a pad in each direction
+        let child = gst::Bin::new(None);
+        let child_srcpad = gst::Pad::new("src", gst::PadDirection::Src);
+        child.add_pad(&child_srcpad).unwrap();
+        let child_sinkpad = gst::Pad::new("sink", gst::PadDirection::Sink);
+        child.add_pad(&child_sinkpad).unwrap();
+
+        bin.add(&child).unwrap();
+        let test_bin = bin.get_impl().downcast_ref::<TestBin>().unwrap();
+        assert_eq!(test_bin.n_children.load(Ordering::SeqCst), 1);
+        assert_eq!(child.get_parent(), Some(bin.clone().upcast::<gst::Object>()));
+
+        let ghost_pad = bin.add_ghost_pad(Some("src"), &child_srcpad).unwrap();
+        assert!(ghost_pad.is_active());
+        assert_eq!(ghost_pad.get_target(), Some(child_srcpad.clone()));
+        assert_eq!(
+            bin.get_static_pad("src"),
+            Some(ghost_pad.clone().upcast::<gst::Pad>())
+        );
+
+        let templ = bin.get_pad_template("sink").unwrap();
+        let ghost_pad = bin.add_ghost_pad_from_template(Some("sink"), &templ)
+            .unwrap();
+        assert!(ghost_pad.is_active());
+        assert_eq!(ghost_pad.get_target(), None);
+        assert!(bin.set_ghost_pad_target(&ghost_pad, Some(&child_sinkpad)));
+        assert_eq!(ghost_pad.get_target(), Some(child_sinkpad.clone()));
+    }
+}
@@ -92,6 +92,15 @@ impl ErrorMessage {
     }
 
     pub fn post<E: IsA<gst::Element>>(&self, element: &E) {
+        self.post_message(element, gst_ffi::GST_MESSAGE_ERROR);
+    }
+
+    // For errors the element could recover from
+    pub fn post_warning<E: IsA<gst::Element>>(&self, element: &E) {
+        self.post_message(element, gst_ffi::GST_MESSAGE_WARNING);
+    }
+
+    fn post_message<E: IsA<gst::Element>>(&self, element: &E, type_: gst_ffi::GstMessageType) {
         let ErrorMessage {
             error_domain,
             error_code,
@@ -105,7 +114,7 @@ impl ErrorMessage {
         unsafe {
             gst_ffi::gst_element_message_full(
                 element.to_glib_none().0,
-                gst_ffi::GST_MESSAGE_ERROR,
+                type_,
                 error_domain,
                 error_code,
                 message.to_glib_full(),
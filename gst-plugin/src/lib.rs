@@ -72,9 +72,17 @@ pub mod object;
 #[macro_use]
 pub mod element;
 #[macro_use]
+pub mod bin;
+#[macro_use]
 pub mod base_src;
 #[macro_use]
 pub mod base_sink;
 #[macro_use]
 pub mod base_transform;
+#[macro_use]
+pub mod base_parse;
+#[cfg(feature = "v1_14")]
+#[macro_use]
+pub mod aggregator;
 pub mod uri_handler;
+pub mod typefind;
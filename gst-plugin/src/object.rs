@@ -16,6 +16,7 @@ use std::sync::Mutex;
 
 use glib_ffi;
 use gobject_ffi;
+use gst_ffi;
 
 use glib;
 use glib::translate::*;
@@ -287,6 +288,8 @@ pub enum PropertyMutability {
     Readable,
     Writable,
     ReadWrite,
+    // Only writable in the NULL and READY states
+    ReadWriteReady,
 }
 
 impl Into<gobject_ffi::GParamFlags> for PropertyMutability {
@@ -297,10 +300,15 @@ impl Into<gobject_ffi::GParamFlags> for PropertyMutability {
             Readable => gobject_ffi::G_PARAM_READABLE,
             Writable => gobject_ffi::G_PARAM_WRITABLE,
             ReadWrite => gobject_ffi::G_PARAM_READWRITE,
+            ReadWriteReady => {
+                gobject_ffi::G_PARAM_READWRITE |
+                    gst_ffi::GST_PARAM_MUTABLE_READY as gobject_ffi::GParamFlags
+            }
         }
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Property<'a> {
     Boolean(&'a str, &'a str, &'a str, bool, PropertyMutability),
     Int(
@@ -362,6 +370,38 @@ pub enum Property<'a> {
     Object(&'a str, &'a str, &'a str, glib::Type, PropertyMutability),
 }
 
+impl<'a> Property<'a> {
+    pub fn get_name(&self) -> &'a str {
+        match *self {
+            Property::Boolean(name, ..) |
+            Property::Int(name, ..) |
+            Property::Int64(name, ..) |
+            Property::UInt(name, ..) |
+            Property::UInt64(name, ..) |
+            Property::Float(name, ..) |
+            Property::Double(name, ..) |
+            Property::String(name, ..) |
+            Property::Boxed(name, ..) |
+            Property::Object(name, ..) => name,
+        }
+    }
+
+    pub fn get_mutability(&self) -> PropertyMutability {
+        match *self {
+            Property::Boolean(.., mutability) |
+            Property::Int(.., mutability) |
+            Property::Int64(.., mutability) |
+            Property::UInt(.., mutability) |
+            Property::UInt64(.., mutability) |
+            Property::Float(.., mutability) |
+            Property::Double(.., mutability) |
+            Property::String(.., mutability) |
+            Property::Boxed(.., mutability) |
+            Property::Object(.., mutability) => mutability,
+        }
+    }
+}
+
 unsafe extern "C" fn class_init<T: ObjectType>(
     klass: glib_ffi::gpointer,
     _klass_data: glib_ffi::gpointer,
@@ -0,0 +1,92 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ptr;
+use std::slice;
+
+use glib_ffi;
+use gst_ffi;
+
+use glib::translate::*;
+use gst;
+
+pub struct TypeFind<'a>(&'a mut gst_ffi::GstTypeFind);
+
+impl<'a> TypeFind<'a> {
+    // Negative offsets are relative to the end of the stream, which is only
+    // possible if the length is known
+    pub fn peek(&mut self, offset: i64, size: u32) -> Option<&[u8]> {
+        unsafe {
+            let data = gst_ffi::gst_type_find_peek(self.0, offset, size);
+            if data.is_null() {
+                None
+            } else {
+                Some(slice::from_raw_parts(data, size as usize))
+            }
+        }
+    }
+
+    pub fn suggest(&mut self, probability: gst::TypeFindProbability, caps: &gst::Caps) {
+        unsafe {
+            gst_ffi::gst_type_find_suggest(
+                self.0,
+                probability.to_glib() as u32,
+                caps.as_mut_ptr(),
+            );
+        }
+    }
+
+    pub fn get_length(&mut self) -> Option<u64> {
+        let length = unsafe { gst_ffi::gst_type_find_get_length(self.0) };
+        if length == 0 {
+            None
+        } else {
+            Some(length)
+        }
+    }
+}
+
+unsafe extern "C" fn type_find_trampoline<F: Fn(&mut TypeFind) + Send + Sync + 'static>(
+    find: *mut gst_ffi::GstTypeFind,
+    user_data: glib_ffi::gpointer,
+) {
+    callback_guard!();
+    let func = &*(user_data as *const F);
+    func(&mut TypeFind(&mut *find));
+}
+
+unsafe extern "C" fn type_find_closure_drop<F: Fn(&mut TypeFind) + Send + Sync + 'static>(
+    data: glib_ffi::gpointer,
+) {
+    Box::<F>::from_raw(data as *mut _);
+}
+
+// Extensions are comma separated without the leading dot, e.g. "flv,f4v"
+pub fn type_find_register<F: Fn(&mut TypeFind) + Send + Sync + 'static>(
+    plugin: &gst::Plugin,
+    name: &str,
+    rank: u32,
+    extensions: Option<&str>,
+    possible_caps: Option<&gst::Caps>,
+    func: F,
+) -> bool {
+    unsafe {
+        let func = Box::new(func);
+
+        from_glib(gst_ffi::gst_type_find_register(
+            plugin.to_glib_none().0,
+            name.to_glib_none().0,
+            rank,
+            Some(type_find_trampoline::<F>),
+            extensions.to_glib_none().0,
+            possible_caps.map_or(ptr::null_mut(), |caps| caps.as_mut_ptr()),
+            Box::into_raw(func) as glib_ffi::gpointer,
+            Some(type_find_closure_drop::<F>),
+        ))
+    }
+}